@@ -2,6 +2,7 @@ use std::path::PathBuf;
 use std::{env, fs};
 
 use anyhow::{anyhow, Context, Result};
+use fbink_rs::coords::Rect;
 use fbink_rs::{FbInk, FbInkConfig};
 use resvg::tiny_skia;
 use resvg::usvg::{self, fontdb};
@@ -40,7 +41,7 @@ fn main() -> Result<()> {
     let mut pixmap = tiny_skia::Pixmap::new(width, height).context("Failed to create pixmap")?;
     resvg::render(&tree, Default::default(), &mut pixmap.as_mut());
     fbink
-        .print_raw_data(pixmap.data(), width as i32, height as i32, 0, 0)
+        .print_raw_data(pixmap.data(), Rect::new(0, 0, width as u16, height as u16))
         .context("FBInk failed to print data to screen")?;
     Ok(())
 }
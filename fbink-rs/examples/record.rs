@@ -0,0 +1,14 @@
+use fbink_rs::recorder::{Recorder, RecordingOutput};
+use fbink_rs::FbInk;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub fn main() {
+    let fbink = FbInk::new(Default::default()).unwrap();
+    let output = RecordingOutput::Gif(PathBuf::from("/tmp/recording.gif"));
+    let mut recorder = Recorder::new(&fbink, output, Default::default()).unwrap();
+    // do something on screen while this is running
+    recorder.record_for(Duration::from_secs(10)).unwrap();
+    let frames = recorder.finish().unwrap();
+    println!("Recorded {frames} frames");
+}
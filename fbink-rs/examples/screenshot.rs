@@ -1,8 +1,11 @@
 use fbink_rs::FbInk;
-use std::fs;
+use std::fs::File;
+use std::io::BufWriter;
 
 pub fn main() {
     let fbink = FbInk::new(Default::default()).unwrap();
-    let bytes = fbink.screenshot(image::ImageFormat::Png).unwrap();
-    fs::write("/tmp/screenshot.png", bytes).unwrap();
+    let file = BufWriter::new(File::create("/tmp/screenshot.png").unwrap());
+    fbink
+        .screenshot_to(file, image::ImageFormat::Png, &Default::default())
+        .unwrap();
 }
@@ -0,0 +1,335 @@
+//! Keep recently shown screens in memory so they can be restored instantly, e.g. when going
+//! back to a previous page
+use crate::coords::{Native, Rect};
+use crate::dump::{Dump, OwnedDump};
+use crate::error::FbInkError;
+use crate::state::PixelFormat;
+#[cfg(feature = "image")]
+use crate::FbInk;
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A dump compressed with run-length encoding, which suits mostly white eInk screens well
+#[derive(Debug, Clone)]
+struct CompressedDump {
+    data: Vec<u8>,
+    len: usize,
+    stride: usize,
+    area: Rect<Native>,
+    clip: Option<Rect<Native>>,
+    rota: u8,
+    bpp: u8,
+    pixel_format: PixelFormat,
+    last_used: u64,
+}
+
+impl CompressedDump {
+    fn new(dump: &dyn Dump) -> Self {
+        let data = dump.data();
+        Self {
+            data: rle_encode(data),
+            len: data.len(),
+            stride: dump.stride(),
+            area: dump.area(),
+            clip: (!dump.is_full()).then(|| dump.clip()),
+            rota: dump.rota(),
+            bpp: dump.bpp(),
+            pixel_format: dump.pixel_format(),
+            last_used: 0,
+        }
+    }
+
+    fn decompress(&self) -> Result<OwnedDump, FbInkError> {
+        let data = rle_decode(&self.data, self.len)
+            .ok_or_else(|| FbInkError::InvalidArgument("corrupt cached dump".into()))?;
+        let mut dump = OwnedDump::new(data, self.stride, self.area, self.rota, self.bpp)?
+            .with_pixel_format(self.pixel_format);
+        if let Some(clip) = self.clip {
+            dump.crop_rect(clip);
+        }
+        Ok(dump)
+    }
+}
+
+/// A cache of compressed dumps keyed by caller-chosen ids. Once the compressed dumps exceed
+/// the memory budget, the least recently used are evicted.
+#[derive(Debug, Clone)]
+pub struct DumpCache<K> {
+    entries: HashMap<K, CompressedDump>,
+    budget: usize,
+    used: usize,
+    clock: u64,
+}
+
+impl<K: Eq + Hash + Clone> DumpCache<K> {
+    /// Create a cache that holds at most `budget` bytes of compressed data
+    pub fn new(budget: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            budget,
+            used: 0,
+            clock: 0,
+        }
+    }
+
+    /// Compress and store a dump, replacing any dump already stored under `key` and evicting
+    /// the least recently used dumps to stay within budget. Returns false if the dump doesn't
+    /// fit in the budget even on its own, in which case it isn't stored and any dump already
+    /// stored under `key` is kept.
+    /// Dumps are restored with fbink_restore, so a [`SunxiDump`](crate::dump::SunxiDump)'s
+    /// RGB data can be cached but not restored.
+    pub fn insert(&mut self, key: K, dump: &dyn Dump) -> bool {
+        let mut entry = CompressedDump::new(dump);
+        if entry.data.len() > self.budget {
+            return false;
+        }
+        self.remove(&key);
+        self.evict_until(self.budget - entry.data.len());
+        entry.last_used = self.tick();
+        self.used += entry.data.len();
+        self.entries.insert(key, entry);
+        true
+    }
+
+    /// Decompress a cached dump, marking it as recently used
+    pub fn get(&mut self, key: &K) -> Result<Option<OwnedDump>, FbInkError> {
+        let tick = self.tick();
+        let Some(entry) = self.entries.get_mut(key) else {
+            return Ok(None);
+        };
+        entry.last_used = tick;
+        entry.decompress().map(Some)
+    }
+
+    #[cfg(feature = "image")]
+    /// Restore a cached dump to the framebuffer. Returns false if nothing is cached under `key`
+    pub fn restore(&mut self, key: &K, fbink: &FbInk) -> Result<bool, FbInkError> {
+        match self.get(key)? {
+            Some(dump) => {
+                dump.restore(fbink)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub fn contains(&self, key: &K) -> bool {
+        self.entries.contains_key(key)
+    }
+
+    /// Remove a dump from the cache. Returns true if it was cached
+    pub fn remove(&mut self, key: &K) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.used -= entry.data.len();
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.used = 0;
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of bytes of compressed data currently cached
+    pub fn memory_used(&self) -> usize {
+        self.used
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Change the memory budget, evicting the least recently used dumps if it's now exceeded
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.evict_until(budget);
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Evict the least recently used dumps until at most `limit` bytes are used
+    fn evict_until(&mut self, limit: usize) {
+        while self.used > limit {
+            let Some(oldest) = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            self.remove(&oldest);
+        }
+    }
+}
+
+/// PackBits-style RLE: a header byte of 0..=127 is followed by that many plus one literal
+/// bytes, and a header of 128..=255 means repeat the next byte (header - 126) times.
+fn rle_encode(data: &[u8]) -> Vec<u8> {
+    const MAX_RUN: usize = 129;
+    const MAX_LITERAL: usize = 128;
+    let mut out = Vec::new();
+    let mut literal_start = 0;
+    let mut i = 0;
+    let flush_literals = |out: &mut Vec<u8>, literals: &[u8]| {
+        for chunk in literals.chunks(MAX_LITERAL) {
+            out.push((chunk.len() - 1) as u8);
+            out.extend_from_slice(chunk);
+        }
+    };
+    while i < data.len() {
+        let byte = data[i];
+        let run = data[i..]
+            .iter()
+            .take(MAX_RUN)
+            .take_while(|&&b| b == byte)
+            .count();
+        if run >= 3 {
+            flush_literals(&mut out, &data[literal_start..i]);
+            out.push((run + 126) as u8);
+            out.push(byte);
+            i += run;
+            literal_start = i;
+        } else {
+            i += run;
+        }
+    }
+    flush_literals(&mut out, &data[literal_start..]);
+    out
+}
+
+/// Decode data from [`rle_encode`]. Returns None unless it decodes to exactly `len` bytes
+fn rle_decode(data: &[u8], len: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < data.len() {
+        let header = usize::from(data[i]);
+        if header < 128 {
+            out.extend_from_slice(data.get(i + 1..i + 2 + header)?);
+            i += header + 2;
+        } else {
+            let byte = *data.get(i + 1)?;
+            out.resize(out.len() + header - 126, byte);
+            i += 2;
+        }
+    }
+    (out.len() == len).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let encoded = rle_encode(data);
+        assert_eq!(rle_decode(&encoded, data.len()).as_deref(), Some(data));
+        encoded
+    }
+
+    #[test]
+    fn rle_runs() {
+        assert!(round_trip(&[]).is_empty());
+        assert_eq!(round_trip(&[7]), [0, 7]);
+        assert_eq!(round_trip(&[7; 2]), [1, 7, 7]);
+        assert_eq!(round_trip(&[7; 3]), [129, 7]);
+        assert_eq!(round_trip(&[7; 128]), [254, 7]);
+        assert_eq!(round_trip(&[7; 129]), [255, 7]);
+        // The 130th byte starts a new run, too short to be one
+        assert_eq!(round_trip(&[7; 130]), [255, 7, 0, 7]);
+        assert_eq!(round_trip(&[7; 132]), [255, 7, 129, 7]);
+    }
+
+    #[test]
+    fn rle_literals() {
+        let literals: Vec<u8> = (0..=255).cycle().take(129).collect();
+        let encoded = round_trip(&literals);
+        assert_eq!(&encoded[..2], [127, 0]);
+        assert_eq!(&encoded[129..131], [0, 128]);
+        assert_eq!(encoded.len(), 131);
+        assert_eq!(round_trip(&literals[..128]).len(), 129);
+    }
+
+    #[test]
+    fn rle_boundaries() {
+        assert_eq!(round_trip(&[1, 2, 2, 2, 3]), [0, 1, 129, 2, 0, 3]);
+        assert_eq!(round_trip(&[1, 1, 2, 2, 2]), [1, 1, 1, 129, 2]);
+        assert_eq!(round_trip(&[2, 2, 2, 1, 1]), [129, 2, 1, 1, 1]);
+        let mut mixed: Vec<u8> = (0..200).map(|i| (i * 7 % 5) as u8).collect();
+        mixed.extend([9; 300]);
+        mixed.extend(0..=255);
+        round_trip(&mixed);
+    }
+
+    #[test]
+    fn rle_rejects_corrupt_data() {
+        let encoded = rle_encode(&[7; 10]);
+        assert_eq!(rle_decode(&encoded, 9), None);
+        assert_eq!(rle_decode(&encoded[..1], 10), None);
+        assert_eq!(rle_decode(&[5, 1, 2], 6), None);
+    }
+
+    fn dump(data: Vec<u8>) -> OwnedDump {
+        let area = Rect::new(0, 0, data.len() as u16, 1);
+        OwnedDump::new(data, area.width.into(), area, 0, 8).unwrap()
+    }
+
+    #[test]
+    fn get_keeps_pixel_format() {
+        let mut cache = DumpCache::new(1024);
+        // Pure red in BGR order, then white
+        let area = Rect::new(0, 0, 2, 1);
+        let mut bgr = OwnedDump::new(vec![0, 0, 255, 255, 255, 255], 6, area, 0, 24)
+            .unwrap()
+            .with_pixel_format(PixelFormat::Bgr24);
+        bgr.crop_rect(Rect::new(0, 0, 1, 1));
+        assert!(cache.insert("bgr", &bgr));
+        let cached = cache.get(&"bgr").unwrap().unwrap();
+        assert_eq!(cached.pixel_format(), PixelFormat::Bgr24);
+        assert!(!cached.is_full());
+        assert_eq!(cached.clip(), Rect::new(0, 0, 1, 1));
+        let image = cached.dynamic_image().unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (1, 1));
+        assert_eq!(image.get_pixel(0, 0).0, [255, 0, 0]);
+    }
+
+    #[test]
+    fn insert_too_large_keeps_existing() {
+        let mut cache = DumpCache::new(8);
+        assert!(cache.insert(1, &dump(vec![0; 64])));
+        let used = cache.memory_used();
+        let noise: Vec<u8> = (0..64).collect();
+        assert!(!cache.insert(1, &dump(noise)));
+        assert_eq!(cache.memory_used(), used);
+        assert_eq!(cache.get(&1).unwrap().unwrap().data(), [0; 64]);
+    }
+
+    #[test]
+    fn insert_evicts_least_recently_used() {
+        let mut cache = DumpCache::new(6);
+        for key in 0..3 {
+            assert!(cache.insert(key, &dump(vec![key; 16])));
+        }
+        cache.get(&0).unwrap();
+        assert!(cache.insert(3, &dump(vec![3; 16])));
+        assert!(cache.contains(&0) && !cache.contains(&1));
+        assert_eq!(cache.memory_used(), 6);
+        // Replacing an entry frees its space first
+        assert!(cache.insert(3, &dump(vec![4; 16])));
+        assert_eq!(cache.len(), 3);
+    }
+}
@@ -0,0 +1,332 @@
+//! What the device and the FBInk build support, so that unsupported operations can be refused
+//! with a precise reason before calling into FBInk
+use crate::config::CfaMode;
+use crate::error::FbInkError;
+use crate::{FbInkConfig, FbInkState};
+
+use strum::{AsRefStr, Display};
+
+/// Something that only works on certain devices or with certain FBInk builds
+#[derive(Debug, Display, AsRefStr, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[strum(serialize_all = "kebab-case")]
+pub enum Feature {
+    // FBInk's optional components, named after the cargo features that enable them
+    Draw,
+    Bitmap,
+    Fonts,
+    Image,
+    #[strum(serialize = "opentype")]
+    OpenType,
+    Input,
+    ButtonScan,
+    // Device support
+    /// Refreshing the screen, which only eInk devices need
+    Refresh,
+    WaitForComplete,
+    WaitForSubmission,
+    /// Converting between native and canonical rotations
+    CanonicalRotation,
+    /// Inverting the screen in hardware rather than in software, i.e. `is_nightmode`
+    HardwareInvert,
+    /// Colour-only config options, i.e. `cfa_mode` and `saturation_boost`
+    Color,
+    /// Controlling how rotation is handled on Sunxi SoCs
+    SunxiRotation,
+    /// Dumping the working buffer of a Sunxi SoC to capture content drawn by other processes
+    WorkingBufferDump,
+    WakeEpdc,
+    EclipseWaveforms,
+    /// Swipe animations, halftoning, auto REAGL and pen mode on MTK SoCs
+    MtkControls,
+}
+
+impl Feature {
+    pub(crate) fn unsupported(self) -> FbInkError {
+        let msg = match self {
+            Self::Draw
+            | Self::Bitmap
+            | Self::Fonts
+            | Self::Image
+            | Self::OpenType
+            | Self::Input
+            | Self::ButtonScan => format!("FBInk was built without the {self} feature"),
+            Self::Refresh | Self::WaitForComplete => {
+                "FBInk was built for generic Linux framebuffers, which aren't eInk".into()
+            }
+            Self::WaitForSubmission => {
+                "Waiting for submission is not supported on this device".into()
+            }
+            Self::CanonicalRotation => {
+                "Canonical rotation is only supported on Kobo devices".into()
+            }
+            Self::HardwareInvert => "This device can't invert the screen in hardware".into(),
+            Self::Color => "This device doesn't have a color panel".into(),
+            Self::SunxiRotation | Self::WorkingBufferDump => {
+                "Only supported on Kobos with Sunxi SoCs".into()
+            }
+            Self::WakeEpdc => "This device can't wake the EPDC up manually".into(),
+            Self::EclipseWaveforms => "This device doesn't have Eclipse waveform modes".into(),
+            Self::MtkControls => "Only supported on devices with MTK SoCs".into(),
+        };
+        FbInkError::NotSupported(msg)
+    }
+}
+
+/// The features supported by the device and the components FBInk was built with.
+/// The components are the ones requested from fbink-sys, so they may not match a library that
+/// was built separately and linked with `FBINK_LIB_DIR` or pkg-config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Capabilities {
+    pub draw: bool,
+    pub bitmap: bool,
+    pub fonts: bool,
+    pub image: bool,
+    pub opentype: bool,
+    pub input: bool,
+    pub button_scan: bool,
+    /// Whether FBInk was built for an eInk device rather than generic Linux
+    pub is_eink: bool,
+    /// Whether FBInk was built for Kobo
+    pub is_kobo: bool,
+    pub is_mtk: bool,
+    pub is_sunxi: bool,
+    pub sunxi_has_fbdamage: bool,
+    pub can_hw_invert: bool,
+    pub has_color_panel: bool,
+    pub can_wait_for_submission: bool,
+    /// Waiting for a refresh to complete may return early, so it's refused
+    pub unreliable_wait_for: bool,
+    pub can_wake_epdc: bool,
+    pub has_eclipse_wfm: bool,
+}
+
+impl Capabilities {
+    pub fn new(state: &FbInkState) -> Self {
+        let is_kobo = !cfg!(any(
+            feature = "kindle",
+            feature = "cervantes",
+            feature = "remarkable",
+            feature = "pocketbook",
+            feature = "linux"
+        ));
+        Self {
+            draw: cfg!(feature = "draw"),
+            bitmap: cfg!(feature = "bitmap"),
+            fonts: cfg!(feature = "fonts"),
+            image: cfg!(feature = "image"),
+            opentype: cfg!(feature = "opentype"),
+            input: cfg!(feature = "input"),
+            button_scan: cfg!(feature = "button-scan") && is_kobo,
+            is_eink: !cfg!(feature = "linux"),
+            is_kobo,
+            is_mtk: state.is_mtk,
+            is_sunxi: state.is_sunxi,
+            sunxi_has_fbdamage: state.sunxi_has_fbdamage,
+            can_hw_invert: state.can_hw_invert,
+            has_color_panel: state.has_color_panel,
+            can_wait_for_submission: state.can_wait_for_submission,
+            unreliable_wait_for: state.unreliable_wait_for,
+            can_wake_epdc: state.can_wake_epdc,
+            has_eclipse_wfm: state.has_eclipse_wfm,
+        }
+    }
+
+    pub fn supports(&self, feature: Feature) -> bool {
+        match feature {
+            Feature::Draw => self.draw,
+            Feature::Bitmap => self.bitmap,
+            Feature::Fonts => self.fonts,
+            Feature::Image => self.image,
+            Feature::OpenType => self.opentype,
+            Feature::Input => self.input,
+            Feature::ButtonScan => self.button_scan,
+            Feature::Refresh => self.is_eink,
+            Feature::WaitForComplete => self.is_eink && !self.unreliable_wait_for,
+            Feature::WaitForSubmission => self.can_wait_for_submission,
+            Feature::CanonicalRotation => self.is_kobo,
+            Feature::HardwareInvert => self.can_hw_invert,
+            Feature::Color => self.has_color_panel,
+            Feature::SunxiRotation | Feature::WorkingBufferDump => self.is_kobo && self.is_sunxi,
+            Feature::WakeEpdc => self.can_wake_epdc,
+            Feature::EclipseWaveforms => self.has_eclipse_wfm,
+            Feature::MtkControls => self.is_mtk,
+        }
+    }
+
+    /// Like [`Capabilities::supports`] but returns a [`FbInkError::NotSupported`] explaining why
+    /// the feature isn't supported
+    pub fn check(&self, feature: Feature) -> Result<(), FbInkError> {
+        if self.supports(feature) {
+            return Ok(());
+        }
+        if feature == Feature::WaitForComplete && self.is_eink {
+            let msg = "Waiting for refreshes is unreliable on this device".into();
+            return Err(FbInkError::NotSupported(msg));
+        }
+        Err(feature.unsupported())
+    }
+
+    /// Check that the device supports the options set in the config, which FBInk would
+    /// otherwise ignore
+    pub fn check_config(&self, config: &FbInkConfig) -> Result<(), FbInkError> {
+        if config.is_nightmode {
+            self.check(Feature::HardwareInvert)?;
+        }
+        if config.cfa_mode != CfaMode::None || config.saturation_boost != 0 {
+            self.check(Feature::Color)?;
+        }
+        Ok(())
+    }
+}
+
+impl FbInkState {
+    /// What the device and the FBInk build support
+    pub fn capabilities(&self) -> Capabilities {
+        Capabilities::new(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Display;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+
+    /// The state of a device without any optional hardware
+    fn state() -> FbInkState {
+        let fbink = VirtualFbInk::new(VirtualDevice::default(), FbInkConfig::default()).unwrap();
+        fbink.state()
+    }
+
+    const COMPONENTS: [Feature; 7] = [
+        Feature::Draw,
+        Feature::Bitmap,
+        Feature::Fonts,
+        Feature::Image,
+        Feature::OpenType,
+        Feature::Input,
+        Feature::ButtonScan,
+    ];
+
+    #[test]
+    fn components_come_from_the_build() {
+        let plain = state().capabilities();
+        assert_eq!(plain.supports(Feature::Draw), cfg!(feature = "draw"));
+        assert_eq!(plain.supports(Feature::Image), cfg!(feature = "image"));
+        assert_eq!(
+            plain.supports(Feature::OpenType),
+            cfg!(feature = "opentype")
+        );
+        // No device flag changes which components were built
+        let mut state = state();
+        state.is_mtk = true;
+        state.is_sunxi = true;
+        state.can_hw_invert = true;
+        state.has_color_panel = true;
+        state.can_wake_epdc = true;
+        let capable = state.capabilities();
+        for feature in COMPONENTS {
+            assert_eq!(
+                capable.supports(feature),
+                plain.supports(feature),
+                "{feature}"
+            );
+        }
+        if !cfg!(feature = "draw") {
+            let err = plain.check(Feature::Draw).unwrap_err().to_string();
+            assert!(err.contains("without the draw feature"), "{err}");
+        }
+    }
+
+    #[test]
+    fn device_features_come_from_the_state() {
+        let mut state = state();
+        let device = [
+            Feature::WaitForSubmission,
+            Feature::HardwareInvert,
+            Feature::Color,
+            Feature::WakeEpdc,
+            Feature::EclipseWaveforms,
+            Feature::MtkControls,
+        ];
+        let capabilities = state.capabilities();
+        for feature in device {
+            assert!(!capabilities.supports(feature), "{feature}");
+            assert!(matches!(
+                capabilities.check(feature),
+                Err(FbInkError::NotSupported(_))
+            ));
+        }
+        state.can_wait_for_submission = true;
+        state.can_hw_invert = true;
+        state.has_color_panel = true;
+        state.can_wake_epdc = true;
+        state.has_eclipse_wfm = true;
+        state.is_mtk = true;
+        let capabilities = state.capabilities();
+        for feature in device {
+            assert!(capabilities.supports(feature), "{feature}");
+            assert!(capabilities.check(feature).is_ok());
+        }
+        // Sunxi features also need a Kobo build
+        assert!(!capabilities.supports(Feature::WorkingBufferDump));
+        state.is_sunxi = true;
+        let is_kobo = state.capabilities().is_kobo;
+        assert_eq!(
+            state.capabilities().supports(Feature::SunxiRotation),
+            is_kobo
+        );
+    }
+
+    #[test]
+    fn unreliable_wait_for() {
+        let mut state = state();
+        let is_eink = state.capabilities().is_eink;
+        assert_eq!(
+            state.capabilities().supports(Feature::WaitForComplete),
+            is_eink
+        );
+        state.unreliable_wait_for = true;
+        let capabilities = state.capabilities();
+        assert!(!capabilities.supports(Feature::WaitForComplete));
+        assert_eq!(capabilities.supports(Feature::Refresh), is_eink);
+        let err = capabilities.check(Feature::WaitForComplete).unwrap_err();
+        if is_eink {
+            assert!(err.to_string().contains("unreliable"), "{err}");
+        }
+    }
+
+    #[test]
+    fn config_options_need_hardware() {
+        let mut state = state();
+        let config = FbInkConfig::default();
+        assert!(state.capabilities().check_config(&config).is_ok());
+        let nightmode = FbInkConfig {
+            is_nightmode: true,
+            ..config
+        };
+        let cfa = FbInkConfig {
+            cfa_mode: CfaMode::S7,
+            ..config
+        };
+        let saturation = FbInkConfig {
+            saturation_boost: 10,
+            ..config
+        };
+        let capabilities = state.capabilities();
+        for config in [nightmode, cfa, saturation] {
+            assert!(matches!(
+                capabilities.check_config(&config),
+                Err(FbInkError::NotSupported(_))
+            ));
+        }
+        state.can_hw_invert = true;
+        state.has_color_panel = true;
+        let capabilities = state.capabilities();
+        for config in [nightmode, cfa, saturation] {
+            assert!(capabilities.check_config(&config).is_ok());
+        }
+    }
+}
@@ -1,6 +1,9 @@
+use crate::state::FbInkState;
+
 use fbink_sys as raw;
 use fbink_sys::*;
 use num_enum::{FromPrimitive, IntoPrimitive};
+use thiserror::Error;
 
 #[derive(Debug, Default, Copy, Clone)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -86,6 +89,133 @@ impl From<FbInkConfig> for raw::FBInkConfig {
     }
 }
 
+impl FbInkConfig {
+    /// Check for options that are out of range, contradict each other or don't apply to the
+    /// device. FBInk would otherwise silently clamp or ignore them. The maximum `fontmult` is
+    /// based on the font FBInk was last initialized with.
+    pub fn validate(&self, state: &FbInkState) -> Result<(), Vec<ConfigIssue>> {
+        let mut issues = Vec::new();
+        if self.font == Font::Max {
+            issues.push(ConfigIssue::NotAFont);
+        }
+        if state.glyph_width > 0 && state.glyph_height > 0 {
+            let max_cols = state.view_width / u32::from(state.glyph_width);
+            let max_rows = state.view_height / u32::from(state.glyph_height);
+            let max = max_cols.min(max_rows).min(u8::MAX.into()) as u8;
+            if self.fontmult > max {
+                issues.push(ConfigIssue::FontMultTooLarge {
+                    fontmult: self.fontmult,
+                    max,
+                });
+            }
+        }
+        if self.is_centered && self.hoffset != 0 {
+            issues.push(ConfigIssue::CenteredWithOffset {
+                hoffset: self.hoffset,
+            });
+        }
+        if !state.has_color_panel {
+            if self.cfa_mode != CfaMode::None {
+                issues.push(ConfigIssue::CfaModeWithoutColor(self.cfa_mode));
+            }
+            if self.saturation_boost != 0 {
+                issues.push(ConfigIssue::SaturationBoostWithoutColor(
+                    self.saturation_boost,
+                ));
+            }
+        }
+        if self.scaled_width < -2 {
+            issues.push(ConfigIssue::ScaledWidthOutOfRange(self.scaled_width));
+        }
+        if self.scaled_height < -2 {
+            issues.push(ConfigIssue::ScaledHeightOutOfRange(self.scaled_height));
+        }
+        if self.is_fgless && self.is_bgless {
+            issues.push(ConfigIssue::NothingDrawn);
+        }
+        match issues.is_empty() {
+            true => Ok(()),
+            false => Err(issues),
+        }
+    }
+}
+
+/// A problem found by [`FbInkConfig::validate`]
+#[derive(Error, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConfigIssue {
+    #[error("Font::Max marks the end of the font list and isn't a font")]
+    NotAFont,
+    #[error("fontmult {fontmult} is larger than the maximum of {max} for this screen")]
+    FontMultTooLarge { fontmult: u8, max: u8 },
+    #[error("is_centered overrides hoffset {hoffset}")]
+    CenteredWithOffset { hoffset: i16 },
+    #[error("cfa_mode {0:?} requires a colour panel")]
+    CfaModeWithoutColor(CfaMode),
+    #[error("saturation_boost {0} requires a colour panel")]
+    SaturationBoostWithoutColor(u8),
+    /// Negative sizes other than -1 and -2, which fit the image to the screen
+    #[error("scaled_width {0} is out of range (must be at least -2)")]
+    ScaledWidthOutOfRange(i16),
+    #[error("scaled_height {0} is out of range (must be at least -2)")]
+    ScaledHeightOutOfRange(i16),
+    #[error("is_fgless and is_bgless are both set, so nothing would be drawn")]
+    NothingDrawn,
+}
+
+/// Options for printing with OpenType fonts, on top of those in [`FbInkConfig`]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FbInkOtConfig {
+    pub margins: OtMargins,
+    pub style: FontStyle,
+    /// Font size in points. Ignored if `size_px` is set
+    pub size_pt: f32,
+    /// Font size in pixels
+    pub size_px: u16,
+    pub is_centered: bool,
+    pub padding: PaddingIndex,
+    /// Interpret `**bold**` and `*italic*` markup
+    pub is_formatted: bool,
+    /// Lay the text out without drawing anything
+    pub compute_only: bool,
+    /// Don't truncate text that doesn't fit, and fail instead
+    pub no_truncation: bool,
+}
+
+/// Margins in pixels around the area OpenType text is printed in. Negative values count from
+/// the opposite edge.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OtMargins {
+    pub top: i16,
+    pub bottom: i16,
+    pub left: i16,
+    pub right: i16,
+}
+
+impl From<FbInkOtConfig> for raw::FBInkOTConfig {
+    fn from(c: FbInkOtConfig) -> Self {
+        Self {
+            font: std::ptr::null_mut(),
+            margins: raw::FBInkOTConfig__bindgen_ty_1 {
+                top: c.margins.top,
+                bottom: c.margins.bottom,
+                left: c.margins.left,
+                right: c.margins.right,
+            },
+            style: c.style.into(),
+            size_pt: c.size_pt,
+            size_px: c.size_px,
+            is_centered: c.is_centered,
+            padding: c.padding.into(),
+            is_formatted: c.is_formatted,
+            compute_only: c.compute_only,
+            no_truncation: c.no_truncation,
+        }
+    }
+}
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, FromPrimitive, IntoPrimitive)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[repr(u8)]
@@ -268,3 +398,159 @@ pub enum CfaMode {
     G1 = CFA_MODE_INDEX_E_CFA_G1,
     G2 = CFA_MODE_INDEX_E_CFA_G2,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Display;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+
+    /// An 800x600 screen with an 8x8 font, so `fontmult` is at most 75
+    fn state(has_color_panel: bool) -> FbInkState {
+        let device = VirtualDevice {
+            has_color_panel,
+            ..Default::default()
+        };
+        let fbink = VirtualFbInk::new(device, FbInkConfig::default()).unwrap();
+        let mut state = fbink.state();
+        (state.view_width, state.view_height) = (800, 600);
+        (state.glyph_width, state.glyph_height) = (8, 8);
+        state
+    }
+
+    fn issues(config: FbInkConfig, state: &FbInkState) -> Vec<ConfigIssue> {
+        config.validate(state).err().unwrap_or_default()
+    }
+
+    #[test]
+    fn valid_configs() {
+        let configs = [
+            FbInkConfig::default(),
+            // fontmult 0 picks a size automatically
+            FbInkConfig {
+                fontmult: 0,
+                ..Default::default()
+            },
+            FbInkConfig {
+                fontmult: 75,
+                is_centered: true,
+                scaled_width: -2,
+                scaled_height: -1,
+                is_fgless: true,
+                ..Default::default()
+            },
+        ];
+        for config in configs {
+            assert_eq!(config.validate(&state(false)), Ok(()), "{config:?}");
+            assert_eq!(config.validate(&state(true)), Ok(()), "{config:?}");
+        }
+        let color = FbInkConfig {
+            cfa_mode: CfaMode::S7,
+            saturation_boost: 10,
+            ..Default::default()
+        };
+        assert_eq!(color.validate(&state(true)), Ok(()));
+    }
+
+    #[test]
+    fn each_issue() {
+        let mono = state(false);
+        let cases = [
+            (
+                FbInkConfig {
+                    font: Font::Max,
+                    ..Default::default()
+                },
+                ConfigIssue::NotAFont,
+            ),
+            (
+                FbInkConfig {
+                    fontmult: 76,
+                    ..Default::default()
+                },
+                ConfigIssue::FontMultTooLarge {
+                    fontmult: 76,
+                    max: 75,
+                },
+            ),
+            (
+                FbInkConfig {
+                    is_centered: true,
+                    hoffset: 10,
+                    ..Default::default()
+                },
+                ConfigIssue::CenteredWithOffset { hoffset: 10 },
+            ),
+            (
+                FbInkConfig {
+                    cfa_mode: CfaMode::S7,
+                    ..Default::default()
+                },
+                ConfigIssue::CfaModeWithoutColor(CfaMode::S7),
+            ),
+            (
+                FbInkConfig {
+                    saturation_boost: 10,
+                    ..Default::default()
+                },
+                ConfigIssue::SaturationBoostWithoutColor(10),
+            ),
+            (
+                FbInkConfig {
+                    scaled_width: -3,
+                    ..Default::default()
+                },
+                ConfigIssue::ScaledWidthOutOfRange(-3),
+            ),
+            (
+                FbInkConfig {
+                    scaled_height: -3,
+                    ..Default::default()
+                },
+                ConfigIssue::ScaledHeightOutOfRange(-3),
+            ),
+            (
+                FbInkConfig {
+                    is_fgless: true,
+                    is_bgless: true,
+                    ..Default::default()
+                },
+                ConfigIssue::NothingDrawn,
+            ),
+        ];
+        for (config, issue) in cases {
+            assert_eq!(issues(config, &mono), [issue]);
+        }
+    }
+
+    #[test]
+    fn all_issues_are_reported() {
+        let config = FbInkConfig {
+            is_centered: true,
+            hoffset: -1,
+            scaled_width: -3,
+            scaled_height: -4,
+            ..Default::default()
+        };
+        assert_eq!(
+            issues(config, &state(false)),
+            [
+                ConfigIssue::CenteredWithOffset { hoffset: -1 },
+                ConfigIssue::ScaledWidthOutOfRange(-3),
+                ConfigIssue::ScaledHeightOutOfRange(-4),
+            ]
+        );
+    }
+
+    #[test]
+    fn fontmult_unchecked_without_glyphs() {
+        // Before FBInk is initialized there's no font to base the maximum on
+        let mut state = state(false);
+        (state.glyph_width, state.glyph_height) = (0, 0);
+        let config = FbInkConfig {
+            fontmult: u8::MAX,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(&state), Ok(()));
+    }
+}
@@ -0,0 +1,386 @@
+//! Points and rects tagged with the coordinate space they're in, so that passing e.g. a
+//! framebuffer rect to a function that expects one in the current rotation doesn't compile.
+//!
+//! FBInk deals with four spaces:
+//! - [`View`]: relative to the viewport, which excludes any rows or columns hidden by the
+//!   bezel. Used for positioning text and images.
+//! - [`Rotated`]: the whole framebuffer as FBInk presents it in the current rotation. Used for
+//!   refreshing and clearing.
+//! - [`Native`]: the framebuffer's memory layout, after any rotation tricks FBInk applies.
+//!   Dumps are in this space.
+//! - [`Canonical`]: the screen held upright in portrait, whatever the current rotation.
+use crate::{FbInkRect, FbInkState};
+
+use std::fmt;
+use std::marker::PhantomData;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A coordinate space. Conversions between spaces go through [`Rotated`].
+pub trait Space: private::Sealed + Copy + fmt::Debug + Eq + std::hash::Hash {
+    /// Convert a rect in this space to the rotated space
+    #[doc(hidden)]
+    fn to_rotated(rect: FbInkRect, frame: &Frame) -> FbInkRect;
+    /// Convert a rect in the rotated space to this space
+    #[doc(hidden)]
+    fn from_rotated(rect: FbInkRect, frame: &Frame) -> FbInkRect;
+}
+
+/// Relative to the viewport origin
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum View {}
+
+/// The framebuffer in its current rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rotated {}
+
+/// The framebuffer's memory layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Native {}
+
+/// Upright in portrait, independent of the current rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Canonical {}
+
+/// The geometry needed to convert between spaces, taken from the state
+#[doc(hidden)]
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    /// The size of the rotated space
+    size: (u16, u16),
+    origin: (u16, u16),
+    /// Whether the native space is the rotated one turned counterclockwise
+    quirky_landscape: bool,
+    /// Clockwise quarter turns from the rotated space to the canonical one
+    canonical_turns: u8,
+}
+
+impl Frame {
+    fn new(state: &FbInkState) -> Self {
+        let clamp = |value: u32| value.min(u16::MAX.into()) as u16;
+        // The rotation map's first entry is the native rotation that's canonically upright
+        let upright = state.quarter_turns_from(state.rotation_map[0]);
+        Self {
+            size: (clamp(state.screen_width), clamp(state.screen_height)),
+            origin: (state.view_hori_origin.into(), state.view_vert_origin.into()),
+            quirky_landscape: state.is_ntx_quirky_landscape,
+            canonical_turns: (4 - upright) % 4,
+        }
+    }
+}
+
+/// Turn a rect in a space of the given size a quarter clockwise, returning the rect and the
+/// size of the turned space
+fn turn_clockwise(rect: FbInkRect, (width, height): (u16, u16)) -> (FbInkRect, (u16, u16)) {
+    let rect = FbInkRect {
+        left: height.saturating_sub(rect.top.saturating_add(rect.height)),
+        top: rect.left,
+        width: rect.height,
+        height: rect.width,
+    };
+    (rect, (height, width))
+}
+
+fn turn_counterclockwise(rect: FbInkRect, (width, height): (u16, u16)) -> (FbInkRect, (u16, u16)) {
+    let rect = FbInkRect {
+        left: rect.top,
+        top: width.saturating_sub(rect.left.saturating_add(rect.width)),
+        width: rect.height,
+        height: rect.width,
+    };
+    (rect, (height, width))
+}
+
+impl private::Sealed for View {}
+impl Space for View {
+    fn to_rotated(rect: FbInkRect, frame: &Frame) -> FbInkRect {
+        FbInkRect {
+            left: rect.left.saturating_add(frame.origin.0),
+            top: rect.top.saturating_add(frame.origin.1),
+            ..rect
+        }
+    }
+    /// Whatever is above or left of the viewport is cut off
+    fn from_rotated(rect: FbInkRect, frame: &Frame) -> FbInkRect {
+        let (x, y) = frame.origin;
+        FbInkRect {
+            left: rect.left.saturating_sub(x),
+            top: rect.top.saturating_sub(y),
+            width: rect.width.saturating_sub(x.saturating_sub(rect.left)),
+            height: rect.height.saturating_sub(y.saturating_sub(rect.top)),
+        }
+    }
+}
+
+impl private::Sealed for Rotated {}
+impl Space for Rotated {
+    fn to_rotated(rect: FbInkRect, _frame: &Frame) -> FbInkRect {
+        rect
+    }
+    fn from_rotated(rect: FbInkRect, _frame: &Frame) -> FbInkRect {
+        rect
+    }
+}
+
+impl private::Sealed for Native {}
+impl Space for Native {
+    // In pickel's quirky landscape mode the framebuffer is landscape, but what's drawn is
+    // shown in portrait, rotated clockwise
+    fn to_rotated(rect: FbInkRect, frame: &Frame) -> FbInkRect {
+        match frame.quirky_landscape {
+            true => turn_clockwise(rect, (frame.size.1, frame.size.0)).0,
+            false => rect,
+        }
+    }
+    fn from_rotated(rect: FbInkRect, frame: &Frame) -> FbInkRect {
+        match frame.quirky_landscape {
+            true => turn_counterclockwise(rect, frame.size).0,
+            false => rect,
+        }
+    }
+}
+
+impl private::Sealed for Canonical {}
+impl Space for Canonical {
+    fn to_rotated(mut rect: FbInkRect, frame: &Frame) -> FbInkRect {
+        let mut size = match frame.canonical_turns % 2 {
+            0 => frame.size,
+            _ => (frame.size.1, frame.size.0),
+        };
+        for _ in 0..frame.canonical_turns {
+            (rect, size) = turn_counterclockwise(rect, size);
+        }
+        rect
+    }
+    fn from_rotated(mut rect: FbInkRect, frame: &Frame) -> FbInkRect {
+        let mut size = frame.size;
+        for _ in 0..frame.canonical_turns {
+            (rect, size) = turn_clockwise(rect, size);
+        }
+        rect
+    }
+}
+
+fn convert<S: Space, T: Space>(rect: FbInkRect, state: &FbInkState) -> FbInkRect {
+    let frame = Frame::new(state);
+    T::from_rotated(S::to_rotated(rect, &frame), &frame)
+}
+
+/// A pixel in the coordinate space `S`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound = ""))]
+pub struct Point<S: Space> {
+    pub x: u16,
+    pub y: u16,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    space: PhantomData<S>,
+}
+
+impl<S: Space> Point<S> {
+    pub fn new(x: u16, y: u16) -> Self {
+        Self {
+            x,
+            y,
+            space: PhantomData,
+        }
+    }
+
+    /// The same pixel in another coordinate space
+    pub fn to<T: Space>(self, state: &FbInkState) -> Point<T> {
+        let rect = convert::<S, T>(Rect::<S>::new(self.x, self.y, 1, 1).into(), state);
+        Point::new(rect.left, rect.top)
+    }
+}
+
+impl<S: Space> Default for Point<S> {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+/// A rectangle in the coordinate space `S`. As with FBInk, an empty rect often means the
+/// whole screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(bound = ""))]
+pub struct Rect<S: Space> {
+    pub left: u16,
+    pub top: u16,
+    pub width: u16,
+    pub height: u16,
+    #[cfg_attr(feature = "serde", serde(skip))]
+    space: PhantomData<S>,
+}
+
+impl<S: Space> Rect<S> {
+    pub fn new(left: u16, top: u16, width: u16, height: u16) -> Self {
+        Self {
+            left,
+            top,
+            width,
+            height,
+            space: PhantomData,
+        }
+    }
+
+    /// Tag a rect from FBInk or a dump as being in this space
+    pub fn from_raw(rect: FbInkRect) -> Self {
+        Self::new(rect.left, rect.top, rect.width, rect.height)
+    }
+
+    /// The rect covering the same pixels in another coordinate space
+    pub fn to<T: Space>(self, state: &FbInkState) -> Rect<T> {
+        Rect::from_raw(convert::<S, T>(self.into(), state))
+    }
+
+    /// The top left corner
+    pub fn origin(&self) -> Point<S> {
+        Point::new(self.left, self.top)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn contains(&self, point: Point<S>) -> bool {
+        let contains = |start: u16, len: u16, value: u16| {
+            value >= start && u32::from(value) < u32::from(start) + u32::from(len)
+        };
+        contains(self.left, self.width, point.x) && contains(self.top, self.height, point.y)
+    }
+}
+
+impl<S: Space> Default for Rect<S> {
+    fn default() -> Self {
+        Self::new(0, 0, 0, 0)
+    }
+}
+
+impl<S: Space> From<Rect<S>> for FbInkRect {
+    fn from(rect: Rect<S>) -> Self {
+        FbInkRect {
+            left: rect.left,
+            top: rect.top,
+            width: rect.width,
+            height: rect.height,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Display;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+    use crate::FbInkConfig;
+
+    /// The state of a screen of the given size in its current rotation
+    fn state(width: u32, height: u32, rota: u8) -> FbInkState {
+        let device = VirtualDevice {
+            width,
+            height,
+            rota,
+            ..Default::default()
+        };
+        VirtualFbInk::new(device, FbInkConfig::default())
+            .unwrap()
+            .state()
+    }
+
+    fn raw(left: u16, top: u16, width: u16, height: u16) -> FbInkRect {
+        Rect::<Rotated>::new(left, top, width, height).into()
+    }
+
+    fn tagged(rect: FbInkRect) -> Rect<Rotated> {
+        Rect::from_raw(rect)
+    }
+
+    #[test]
+    fn quarter_turns() {
+        let rect = raw(1, 2, 3, 1);
+        let (turned, size) = turn_clockwise(rect, (10, 6));
+        assert_eq!((tagged(turned), size), (Rect::new(3, 1, 1, 3), (6, 10)));
+        let (back, size) = turn_counterclockwise(turned, size);
+        assert_eq!((tagged(back), size), (tagged(rect), (10, 6)));
+        // Four turns either way end up where they started
+        let (mut clockwise, mut counterclockwise) = ((rect, (10, 6)), (rect, (10, 6)));
+        for _ in 0..4 {
+            clockwise = turn_clockwise(clockwise.0, clockwise.1);
+            counterclockwise = turn_counterclockwise(counterclockwise.0, counterclockwise.1);
+        }
+        assert_eq!(tagged(clockwise.0), tagged(rect));
+        assert_eq!(tagged(counterclockwise.0), tagged(rect));
+    }
+
+    #[test]
+    fn frame_from_state() {
+        let mut state = state(800, 600, 1);
+        state.view_hori_origin = 4;
+        state.view_vert_origin = 2;
+        let frame = Frame::new(&state);
+        assert_eq!(frame.size, (800, 600));
+        assert_eq!(frame.origin, (4, 2));
+        assert!(!frame.quirky_landscape);
+        // Native rotation 0 is upright, so the canonical space is three turns further
+        assert_eq!(frame.canonical_turns, 3);
+        // Rotation maps with the upright rotation elsewhere are followed
+        state.rotation_map = [3, 0, 1, 2];
+        state.current_rota = 3;
+        assert_eq!(Frame::new(&state).canonical_turns, 0);
+        state.is_ntx_quirky_landscape = true;
+        assert!(Frame::new(&state).quirky_landscape);
+    }
+
+    #[test]
+    fn view_is_offset_by_origin() {
+        let mut state = state(600, 800, 0);
+        state.view_hori_origin = 4;
+        state.view_vert_origin = 2;
+        let rect = Rect::<View>::new(0, 0, 10, 10);
+        assert_eq!(rect.to::<Rotated>(&state), Rect::new(4, 2, 10, 10));
+        assert_eq!(rect.to::<Native>(&state), Rect::new(4, 2, 10, 10));
+        // Anything above or left of the viewport is cut off
+        let rect = Rect::<Rotated>::new(2, 1, 10, 10);
+        assert_eq!(rect.to::<View>(&state), Rect::new(0, 0, 8, 9));
+    }
+
+    #[test]
+    fn canonical_is_upright() {
+        // Turned a quarter clockwise from upright, so the top left corner of the
+        // framebuffer is the bottom left of the canonical portrait screen
+        let state = state(800, 600, 1);
+        let corner = Rect::<Rotated>::new(0, 0, 1, 1);
+        assert_eq!(corner.to::<Canonical>(&state), Rect::new(0, 799, 1, 1));
+        let point = Point::<Native>::new(799, 0);
+        assert_eq!(point.to::<Canonical>(&state), Point::new(0, 0));
+        let rect = Rect::<Native>::new(10, 20, 30, 40);
+        let canonical = rect.to::<Canonical>(&state);
+        assert_eq!(canonical, Rect::new(20, 760, 40, 30));
+        assert_eq!(canonical.to::<Native>(&state), rect);
+        // Upright, the spaces line up
+        let state = self::state(600, 800, 0);
+        assert_eq!(rect.to::<Canonical>(&state), Rect::new(10, 20, 30, 40));
+        for rota in 0..4 {
+            let state = self::state(600, 800, rota);
+            let canonical = rect.to::<Canonical>(&state);
+            assert_eq!(canonical.to::<Native>(&state), rect, "rotation {rota}");
+        }
+    }
+
+    #[test]
+    fn quirky_landscape() {
+        // The framebuffer is portrait in memory but landscape in its current rotation
+        let mut state = state(800, 600, 0);
+        state.is_ntx_quirky_landscape = true;
+        let native = Rect::<Native>::new(0, 0, 10, 20);
+        let rotated = native.to::<Rotated>(&state);
+        assert_eq!(rotated, Rect::new(780, 0, 20, 10));
+        assert_eq!(rotated.to::<Native>(&state), native);
+        assert_eq!(native.to::<View>(&state), rotated.to::<View>(&state));
+        state.is_ntx_quirky_landscape = false;
+        assert_eq!(native.to::<Rotated>(&state), Rect::new(0, 0, 10, 20));
+    }
+}
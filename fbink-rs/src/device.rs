@@ -0,0 +1,386 @@
+//! Hardware details of the devices FBInk supports, so apps can pick layouts and features
+//! without hardcoding lists of devices. Specs are from vendor documentation.
+use crate::state::DeviceId;
+use crate::FbInkState;
+
+use strum::{AsRefStr, Display};
+
+#[derive(Debug, Display, AsRefStr, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Vendor {
+    Kobo,
+    /// Tolinos are made by Kobo and share its device ids and hardware
+    Tolino,
+    Cervantes,
+    #[strum(serialize = "reMarkable")]
+    Remarkable,
+    #[strum(serialize = "PocketBook")]
+    Pocketbook,
+}
+
+/// The family of SoC a device is built around
+#[derive(Debug, Display, AsRefStr, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Soc {
+    #[strum(serialize = "i.MX")]
+    Imx,
+    /// Allwinner B300, whose EPDC FBInk handles very differently
+    Sunxi,
+    #[strum(serialize = "MTK")]
+    Mtk,
+    Unknown,
+}
+
+/// The kind of colour filter on top of the eInk panel
+#[derive(Debug, Display, AsRefStr, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ColorPanel {
+    Triton,
+    Kaleido,
+    #[strum(serialize = "Kaleido Plus")]
+    KaleidoPlus,
+    #[strum(serialize = "Kaleido 3")]
+    Kaleido3,
+}
+
+/// Known hardware quirks worth working around
+#[derive(Debug, Display, AsRefStr, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Quirk {
+    /// The touch panel only reports a single contact
+    SingleTouch,
+    /// The framebuffer can't be dumped directly.
+    /// See [`FbInk::dump_workaround_sunxi`](crate::FbInk::dump_workaround_sunxi)
+    NoDirectDump,
+    /// The colour layer has half the resolution of the grayscale one
+    HalfResolutionColor,
+    /// Another model reports the same device id, so the codename is needed to tell them apart
+    SharedDeviceId,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub vendor: Vendor,
+    /// The marketing name
+    pub name: &'static str,
+    /// The diagonal size of the panel in inches
+    pub size: f32,
+    /// The panel's resolution in portrait
+    pub width: u16,
+    pub height: u16,
+    pub color_panel: Option<ColorPanel>,
+    pub has_frontlight: bool,
+    /// Whether the frontlight's colour temperature can be adjusted
+    pub has_warmth: bool,
+    pub has_stylus: bool,
+    pub soc: Soc,
+    pub quirks: &'static [Quirk],
+}
+
+impl DeviceInfo {
+    const fn new(
+        id: DeviceId,
+        vendor: Vendor,
+        name: &'static str,
+        size: f32,
+        (width, height): (u16, u16),
+        soc: Soc,
+    ) -> Self {
+        Self {
+            id,
+            vendor,
+            name,
+            size,
+            width,
+            height,
+            color_panel: None,
+            has_frontlight: false,
+            has_warmth: false,
+            has_stylus: false,
+            soc,
+            quirks: &[],
+        }
+    }
+
+    const fn frontlight(mut self) -> Self {
+        self.has_frontlight = true;
+        self
+    }
+
+    const fn warmth(mut self) -> Self {
+        self.has_frontlight = true;
+        self.has_warmth = true;
+        self
+    }
+
+    const fn stylus(mut self) -> Self {
+        self.has_stylus = true;
+        self
+    }
+
+    const fn color(mut self, panel: ColorPanel) -> Self {
+        self.color_panel = Some(panel);
+        self
+    }
+
+    const fn quirks(mut self, quirks: &'static [Quirk]) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Look up a device by id. Where several models share an id, this returns the one the id
+    /// was originally assigned to. Use [`DeviceInfo::resolve`] to tell them apart.
+    pub fn lookup(id: DeviceId) -> Option<&'static Self> {
+        DEVICES.iter().find(|device| device.id == id)
+    }
+
+    /// Look up a device by id, using the codename FBInk reports to resolve ids shared by
+    /// several models
+    pub fn resolve(id: DeviceId, codename: &str) -> Option<&'static Self> {
+        let codename = codename.to_ascii_lowercase();
+        ALIASES
+            .iter()
+            .find(|(pattern, device)| device.id == id && codename.contains(pattern))
+            .map(|(_, device)| device)
+            .or_else(|| Self::lookup(id))
+    }
+
+    /// The grayscale resolution in dots per inch
+    pub fn dpi(&self) -> u16 {
+        let (w, h) = (f32::from(self.width), f32::from(self.height));
+        ((w * w + h * h).sqrt() / self.size).round() as u16
+    }
+
+    pub fn has_color(&self) -> bool {
+        self.color_panel.is_some()
+    }
+
+    pub fn has_quirk(&self, quirk: Quirk) -> bool {
+        self.quirks.contains(&quirk)
+    }
+}
+
+impl FbInkState {
+    /// The hardware details of the device, if it's a known model
+    pub fn device_info(&self) -> Option<&'static DeviceInfo> {
+        DeviceInfo::resolve(self.device_id, &self.device_codename)
+    }
+}
+
+use ColorPanel::*;
+use DeviceId as Id;
+use Quirk::*;
+use Soc::*;
+use Vendor::*;
+
+const SD: (u16, u16) = (600, 800);
+const HD: (u16, u16) = (758, 1024);
+const FHD: (u16, u16) = (1072, 1448);
+const LIBRA: (u16, u16) = (1264, 1680);
+const FORMA: (u16, u16) = (1440, 1920);
+const CARTA: (u16, u16) = (1404, 1872);
+
+const SUNXI_QUIRKS: &[Quirk] = &[NoDirectDump];
+const KALEIDO_QUIRKS: &[Quirk] = &[HalfResolutionColor];
+
+#[rustfmt::skip]
+static DEVICES: &[DeviceInfo] = &[
+    // Cervantes
+    DeviceInfo::new(Id::CervantesTouch, Cervantes, "BQ Cervantes Touch", 6.0, SD, Imx),
+    DeviceInfo::new(Id::CervantesTouchlight, Cervantes, "BQ Cervantes TouchLight", 6.0, SD, Imx).frontlight(),
+    DeviceInfo::new(Id::Cervantes2013, Cervantes, "BQ Cervantes 2013", 6.0, HD, Imx).frontlight(),
+    DeviceInfo::new(Id::Cervantes3, Cervantes, "BQ Cervantes 3", 6.0, FHD, Imx).frontlight(),
+    DeviceInfo::new(Id::Cervantes4, Cervantes, "BQ Cervantes 4", 6.0, FHD, Imx).warmth(),
+    // Kobo
+    DeviceInfo::new(Id::KoboTouchA, Kobo, "Kobo Touch", 6.0, SD, Imx).quirks(&[SingleTouch]),
+    DeviceInfo::new(Id::KoboTouchB, Kobo, "Kobo Touch", 6.0, SD, Imx).quirks(&[SingleTouch]),
+    DeviceInfo::new(Id::KoboTouchC, Kobo, "Kobo Touch", 6.0, SD, Imx),
+    DeviceInfo::new(Id::KoboMini, Kobo, "Kobo Mini", 5.0, SD, Imx),
+    DeviceInfo::new(Id::KoboGlo, Kobo, "Kobo Glo", 6.0, HD, Imx).frontlight(),
+    DeviceInfo::new(Id::KoboGloHd, Kobo, "Kobo Glo HD", 6.0, FHD, Imx).frontlight(),
+    DeviceInfo::new(Id::TolinoShine2Hd, Tolino, "Tolino Shine 2 HD", 6.0, FHD, Imx).frontlight(),
+    DeviceInfo::new(Id::KoboTouch2, Kobo, "Kobo Touch 2.0", 6.0, SD, Imx),
+    DeviceInfo::new(Id::KoboAura, Kobo, "Kobo Aura", 6.0, HD, Imx).frontlight(),
+    DeviceInfo::new(Id::KoboAuraHd, Kobo, "Kobo Aura HD", 6.8, (1080, 1440), Imx).frontlight(),
+    DeviceInfo::new(Id::KoboAuraH2o, Kobo, "Kobo Aura H2O", 6.8, (1080, 1430), Imx).frontlight(),
+    DeviceInfo::new(Id::KoboAuraH2o2, Kobo, "Kobo Aura H2O Edition 2", 6.8, (1080, 1440), Imx).warmth(),
+    DeviceInfo::new(Id::KoboAuraH2o2R2, Kobo, "Kobo Aura H2O Edition 2", 6.8, (1080, 1440), Imx).warmth(),
+    DeviceInfo::new(Id::KoboAuraOne, Kobo, "Kobo Aura One", 7.8, CARTA, Imx).warmth(),
+    DeviceInfo::new(Id::KoboAuraOneLe, Kobo, "Kobo Aura One Limited Edition", 7.8, CARTA, Imx).warmth(),
+    DeviceInfo::new(Id::KoboAuraSe, Kobo, "Kobo Aura Edition 2", 6.0, HD, Imx).frontlight(),
+    DeviceInfo::new(Id::TolinoVision, Tolino, "Tolino Vision", 6.0, FHD, Imx).warmth(),
+    DeviceInfo::new(Id::KoboAuraSeR2, Kobo, "Kobo Aura Edition 2", 6.0, HD, Imx).frontlight(),
+    DeviceInfo::new(Id::KoboClaraHd, Kobo, "Kobo Clara HD", 6.0, FHD, Imx).warmth(),
+    DeviceInfo::new(Id::TolinoShine3, Tolino, "Tolino Shine 3", 6.0, FHD, Imx).warmth(),
+    DeviceInfo::new(Id::KoboForma, Kobo, "Kobo Forma", 8.0, FORMA, Imx).warmth(),
+    DeviceInfo::new(Id::TolinoEpos2, Tolino, "Tolino Epos 2", 8.0, FORMA, Imx).warmth(),
+    DeviceInfo::new(Id::KoboForma32, Kobo, "Kobo Forma 32GB", 8.0, FORMA, Imx).warmth(),
+    DeviceInfo::new(Id::KoboLibraH2o, Kobo, "Kobo Libra H2O", 7.0, LIBRA, Imx).warmth(),
+    DeviceInfo::new(Id::TolinoVision5, Tolino, "Tolino Vision 5", 7.0, LIBRA, Imx).warmth(),
+    DeviceInfo::new(Id::KoboNia, Kobo, "Kobo Nia", 6.0, HD, Imx).frontlight(),
+    DeviceInfo::new(Id::KoboElipsa, Kobo, "Kobo Elipsa", 10.3, CARTA, Sunxi).frontlight().stylus().quirks(SUNXI_QUIRKS),
+    DeviceInfo::new(Id::KoboLibra2, Kobo, "Kobo Libra 2", 7.0, LIBRA, Imx).warmth(),
+    DeviceInfo::new(Id::KoboSage, Kobo, "Kobo Sage", 8.0, FORMA, Sunxi).warmth().stylus().quirks(SUNXI_QUIRKS),
+    DeviceInfo::new(Id::TolinoEpos3, Tolino, "Tolino Epos 3", 8.0, FORMA, Sunxi).warmth().quirks(SUNXI_QUIRKS),
+    DeviceInfo::new(Id::KoboClara2e, Kobo, "Kobo Clara 2E", 6.0, FHD, Imx).warmth(),
+    DeviceInfo::new(Id::KoboElipsa2e, Kobo, "Kobo Elipsa 2E", 10.3, CARTA, Mtk).warmth().stylus(),
+    DeviceInfo::new(Id::KoboLibraColour, Kobo, "Kobo Libra Colour", 7.0, LIBRA, Mtk).warmth().stylus().color(Kaleido3).quirks(KALEIDO_QUIRKS),
+    DeviceInfo::new(Id::TolinoVisionColor, Tolino, "Tolino Vision Color", 7.0, LIBRA, Mtk).warmth().stylus().color(Kaleido3).quirks(KALEIDO_QUIRKS),
+    DeviceInfo::new(Id::KoboClaraBw, Kobo, "Kobo Clara BW", 6.0, FHD, Mtk).warmth(),
+    DeviceInfo::new(Id::TolinoShineBw, Tolino, "Tolino Shine", 6.0, FHD, Mtk).warmth(),
+    DeviceInfo::new(Id::KoboClaraColour, Kobo, "Kobo Clara Colour", 6.0, FHD, Mtk).warmth().color(Kaleido3).quirks(KALEIDO_QUIRKS),
+    DeviceInfo::new(Id::TolinoShineColor, Tolino, "Tolino Shine Color", 6.0, FHD, Mtk).warmth().color(Kaleido3).quirks(KALEIDO_QUIRKS),
+    // Tolinos running a mainline kernel
+    DeviceInfo::new(Id::MainlineTolinoShine2hd, Tolino, "Tolino Shine 2 HD", 6.0, FHD, Imx).frontlight(),
+    DeviceInfo::new(Id::MainlineTolinoShine3, Tolino, "Tolino Shine 3", 6.0, FHD, Imx).warmth(),
+    DeviceInfo::new(Id::MainlineTolinoVision, Tolino, "Tolino Vision", 6.0, FHD, Imx).warmth(),
+    DeviceInfo::new(Id::MainlineTolinoVision5, Tolino, "Tolino Vision 5", 7.0, LIBRA, Imx).warmth(),
+    // reMarkable
+    DeviceInfo::new(Id::Remarkable1, Remarkable, "reMarkable", 10.3, CARTA, Imx).stylus(),
+    DeviceInfo::new(Id::Remarkable2, Remarkable, "reMarkable 2", 10.3, CARTA, Imx).stylus(),
+    // PocketBook
+    DeviceInfo::new(Id::PocketbookMini, Pocketbook, "PocketBook Mini", 5.0, SD, Soc::Unknown),
+    DeviceInfo::new(Id::Pocketbook606, Pocketbook, "PocketBook Basic 4", 6.0, HD, Soc::Unknown),
+    DeviceInfo::new(Id::Pocketbook611, Pocketbook, "PocketBook Basic 2", 6.0, SD, Soc::Unknown),
+    DeviceInfo::new(Id::Pocketbook613, Pocketbook, "PocketBook Basic New", 6.0, HD, Soc::Unknown),
+    DeviceInfo::new(Id::Pocketbook614, Pocketbook, "PocketBook Basic 3", 6.0, SD, Soc::Unknown),
+    DeviceInfo::new(Id::Pocketbook615, Pocketbook, "PocketBook Basic Lux", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::Pocketbook616, Pocketbook, "PocketBook Basic Lux 2", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::Pocketbook617, Pocketbook, "PocketBook Basic Lux 3", 6.0, HD, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::Pocketbook618, Pocketbook, "PocketBook Basic Lux 4", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookTouch, Pocketbook, "PocketBook Touch", 6.0, SD, Soc::Unknown),
+    DeviceInfo::new(Id::PocketbookLux, Pocketbook, "PocketBook Touch Lux", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookBasicTouch, Pocketbook, "PocketBook Basic Touch", 6.0, SD, Soc::Unknown),
+    DeviceInfo::new(Id::PocketbookBasicTouch2, Pocketbook, "PocketBook Basic Touch 2", 6.0, SD, Soc::Unknown),
+    DeviceInfo::new(Id::PocketbookLux3, Pocketbook, "PocketBook Touch Lux 3", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookLux4, Pocketbook, "PocketBook Touch Lux 4", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookLux5, Pocketbook, "PocketBook Touch Lux 5", 6.0, HD, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookVerse, Pocketbook, "PocketBook Verse", 6.0, HD, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookSense, Pocketbook, "PocketBook Sense", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookTouchHd, Pocketbook, "PocketBook Touch HD", 6.0, FHD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookTouchHdPlus, Pocketbook, "PocketBook Touch HD Plus", 6.0, FHD, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookColor, Pocketbook, "PocketBook Color", 6.0, FHD, Soc::Unknown).frontlight().color(Kaleido).quirks(KALEIDO_QUIRKS),
+    DeviceInfo::new(Id::PocketbookVersePro, Pocketbook, "PocketBook Verse Pro", 6.0, FHD, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookAqua, Pocketbook, "PocketBook Aqua", 6.0, SD, Soc::Unknown),
+    DeviceInfo::new(Id::PocketbookAqua2, Pocketbook, "PocketBook Aqua 2", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookUltra, Pocketbook, "PocketBook Ultra", 6.0, HD, Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookEra, Pocketbook, "PocketBook Era", 7.0, LIBRA, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookEraColor, Pocketbook, "PocketBook Era Color", 7.0, LIBRA, Soc::Unknown).warmth().color(Kaleido3).quirks(KALEIDO_QUIRKS),
+    DeviceInfo::new(Id::PocketbookInkpad3, Pocketbook, "PocketBook InkPad 3", 7.8, CARTA, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookInkpad3Pro, Pocketbook, "PocketBook InkPad 3 Pro", 7.8, CARTA, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookInkpadColor, Pocketbook, "PocketBook InkPad Color", 7.8, CARTA, Soc::Unknown).warmth().color(Kaleido).quirks(KALEIDO_QUIRKS),
+    DeviceInfo::new(Id::PocketbookInkpadColor2, Pocketbook, "PocketBook InkPad Color 2", 7.8, CARTA, Soc::Unknown).warmth().color(KaleidoPlus).quirks(&[HalfResolutionColor, SharedDeviceId]),
+    DeviceInfo::new(Id::PocketbookInkpadColor3, Pocketbook, "PocketBook InkPad Color 3", 7.8, CARTA, Soc::Unknown).warmth().color(Kaleido3).quirks(KALEIDO_QUIRKS),
+    DeviceInfo::new(Id::PocketbookInkpad, Pocketbook, "PocketBook InkPad", 8.0, (1200, 1600), Soc::Unknown).frontlight(),
+    DeviceInfo::new(Id::PocketbookInkpadX, Pocketbook, "PocketBook InkPad X", 10.3, CARTA, Soc::Unknown).warmth(),
+    DeviceInfo::new(Id::PocketbookColorLux, Pocketbook, "PocketBook Color Lux", 8.0, SD, Soc::Unknown).frontlight().color(Triton),
+    DeviceInfo::new(Id::PocketbookInkpadLite, Pocketbook, "PocketBook InkPad Lite", 9.7, (825, 1200), Soc::Unknown),
+];
+
+/// Models that share their device id with an entry in [`DEVICES`], along with a lowercase
+/// substring of the codename FBInk reports for them
+#[rustfmt::skip]
+static ALIASES: &[(&str, DeviceInfo)] = &[
+    ("743g", DeviceInfo::new(Id::PocketbookInkpadColor2, Pocketbook, "PocketBook InkPad 4", 7.8, CARTA, Soc::Unknown).warmth().quirks(&[SharedDeviceId])),
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Display;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+    use crate::FbInkConfig;
+
+    #[test]
+    fn shared_id_resolved_by_codename() {
+        let id = Id::PocketbookInkpadColor2;
+        let inkpad4 = DeviceInfo::resolve(id, "PB743G").unwrap();
+        assert_eq!(inkpad4.name, "PocketBook InkPad 4");
+        assert!(!inkpad4.has_color());
+        assert!(inkpad4.has_quirk(SharedDeviceId));
+
+        let color2 = DeviceInfo::resolve(id, "PB743K").unwrap();
+        assert_eq!(color2.name, "PocketBook InkPad Color 2");
+        assert_eq!(color2.color_panel, Some(KaleidoPlus));
+        assert!(color2.has_quirk(SharedDeviceId));
+        assert!(color2.has_quirk(HalfResolutionColor));
+
+        // Without a codename, the id belongs to the model it was first assigned to
+        assert_eq!(DeviceInfo::lookup(id), Some(color2));
+        assert_eq!(DeviceInfo::resolve(id, ""), Some(color2));
+    }
+
+    #[test]
+    fn aliases_only_apply_to_their_id() {
+        let era = DeviceInfo::resolve(Id::PocketbookEra, "PB743G").unwrap();
+        assert_eq!(era.name, "PocketBook Era");
+        assert_eq!(DeviceInfo::resolve(Id::Unknown(0), "PB743G"), None);
+    }
+
+    #[test]
+    fn state_uses_the_codename() {
+        let fbink = VirtualFbInk::new(VirtualDevice::default(), FbInkConfig::default()).unwrap();
+        let mut state = fbink.state();
+        state.device_id = Id::PocketbookInkpadColor2;
+        state.device_codename = "PB743G".into();
+        assert_eq!(state.device_info().unwrap().name, "PocketBook InkPad 4");
+        state.device_codename = "PB743K".into();
+        assert_eq!(
+            state.device_info().unwrap().name,
+            "PocketBook InkPad Color 2"
+        );
+    }
+
+    #[test]
+    fn table_entries() {
+        let libra2 = DeviceInfo::lookup(Id::KoboLibra2).unwrap();
+        assert_eq!(libra2.vendor, Kobo);
+        assert_eq!((libra2.width, libra2.height), (1264, 1680));
+        assert_eq!(libra2.soc, Imx);
+        assert!(libra2.has_frontlight && libra2.has_warmth);
+        assert_eq!(libra2.dpi(), 300);
+
+        let elipsa = DeviceInfo::lookup(Id::KoboElipsa).unwrap();
+        assert_eq!(elipsa.soc, Sunxi);
+        assert!(elipsa.has_stylus && !elipsa.has_warmth);
+        assert!(elipsa.has_quirk(NoDirectDump));
+
+        let colour = DeviceInfo::lookup(Id::KoboLibraColour).unwrap();
+        assert_eq!(colour.color_panel, Some(Kaleido3));
+        assert!(colour.has_quirk(HalfResolutionColor));
+
+        let touch = DeviceInfo::lookup(Id::KoboTouchA).unwrap();
+        assert!(touch.has_quirk(SingleTouch));
+        assert!(!touch.has_frontlight);
+
+        let tolino = DeviceInfo::lookup(Id::TolinoEpos3).unwrap();
+        assert_eq!(tolino.vendor.as_ref(), "Tolino");
+        assert_eq!(Remarkable.to_string(), "reMarkable");
+        assert_eq!(Imx.to_string(), "i.MX");
+    }
+
+    #[test]
+    fn table_is_consistent() {
+        for (i, device) in DEVICES.iter().enumerate() {
+            assert!(
+                DEVICES[..i].iter().all(|other| other.id != device.id),
+                "{} is listed twice",
+                device.name
+            );
+            assert!(
+                device.width < device.height,
+                "{} isn't portrait",
+                device.name
+            );
+            assert_eq!(device.has_quirk(NoDirectDump), device.soc == Sunxi);
+        }
+        for (pattern, alias) in ALIASES {
+            assert_eq!(*pattern, pattern.to_ascii_lowercase());
+            let original = DeviceInfo::lookup(alias.id).unwrap();
+            assert!(original.has_quirk(SharedDeviceId), "{}", original.name);
+            assert!(alias.has_quirk(SharedDeviceId), "{}", alias.name);
+        }
+    }
+}
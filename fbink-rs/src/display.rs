@@ -0,0 +1,140 @@
+//! A common interface for drawing on a real framebuffer through FBInk or on an in-memory
+//! [`VirtualFbInk`](crate::virtual_fbink::VirtualFbInk), so apps can be tested without a device
+#[cfg(not(all(feature = "draw", feature = "bitmap", feature = "image")))]
+use crate::capabilities::Feature;
+use crate::coords::{Native, Rect, Rotated, View};
+use crate::dump::Dump;
+use crate::error::FbInkError;
+use crate::region::Region;
+use crate::thin::ReinitResult;
+use crate::{FbInk, FbInkConfig, FbInkState};
+
+/// The subset of [`FbInk`]'s methods that can also be emulated in memory
+pub trait Display {
+    /// Return the current state of the display
+    fn state(&self) -> FbInkState;
+    /// The configuration used by subsequent calls
+    fn config(&self) -> &FbInkConfig;
+    fn config_mut(&mut self) -> &mut FbInkConfig;
+    /// Re-initialize after changing config options that require it
+    fn reinit(&self) -> ReinitResult;
+    /// Print text with the current configuration. Returns number of rows printed on success
+    fn print(&self, msg: &str) -> Result<i32, FbInkError>;
+    /// Print text at the given coordinates. Returns number of rows printed on success
+    fn print_coords(&self, msg: &str, x: i16, y: i16) -> Result<i32, FbInkError>;
+    /// Print raw scanlines (packed pixels) into a rect of the viewport
+    fn print_raw_data(&self, data: &[u8], rect: Rect<View>) -> Result<(), FbInkError>;
+    /// Refresh a rect of the screen in the current rotation. An empty rect performs a full
+    /// refresh.
+    fn refresh(&self, rect: Rect<Rotated>) -> Result<(), FbInkError>;
+    /// Refresh a rect or region of the screen in the current rotation. Nearby rects are merged
+    /// first, and an empty region refreshes the whole screen.
+    fn refresh_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError>;
+    /// Clear the entire screen using the background pen color
+    fn cls(&self) -> Result<(), FbInkError>;
+    /// Clear a rect or region of the screen using the background pen color. An empty region
+    /// clears the whole screen.
+    fn cls_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError>;
+    /// Dump the contents of the framebuffer
+    fn dump(&self) -> Result<Box<dyn Dump>, FbInkError>;
+    /// Dump the bounds of a rect or region of the framebuffer's memory layout without any
+    /// rotation/positioning tricks
+    fn rect_dump(&self, region: &Region<Native>) -> Result<Box<dyn Dump>, FbInkError>;
+    /// Restore the contents of a dump back to the framebuffer
+    fn restore(&self, dump: &dyn Dump) -> Result<(), FbInkError>;
+    /// Get the coordinates & dimensions of the last thing drawn on the framebuffer
+    fn get_last_rect(&self) -> Rect<Rotated>;
+    /// Like get_last_rect but after any rotation tricks, as it was sent to the display driver
+    fn get_last_native_rect(&self) -> Rect<Native>;
+    /// The marker of the last refresh requested
+    fn get_last_marker(&self) -> u32;
+    /// Wait for the refresh with the given marker to complete
+    fn wait_for_complete(&self, marker: u32) -> Result<(), FbInkError>;
+}
+
+impl Display for FbInk {
+    fn state(&self) -> FbInkState {
+        FbInk::state(self)
+    }
+    fn config(&self) -> &FbInkConfig {
+        &self.config
+    }
+    fn config_mut(&mut self) -> &mut FbInkConfig {
+        &mut self.config
+    }
+    fn reinit(&self) -> ReinitResult {
+        FbInk::reinit(self)
+    }
+    #[cfg_attr(not(feature = "bitmap"), allow(unused_variables))]
+    fn print(&self, msg: &str) -> Result<i32, FbInkError> {
+        #[cfg(feature = "bitmap")]
+        return FbInk::print(self, msg);
+        #[cfg(not(feature = "bitmap"))]
+        Err(Feature::Bitmap.unsupported())
+    }
+    #[cfg_attr(not(feature = "bitmap"), allow(unused_variables))]
+    fn print_coords(&self, msg: &str, x: i16, y: i16) -> Result<i32, FbInkError> {
+        #[cfg(feature = "bitmap")]
+        return FbInk::print_coords(self, msg, x, y);
+        #[cfg(not(feature = "bitmap"))]
+        Err(Feature::Bitmap.unsupported())
+    }
+    #[cfg_attr(not(feature = "image"), allow(unused_variables))]
+    fn print_raw_data(&self, data: &[u8], rect: Rect<View>) -> Result<(), FbInkError> {
+        #[cfg(feature = "image")]
+        return FbInk::print_raw_data(self, data, rect);
+        #[cfg(not(feature = "image"))]
+        Err(Feature::Image.unsupported())
+    }
+    fn refresh(&self, rect: Rect<Rotated>) -> Result<(), FbInkError> {
+        FbInk::refresh(self, rect)
+    }
+    fn refresh_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        FbInk::refresh_rect(self, region)
+    }
+    fn cls(&self) -> Result<(), FbInkError> {
+        #[cfg(feature = "draw")]
+        return FbInk::cls(self);
+        #[cfg(not(feature = "draw"))]
+        Err(Feature::Draw.unsupported())
+    }
+    #[cfg_attr(not(feature = "draw"), allow(unused_variables))]
+    fn cls_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        #[cfg(feature = "draw")]
+        return FbInk::cls_rect(self, region);
+        #[cfg(not(feature = "draw"))]
+        Err(Feature::Draw.unsupported())
+    }
+    fn dump(&self) -> Result<Box<dyn Dump>, FbInkError> {
+        #[cfg(feature = "image")]
+        return Ok(Box::new(FbInk::dump(self)?));
+        #[cfg(not(feature = "image"))]
+        Err(Feature::Image.unsupported())
+    }
+    #[cfg_attr(not(feature = "image"), allow(unused_variables))]
+    fn rect_dump(&self, region: &Region<Native>) -> Result<Box<dyn Dump>, FbInkError> {
+        #[cfg(feature = "image")]
+        return Ok(Box::new(FbInk::rect_dump(self, region)?));
+        #[cfg(not(feature = "image"))]
+        Err(Feature::Image.unsupported())
+    }
+    #[cfg_attr(not(feature = "image"), allow(unused_variables))]
+    fn restore(&self, dump: &dyn Dump) -> Result<(), FbInkError> {
+        #[cfg(feature = "image")]
+        return FbInk::restore(self, dump);
+        #[cfg(not(feature = "image"))]
+        Err(Feature::Image.unsupported())
+    }
+    fn get_last_rect(&self) -> Rect<Rotated> {
+        FbInk::get_last_rect(self)
+    }
+    fn get_last_native_rect(&self) -> Rect<Native> {
+        FbInk::get_last_native_rect(self)
+    }
+    fn get_last_marker(&self) -> u32 {
+        FbInk::get_last_marker(self)
+    }
+    fn wait_for_complete(&self, marker: u32) -> Result<(), FbInkError> {
+        FbInk::wait_for_complete(self, marker)
+    }
+}
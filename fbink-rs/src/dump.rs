@@ -1,109 +1,421 @@
+#[cfg(feature = "image")]
+use crate::coords::View;
+use crate::coords::{Native, Point, Rect};
+use crate::region::{merge_rects, rect_bottom, rect_right, Region, MERGE_THRESHOLD};
+use crate::screenshot::{decode_rgb, encode_rgb};
+use crate::state::PixelFormat;
 use crate::thin::fbink_free_dump_data;
-use crate::thin::fbink_restore;
-use crate::{error::FbInkError, FbInk, FbInkRect};
+#[cfg(feature = "image")]
+use crate::thin::{fbink_restore, fbink_restore_raw};
+#[cfg(feature = "image")]
+use crate::FbInk;
+use crate::{error::FbInkError, FbInkRect, FbInkState};
 
+use std::alloc::Layout;
+use std::ffi::CString;
 use std::fs;
-use std::io::Cursor;
-use std::path::PathBuf;
-use std::process::Command;
-use std::slice;
+use std::io::{self, Cursor};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::{ptr, slice};
 
 use fbink_sys as raw;
-use image::{imageops, ColorType, DynamicImage, ImageFormat};
+use image::{imageops, DynamicImage, GenericImageView, ImageFormat, Rgba};
 use proc_mounts::MountIter;
 
 pub trait Dump {
     fn data(&self) -> &[u8];
     fn size(&self) -> usize;
     fn stride(&self) -> usize;
-    fn area(&self) -> FbInkRect;
-    fn clip(&self) -> FbInkRect;
+    /// The dumped rect, in the framebuffer's memory layout
+    fn area(&self) -> Rect<Native>;
+    /// The rect the dump has been cropped to, if it isn't [`full`](Self::is_full)
+    fn clip(&self) -> Rect<Native>;
     fn rota(&self) -> u8;
     fn bpp(&self) -> u8;
     fn is_full(&self) -> bool;
+    /// The pixel format used to decode the dump's data. With [`PixelFormat::Unknown`], 16, 24
+    /// and 32 bpp data is assumed to be in RGB order.
+    fn pixel_format(&self) -> PixelFormat {
+        PixelFormat::Unknown
+    }
     /// Crop the regions of the dump. Doesn't touch the actual data but affects calls to restore
-    fn crop(&mut self, left: u16, top: u16, width: u16, height: u16);
-    fn crop_rect(&mut self, rect: FbInkRect);
+    fn crop(&mut self, left: u16, top: u16, width: u16, height: u16) {
+        self.crop_rect(Rect::new(left, top, width, height));
+    }
+    fn crop_rect(&mut self, rect: Rect<Native>);
+    #[cfg(feature = "image")]
     fn restore(&self, fbink: &FbInk) -> Result<(), FbInkError>;
 
-    /// Clone the dump's data and convert it to a DynamicImage
+    /// Decode the dump's data into a new DynamicImage. If the dump has been cropped, only the
+    /// clipped region is decoded. Pixels are decoded like [`GenericImageView`] on a
+    /// [`FbInkDump`]: 4 and 8 bpp data becomes grayscale, 16 bpp data is RGB565 and alpha is
+    /// always opaque.
     fn dynamic_image(&self) -> Result<DynamicImage, FbInkError>;
-    /// Return a reference to a DynamicImage. If the dump is a SunxiDump this won't allocate.
-    /// Otherwise it will clone the data the first time it is called.
-    fn dynamic_image_ref(&mut self) -> Result<&DynamicImage, FbInkError>;
-    /// Encode the dump's data in the given image format and return the bytes
+    /// Like [`dynamic_image`](Self::dynamic_image), but rotated to the orientation the user
+    /// sees the screen in rather than the framebuffer's native layout
+    fn canonical_image(&self, state: &FbInkState) -> Result<DynamicImage, FbInkError> {
+        let image = self.dynamic_image()?;
+        Ok(rotate_image(image, state.display_turns(self.rota())))
+    }
+    /// Encode the dump's data in the given image format and return the bytes. If the dump has
+    /// been cropped, only the clipped region is encoded.
     fn encode(&self, encoding: ImageFormat) -> Result<Vec<u8>, FbInkError> {
-        let color_type = match self.bpp() {
-            32 => ColorType::Rgba8,
-            24 => ColorType::Rgb8,
-            // not sure if these are correct
-            16 => ColorType::L16,
-            8 => ColorType::L8,
-            _ => {
-                let msg = format!("Can't encode a dump with {} bpp", self.bpp());
-                return Err(FbInkError::NotSupported(msg));
-            }
-        };
         let mut writer = Cursor::new(Vec::new());
-        image::write_buffer_with_format(
-            &mut writer,
-            self.data(),
-            self.area().width.into(),
-            self.area().height.into(),
-            color_type,
-            encoding,
-        )?;
+        self.dynamic_image()?.write_to(&mut writer, encoding)?;
         Ok(writer.into_inner())
     }
-    /// Overlay an image on the dump and print it to the framebuffer
+    /// Copy a region of the dump into a new dump that only contains that region. The rect is
+    /// in the same (framebuffer) coordinates as the dump's area and must lie within it.
+    /// The data keeps the source's pixel format, so a region extracted from a [`SunxiDump`]
+    /// holds RGB data rather than the framebuffer's and can't be restored with fbink_restore.
+    fn extract(&self, rect: Rect<Native>) -> Result<OwnedDump, FbInkError> {
+        let area = self.area();
+        let bpp = usize::from(self.bpp());
+        if rect.left < area.left
+            || rect.top < area.top
+            || rect_right(rect.into()) > rect_right(area.into())
+            || rect_bottom(rect.into()) > rect_bottom(area.into())
+        {
+            let msg = format!("{rect:?} is outside the dump's area {area:?}");
+            return Err(FbInkError::OutOfRange(msg));
+        }
+        if bpp == 0 || (bpp % 8 != 0 && bpp != 4) {
+            let msg = format!("Can't extract from a dump with {bpp} bpp");
+            return Err(FbInkError::NotSupported(msg));
+        }
+        let x = usize::from(rect.left - area.left);
+        let y = usize::from(rect.top - area.top);
+        let (width, height) = (usize::from(rect.width), usize::from(rect.height));
+        let stride = (width * bpp).div_ceil(8);
+        let src_stride = self.stride();
+        let src = self.data();
+        let mut data = vec![0; stride * height];
+        for (row, dst) in data
+            .chunks_exact_mut(stride.max(1))
+            .enumerate()
+            .take(height)
+        {
+            let src_row = (y + row) * src_stride;
+            if bpp % 8 == 0 {
+                let start = src_row + x * bpp / 8;
+                let Some(src) = src.get(start..start + stride) else {
+                    let msg = "dump has less data than its area requires".into();
+                    return Err(FbInkError::InvalidArgument(msg));
+                };
+                dst.copy_from_slice(src);
+            } else {
+                // 4bpp packs two pixels per byte, the even pixel in the high nibble
+                for col in 0..width {
+                    let src_x = x + col;
+                    let Some(byte) = src.get(src_row + src_x / 2) else {
+                        let msg = "dump has less data than its area requires".into();
+                        return Err(FbInkError::InvalidArgument(msg));
+                    };
+                    let nibble = if src_x.is_multiple_of(2) {
+                        byte >> 4
+                    } else {
+                        byte & 0x0F
+                    };
+                    dst[col / 2] |= if col.is_multiple_of(2) {
+                        nibble << 4
+                    } else {
+                        nibble
+                    };
+                }
+            }
+        }
+        let dump = OwnedDump::new(data, stride, rect, self.rota(), self.bpp())?;
+        Ok(dump.with_pixel_format(self.pixel_format()))
+    }
+    #[cfg(feature = "image")]
+    /// Overlay an image on the dump and print it to the framebuffer. The offsets are relative
+    /// to the dump's image (i.e. the clip if it has been cropped), and the overlay must lie
+    /// within the dump's area. Only the part of the dump under the overlay is decoded.
     fn print_overlay(
-        &mut self,
+        &self,
         fbink: &FbInk,
         overlay: &DynamicImage,
         x_offset: u32,
         y_offset: u32,
     ) -> Result<(), FbInkError> {
-        let mut to_print = self.dynamic_image_ref()?.crop_imm(
-            x_offset,
-            y_offset,
-            overlay.width(),
-            overlay.height(),
-        );
-        image::imageops::overlay(&mut to_print, overlay, 0, 0);
+        // The image starts at the clip's origin if the dump has been cropped
+        let origin = if self.is_full() {
+            self.area()
+        } else {
+            self.clip()
+        };
+        let x_offset = x_offset + u32::from(origin.left);
+        let y_offset = y_offset + u32::from(origin.top);
         let (Ok(width), Ok(height)) = (overlay.width().try_into(), overlay.height().try_into())
         else {
-            return Err(FbInkError::SunxiDumpError);
+            let msg = format!(
+                "{}x{} overlay is too large",
+                overlay.width(),
+                overlay.height()
+            );
+            return Err(FbInkError::OutOfRange(msg));
         };
         let (Ok(x), Ok(y)) = (x_offset.try_into(), y_offset.try_into()) else {
-            return Err(FbInkError::SunxiDumpError);
+            let msg = format!("overlay offset {x_offset},{y_offset} is too large");
+            return Err(FbInkError::OutOfRange(msg));
         };
-        fbink.print_raw_data(to_print.as_bytes(), width, height, x, y)
+        let rect = Rect::<Native>::new(x, y, width, height);
+        let mut to_print = self.extract(rect)?.dynamic_image()?;
+        imageops::overlay(&mut to_print, overlay, 0, 0);
+        fbink.print_raw_data(to_print.as_bytes(), rect.to::<View>(&fbink.state()))
+    }
+    /// Compare with another dump of the same geometry and return a region covering the changed
+    /// pixels, using the default [`DiffOptions`]. Convert the region's rects to
+    /// [`Rotated`](crate::coords::Rotated) before refreshing them.
+    fn diff(&self, other: &dyn Dump) -> Region<Native> {
+        self.diff_with(other, &DiffOptions::default())
+    }
+    /// Like [`Dump::diff`] but with configurable tile size and merge threshold. If the dumps
+    /// don't share the same dimensions and bpp, the entire area of this dump is returned. The
+    /// region covers the merged rects, so it includes the unchanged pixels they take in.
+    fn diff_with(&self, other: &dyn Dump, options: &DiffOptions) -> Region<Native> {
+        let area = self.area();
+        let other_area = other.area();
+        if area.width != other_area.width
+            || area.height != other_area.height
+            || self.bpp() != other.bpp()
+            || self.bpp() == 0
+        {
+            return area.into();
+        }
+        let tile = usize::from(options.tile_size.max(1));
+        let (width, height) = (usize::from(area.width), usize::from(area.height));
+        let mut rects = Vec::new();
+        for y in (0..height).step_by(tile) {
+            for x in (0..width).step_by(tile) {
+                let (w, h) = (tile.min(width - x), tile.min(height - y));
+                if let Some(rect) = changed_bounds(self, other, x, y, w, h) {
+                    rects.push(rect);
+                }
+            }
+        }
+        let mut rects = merge_rects(rects, options.merge_threshold);
+        for rect in rects.iter_mut() {
+            rect.left += area.left;
+            rect.top += area.top;
+        }
+        Region::from_raw(rects)
     }
 }
 
-#[derive(Debug, Clone)]
+/// Options for [`Dump::diff_with`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DiffOptions {
+    /// Width & height of the tiles the dumps are compared in. Smaller tiles produce tighter
+    /// rects at the cost of more of them.
+    pub tile_size: u16,
+    /// Merge two rects if the unchanged pixels they'd cover when merged make up no more than
+    /// this fraction of the merged rect. 0.0 only merges when nothing is wasted.
+    pub merge_threshold: f32,
+}
+
+impl Default for DiffOptions {
+    fn default() -> Self {
+        Self {
+            tile_size: 32,
+            merge_threshold: MERGE_THRESHOLD,
+        }
+    }
+}
+
+/// The bounding rect of the changed pixels within a single tile, if any
+fn changed_bounds<A: Dump + ?Sized>(
+    a: &A,
+    b: &dyn Dump,
+    x: usize,
+    y: usize,
+    w: usize,
+    h: usize,
+) -> Option<FbInkRect> {
+    let bpp = usize::from(a.bpp());
+    let (a_data, b_data) = (a.data(), b.data());
+    let (a_stride, b_stride) = (a.stride(), b.stride());
+    let start = x * bpp / 8;
+    let end = ((x + w) * bpp).div_ceil(8);
+    let (mut min_x, mut max_x, mut min_y, mut max_y) = (usize::MAX, 0, usize::MAX, 0);
+    for row in y..y + h {
+        let a_row = a_data.get(row * a_stride + start..row * a_stride + end);
+        let b_row = b_data.get(row * b_stride + start..row * b_stride + end);
+        let (first, last) = match (a_row, b_row) {
+            (Some(a_row), Some(b_row)) => {
+                if a_row == b_row {
+                    continue;
+                }
+                let first = a_row.iter().zip(b_row).position(|(a, b)| a != b);
+                let last = a_row.iter().zip(b_row).rposition(|(a, b)| a != b);
+                let (first, last) = (first.unwrap_or(0), last.unwrap_or(0));
+                // Convert byte offsets to pixels, clamped to the tile for sub-byte pixels
+                let first = ((start + first) * 8 / bpp).max(x);
+                let last = (((start + last) * 8 + 7) / bpp).min(x + w - 1);
+                (first, last)
+            }
+            // Treat missing data as changed
+            _ => (x, x + w - 1),
+        };
+        min_x = min_x.min(first);
+        max_x = max_x.max(last);
+        min_y = min_y.min(row);
+        max_y = row;
+    }
+    if min_y == usize::MAX {
+        return None;
+    }
+    Some(FbInkRect {
+        left: min_x as u16,
+        top: min_y as u16,
+        width: (max_x - min_x + 1) as u16,
+        height: (max_y - min_y + 1) as u16,
+    })
+}
+
+/// Check that a dump's data can be decoded: the bpp must be one [`decode_rgb`] supports and
+/// there must be enough data for the area
+fn check_layout(len: usize, stride: usize, area: Rect<Native>, bpp: u8) -> Result<(), FbInkError> {
+    if !matches!(bpp, 4 | 8 | 16 | 24 | 32) {
+        return Err(FbInkError::NotSupported(format!("{bpp} bpp dumps")));
+    }
+    let row_len = (usize::from(area.width) * usize::from(bpp)).div_ceil(8);
+    if stride < row_len || len < stride * usize::from(area.height) {
+        let msg = format!(
+            "{len} bytes with a stride of {stride} is too small for a {}x{} area at {bpp} bpp",
+            area.width, area.height
+        );
+        return Err(FbInkError::InvalidArgument(msg));
+    }
+    Ok(())
+}
+
+/// The part of the dump's data an image of it covers, as an offset into the data: the whole
+/// area, or the clip of a cropped dump limited to the area
+fn view_rect<D: Dump + ?Sized>(dump: &D) -> FbInkRect {
+    let area = dump.area();
+    if dump.is_full() {
+        return Rect::<Native>::new(0, 0, area.width, area.height).into();
+    }
+    let clip = dump.clip();
+    let left = clip.left.saturating_sub(area.left).min(area.width);
+    let top = clip.top.saturating_sub(area.top).min(area.height);
+    FbInkRect {
+        left,
+        top,
+        width: clip.width.min(area.width - left),
+        height: clip.height.min(area.height - top),
+    }
+}
+
+/// A dump of the framebuffer. Also implements [`GenericImageView`], decoding pixels straight
+/// from the framebuffer data so [`imageops`] functions can run on it without copying the frame.
+#[derive(Debug)]
 pub struct FbInkDump {
     raw: raw::FBInkDump,
-    image: Option<DynamicImage>,
+    pixel_format: PixelFormat,
 }
 
+// The pixel data is owned exclusively by each FbInkDump (clones get their own copy)
+// and is only ever read through a shared reference, so it's safe to move between threads.
+unsafe impl Send for FbInkDump {}
+unsafe impl Sync for FbInkDump {}
+
 impl Drop for FbInkDump {
     fn drop(&mut self) {
-        fbink_free_dump_data(&mut self.raw).unwrap();
+        if !self.raw.data.is_null() {
+            // Nothing sensible can be done if this fails, and panicking in drop could abort
+            let _ = fbink_free_dump_data(&mut self.raw);
+        }
+    }
+}
+
+impl Clone for FbInkDump {
+    /// Deep-copy the pixel data so that each dump frees its own buffer
+    fn clone(&self) -> Self {
+        let mut raw = self.raw;
+        if !self.raw.data.is_null() && self.raw.size > 0 {
+            // FBInk releases the data with free(), so the copy must be allocated with malloc
+            let data = unsafe { libc::malloc(self.raw.size) } as *mut u8;
+            if data.is_null() {
+                let layout = Layout::array::<u8>(self.raw.size).unwrap();
+                std::alloc::handle_alloc_error(layout);
+            }
+            unsafe { ptr::copy_nonoverlapping(self.raw.data, data, self.raw.size) };
+            raw.data = data;
+        } else {
+            raw.data = ptr::null_mut();
+        }
+        Self {
+            raw,
+            pixel_format: self.pixel_format,
+        }
     }
 }
 
 impl FbInkDump {
-    pub fn new(raw: raw::FBInkDump) -> Self {
-        Self { raw, image: None }
+    /// Take ownership of a dump from FBInk. Fails, freeing the data, if its bpp can't be
+    /// decoded or it has less data than its area requires.
+    pub fn new(raw: raw::FBInkDump) -> Result<Self, FbInkError> {
+        let dump = Self {
+            raw,
+            pixel_format: PixelFormat::Unknown,
+        };
+        check_layout(dump.size(), dump.stride(), dump.area(), dump.bpp())?;
+        Ok(dump)
     }
     pub fn as_raw(&self) -> &raw::FBInkDump {
         &self.raw
     }
+    /// The pixel format of the framebuffer the dump was taken from. Dumps taken through
+    /// [`FbInk`] have this set from its state.
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+    /// Set the pixel format used to decode the dump's data. With [`PixelFormat::Unknown`],
+    /// 16, 24 and 32 bpp data is assumed to be in RGB order.
+    pub fn with_pixel_format(mut self, pixel_format: PixelFormat) -> Self {
+        self.pixel_format = pixel_format;
+        self
+    }
+}
+
+impl GenericImageView for FbInkDump {
+    type Pixel = Rgba<u8>;
+
+    fn dimensions(&self) -> (u32, u32) {
+        let rect = view_rect(self);
+        (rect.width.into(), rect.height.into())
+    }
+
+    /// Decode a single pixel of the dump (or its clip, if cropped). Grayscale data is returned
+    /// as stored, without accounting for an inverted panel. Alpha is always opaque.
+    fn get_pixel(&self, x: u32, y: u32) -> Self::Pixel {
+        let rect = view_rect(self);
+        assert!(
+            x < rect.width.into() && y < rect.height.into(),
+            "pixel ({x}, {y}) is outside the {}x{} dump",
+            rect.width,
+            rect.height
+        );
+        let (x, y) = (
+            usize::from(rect.left) + x as usize,
+            usize::from(rect.top) + y as usize,
+        );
+        let row = self.data().get(y * self.stride()..).unwrap_or_default();
+        // The bpp and data size were checked when the dump was created
+        let [r, g, b] = decode_rgb(row, x, self.bpp(), self.pixel_format).unwrap_or_default();
+        Rgba([r, g, b, u8::MAX])
+    }
 }
 
 impl Dump for FbInkDump {
     fn data(&self) -> &[u8] {
+        if self.raw.data.is_null() {
+            return &[];
+        }
         unsafe { slice::from_raw_parts(self.raw.data, self.raw.size) }
     }
     fn size(&self) -> usize {
@@ -112,11 +424,11 @@ impl Dump for FbInkDump {
     fn stride(&self) -> usize {
         self.raw.stride
     }
-    fn area(&self) -> FbInkRect {
-        self.raw.area
+    fn area(&self) -> Rect<Native> {
+        Rect::from_raw(self.raw.area)
     }
-    fn clip(&self) -> FbInkRect {
-        self.raw.clip
+    fn clip(&self) -> Rect<Native> {
+        Rect::from_raw(self.raw.clip)
     }
     fn rota(&self) -> u8 {
         self.raw.rota
@@ -127,65 +439,376 @@ impl Dump for FbInkDump {
     fn is_full(&self) -> bool {
         self.raw.is_full
     }
-    fn crop(&mut self, left: u16, top: u16, width: u16, height: u16) {
-        self.raw.clip.left = left;
-        self.raw.clip.top = top;
-        self.raw.clip.width = width;
-        self.raw.clip.height = height;
-        self.raw.is_full = false;
+    fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
     }
-    fn crop_rect(&mut self, rect: FbInkRect) {
-        self.raw.clip = rect;
+    fn crop_rect(&mut self, rect: Rect<Native>) {
+        self.raw.clip = rect.into();
         self.raw.is_full = false;
     }
+    #[cfg(feature = "image")]
     fn restore(&self, fbink: &FbInk) -> Result<(), FbInkError> {
+        fbink.check_config(&fbink.config)?;
         fbink_restore(fbink.fbfd, &fbink.config, self)
     }
-    fn dynamic_image_ref(&mut self) -> Result<&DynamicImage, FbInkError> {
-        if self.image.is_none() {
-            self.image = Some(self.dynamic_image()?);
+    fn dynamic_image(&self) -> Result<DynamicImage, FbInkError> {
+        let view = view_rect(self);
+        raw_to_image(
+            self.data(),
+            view,
+            self.stride(),
+            self.bpp(),
+            self.pixel_format,
+        )
+    }
+}
+
+/// A dump that owns its data in Rust-allocated memory, e.g. a region extracted from another
+/// dump. Restored with the same semantics as a [`FbInkDump`].
+#[derive(Debug, Clone)]
+pub struct OwnedDump {
+    data: Vec<u8>,
+    stride: usize,
+    area: Rect<Native>,
+    clip: Rect<Native>,
+    rota: u8,
+    bpp: u8,
+    pixel_format: PixelFormat,
+    is_full: bool,
+}
+
+impl OwnedDump {
+    /// Create a dump from packed pixel data in the framebuffer's format. Fails if the bpp
+    /// can't be decoded or the data is too small for the area.
+    pub fn new(
+        data: Vec<u8>,
+        stride: usize,
+        area: Rect<Native>,
+        rota: u8,
+        bpp: u8,
+    ) -> Result<Self, FbInkError> {
+        check_layout(data.len(), stride, area, bpp)?;
+        Ok(Self {
+            data,
+            stride,
+            area,
+            clip: Rect::default(),
+            rota,
+            bpp,
+            pixel_format: PixelFormat::Unknown,
+            is_full: true,
+        })
+    }
+    /// Set the pixel format used to decode the dump's data
+    pub fn with_pixel_format(mut self, pixel_format: PixelFormat) -> Self {
+        self.pixel_format = pixel_format;
+        self
+    }
+    #[cfg(feature = "image")]
+    /// A raw FBInkDump borrowing this dump's data, for passing to fbink_restore.
+    /// FBInk never writes to or frees the data of a dump it's restoring.
+    fn as_raw(&self) -> raw::FBInkDump {
+        raw::FBInkDump {
+            data: self.data.as_ptr() as *mut u8,
+            stride: self.stride,
+            size: self.data.len(),
+            area: self.area.into(),
+            clip: self.clip.into(),
+            rota: self.rota,
+            bpp: self.bpp,
+            is_full: self.is_full,
         }
-        Ok(self.image.as_ref().unwrap())
+    }
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+impl Dump for OwnedDump {
+    fn data(&self) -> &[u8] {
+        &self.data
+    }
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+    fn stride(&self) -> usize {
+        self.stride
+    }
+    fn area(&self) -> Rect<Native> {
+        self.area
+    }
+    fn clip(&self) -> Rect<Native> {
+        self.clip
+    }
+    fn rota(&self) -> u8 {
+        self.rota
+    }
+    fn bpp(&self) -> u8 {
+        self.bpp
+    }
+    fn is_full(&self) -> bool {
+        self.is_full
+    }
+    fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+    fn crop_rect(&mut self, rect: Rect<Native>) {
+        self.clip = rect;
+        self.is_full = false;
+    }
+    #[cfg(feature = "image")]
+    fn restore(&self, fbink: &FbInk) -> Result<(), FbInkError> {
+        fbink.check_config(&fbink.config)?;
+        fbink_restore_raw(fbink.fbfd, &fbink.config, &self.as_raw())
     }
     fn dynamic_image(&self) -> Result<DynamicImage, FbInkError> {
-        type Gray16Image = image::ImageBuffer<image::Luma<u16>, Vec<u16>>;
-        let width = self.area().width as u32;
-        let height = self.area().height as u32;
-        let err_msg = "Unable to convert dump to DynamicImage";
-        let image = match self.bpp() {
-            32 => {
-                let buf = self.data().to_vec();
-                DynamicImage::ImageRgba8(image::RgbaImage::from_raw(width, height, buf).unwrap())
-            }
-            24 => {
-                let buf = self.data().to_vec();
-                DynamicImage::ImageRgb8(image::RgbImage::from_raw(width, height, buf).unwrap())
-            }
-            8 => {
-                let buf = self.data().to_vec();
-                DynamicImage::ImageLuma8(image::GrayImage::from_raw(width, height, buf).unwrap())
-            }
-            // Not sure if this is the correct thing to do.
-            16 => {
-                let (prefix, shorts, suffix) = unsafe { self.data().align_to::<u16>() };
-                if !prefix.is_empty() || !suffix.is_empty() {
-                    return Err(FbInkError::NotSupported(err_msg.into()));
+        let view = view_rect(self);
+        raw_to_image(&self.data, view, self.stride, self.bpp, self.pixel_format)
+    }
+}
+
+/// The fields a dump is serialized with. Any kind of dump can be serialized, and they all
+/// deserialize as an [`OwnedDump`].
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerializedDump<D> {
+    data: D,
+    stride: usize,
+    area: Rect<Native>,
+    clip: Rect<Native>,
+    rota: u8,
+    bpp: u8,
+    #[serde(default = "unknown_pixel_format")]
+    pixel_format: PixelFormat,
+    is_full: bool,
+}
+
+/// Dumps serialized before the pixel format was recorded are decoded as RGB
+#[cfg(feature = "serde")]
+fn unknown_pixel_format() -> PixelFormat {
+    PixelFormat::Unknown
+}
+
+#[cfg(feature = "serde")]
+fn serialize_dump<D, S>(dump: &D, serializer: S) -> Result<S::Ok, S::Error>
+where
+    D: Dump + ?Sized,
+    S: serde::Serializer,
+{
+    let dump = SerializedDump {
+        data: dump.data(),
+        stride: dump.stride(),
+        area: dump.area(),
+        clip: dump.clip(),
+        rota: dump.rota(),
+        bpp: dump.bpp(),
+        pixel_format: dump.pixel_format(),
+        is_full: dump.is_full(),
+    };
+    serde::Serialize::serialize(&dump, serializer)
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for FbInkDump {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_dump(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for OwnedDump {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_dump(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for ImageDump {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_dump(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for SunxiDump {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_dump(self, serializer)
+    }
+}
+
+/// Checked like [`OwnedDump::new`], so the data must be large enough for the area
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for OwnedDump {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let dump = SerializedDump::<Vec<u8>>::deserialize(deserializer)?;
+        let mut owned = OwnedDump::new(dump.data, dump.stride, dump.area, dump.rota, dump.bpp)
+            .map_err(serde::de::Error::custom)?
+            .with_pixel_format(dump.pixel_format);
+        if !dump.is_full {
+            owned.crop_rect(dump.clip);
+        }
+        Ok(owned)
+    }
+}
+
+/// A dump created from an image rather than the framebuffer, e.g. a pre-rendered screen loaded
+/// from disk. The image is converted to the framebuffer's bpp and pixel format up front, so it
+/// can be cropped and restored exactly as if it had been dumped.
+#[derive(Debug, Clone)]
+pub struct ImageDump {
+    dump: OwnedDump,
+}
+
+impl ImageDump {
+    /// Convert an image to be restored at the top left of the framebuffer
+    pub fn new(image: &DynamicImage, state: &FbInkState) -> Result<Self, FbInkError> {
+        Self::at(image, Point::default(), state)
+    }
+    /// Convert an image to be restored at the given position. Like a dump, the image and
+    /// position are in the framebuffer's layout for its current rotation, not the canonical
+    /// orientation. Transparent pixels are blended against white.
+    pub fn at(
+        image: &DynamicImage,
+        origin: Point<Native>,
+        state: &FbInkState,
+    ) -> Result<Self, FbInkError> {
+        let bpp = state.bpp as u8;
+        if !matches!(bpp, 4 | 8 | 16 | 24 | 32) {
+            let msg = format!("Can't convert an image to {bpp} bpp");
+            return Err(FbInkError::NotSupported(msg));
+        }
+        let (Ok(width), Ok(height)) = (image.width().try_into(), image.height().try_into()) else {
+            let msg = format!("{}x{} image is too large", image.width(), image.height());
+            return Err(FbInkError::OutOfRange(msg));
+        };
+        let area = Rect::new(origin.x, origin.y, width, height);
+        if rect_right(area.into()) > state.screen_width
+            || rect_bottom(area.into()) > state.screen_height
+        {
+            let msg = format!(
+                "{width}x{height} image at {},{} doesn't fit on a {}x{} screen",
+                origin.x, origin.y, state.screen_width, state.screen_height
+            );
+            return Err(FbInkError::OutOfRange(msg));
+        }
+        let invert = state.inverted_grayscale
+            && matches!(state.pixel_format, PixelFormat::Y4 | PixelFormat::Y8);
+        let stride = (usize::from(width) * usize::from(bpp)).div_ceil(8);
+        let mut data = vec![0; stride * usize::from(height)];
+        let rgba = image.to_rgba8();
+        for (y, row) in rgba.rows().enumerate() {
+            let out = &mut data[y * stride..(y + 1) * stride];
+            for (x, p) in row.enumerate() {
+                let [r, g, b, a] = p.0;
+                let blend = |c: u8| {
+                    let c = u16::from(c) * u16::from(a) + 255 * (255 - u16::from(a));
+                    (c / 255) as u8
+                };
+                let mut rgb = [blend(r), blend(g), blend(b)];
+                if invert {
+                    rgb = rgb.map(|c| 255 - c);
                 }
-                let buf = shorts.to_vec();
-                DynamicImage::ImageLuma16(Gray16Image::from_raw(width, height, buf).unwrap())
+                encode_rgb(out, x, bpp, state.pixel_format, rgb).ok_or_else(|| {
+                    FbInkError::NotSupported(format!("Can't convert an image to {bpp} bpp"))
+                })?;
             }
-            _ => return Err(FbInkError::NotSupported(err_msg.into())),
-        };
-        Ok(image)
+        }
+        let dump = OwnedDump::new(data, stride, area, state.current_rota, bpp)?
+            .with_pixel_format(state.pixel_format);
+        Ok(Self { dump })
+    }
+    /// The pixel format the image was converted to
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.dump.pixel_format()
+    }
+    pub fn into_data(self) -> Vec<u8> {
+        self.dump.into_data()
+    }
+}
+
+impl Dump for ImageDump {
+    fn data(&self) -> &[u8] {
+        self.dump.data()
+    }
+    fn size(&self) -> usize {
+        self.dump.size()
+    }
+    fn stride(&self) -> usize {
+        self.dump.stride()
+    }
+    fn area(&self) -> Rect<Native> {
+        self.dump.area()
+    }
+    fn clip(&self) -> Rect<Native> {
+        self.dump.clip()
+    }
+    fn rota(&self) -> u8 {
+        self.dump.rota()
+    }
+    fn bpp(&self) -> u8 {
+        self.dump.bpp()
+    }
+    fn is_full(&self) -> bool {
+        self.dump.is_full()
+    }
+    fn pixel_format(&self) -> PixelFormat {
+        self.dump.pixel_format()
+    }
+    fn crop_rect(&mut self, rect: Rect<Native>) {
+        self.dump.crop_rect(rect)
+    }
+    #[cfg(feature = "image")]
+    fn restore(&self, fbink: &FbInk) -> Result<(), FbInkError> {
+        self.dump.restore(fbink)
+    }
+    fn dynamic_image(&self) -> Result<DynamicImage, FbInkError> {
+        self.dump.dynamic_image()
+    }
+}
+
+/// Decode a rect of packed framebuffer data into a DynamicImage with [`decode_rgb`], dropping
+/// any padding at the end of rows. 4 and 8 bpp data becomes grayscale, 16 and 24 bpp RGB and
+/// 32 bpp RGBA with opaque alpha.
+fn raw_to_image(
+    data: &[u8],
+    rect: FbInkRect,
+    stride: usize,
+    bpp: u8,
+    format: PixelFormat,
+) -> Result<DynamicImage, FbInkError> {
+    let err = || FbInkError::NotSupported(format!("Unable to convert {bpp} bpp dump to an image"));
+    let (width, height) = (u32::from(rect.width), u32::from(rect.height));
+    let (left, top) = (usize::from(rect.left), usize::from(rect.top));
+    let channels = match bpp {
+        4 | 8 => 1,
+        16 | 24 => 3,
+        32 => 4,
+        _ => return Err(err()),
+    };
+    let mut buf = Vec::with_capacity(width as usize * height as usize * channels);
+    for y in top..top + height as usize {
+        let row = data.get(y * stride..).ok_or_else(err)?;
+        for x in left..left + width as usize {
+            let rgb = decode_rgb(row, x, bpp, format).ok_or_else(err)?;
+            buf.extend_from_slice(&rgb[..channels.min(3)]);
+            if channels == 4 {
+                buf.push(u8::MAX);
+            }
+        }
     }
+    let image = match channels {
+        1 => image::GrayImage::from_raw(width, height, buf).map(DynamicImage::ImageLuma8),
+        3 => image::RgbImage::from_raw(width, height, buf).map(DynamicImage::ImageRgb8),
+        _ => image::RgbaImage::from_raw(width, height, buf).map(DynamicImage::ImageRgba8),
+    };
+    image.ok_or_else(err)
 }
 
 pub struct SunxiDump {
     image: DynamicImage,
-    clip: FbInkRect,
-    area: FbInkRect,
+    clip: Rect<Native>,
+    area: Rect<Native>,
     rota: u8,
-    bpp: u8,
     is_full: bool,
 }
 
@@ -199,14 +822,14 @@ impl Dump for SunxiDump {
     }
 
     fn stride(&self) -> usize {
-        self.image.width() as usize * (self.bpp() as usize / 8)
+        self.image.width() as usize * self.image.color().bytes_per_pixel() as usize
     }
 
-    fn area(&self) -> FbInkRect {
+    fn area(&self) -> Rect<Native> {
         self.area
     }
 
-    fn clip(&self) -> FbInkRect {
+    fn clip(&self) -> Rect<Native> {
         self.clip
     }
 
@@ -215,96 +838,442 @@ impl Dump for SunxiDump {
     }
 
     fn bpp(&self) -> u8 {
-        self.bpp
+        self.image.color().bits_per_pixel() as u8
     }
 
     fn is_full(&self) -> bool {
         self.is_full
     }
 
-    fn dynamic_image_ref(&mut self) -> Result<&DynamicImage, FbInkError> {
-        Ok(&self.image)
-    }
-
     fn dynamic_image(&self) -> Result<DynamicImage, FbInkError> {
-        Ok(self.image.clone())
+        if self.is_full {
+            return Ok(self.image.clone());
+        }
+        let c = self.clip;
+        let x = c.left.saturating_sub(self.area.left);
+        let y = c.top.saturating_sub(self.area.top);
+        Ok(self
+            .image
+            .crop_imm(x.into(), y.into(), c.width.into(), c.height.into()))
     }
 
-    fn crop(&mut self, left: u16, top: u16, width: u16, height: u16) {
-        self.clip.left = left;
-        self.clip.top = top;
-        self.clip.width = width;
-        self.clip.height = height;
-        self.is_full = false;
-    }
-    fn crop_rect(&mut self, rect: FbInkRect) {
+    fn crop_rect(&mut self, rect: Rect<Native>) {
         self.clip = rect;
         self.is_full = false;
     }
+    #[cfg(feature = "image")]
     fn restore(&self, fbink: &FbInk) -> Result<(), FbInkError> {
+        let state = fbink.state();
         if self.is_full {
-            let (width, height) = (self.area.width.into(), self.area.height.into());
-            fbink.print_raw_data(self.data(), width, height, 0, 0)
+            fbink.print_raw_data(self.data(), self.area.to(&state))
         } else {
+            // The clip is in framebuffer coordinates, the image's origin is the area's
             let c = self.clip;
-            let cropped =
-                self.image
-                    .crop_imm(c.left.into(), c.top.into(), c.width.into(), c.height.into());
-            let (width, height) = (c.width.into(), c.height.into());
-            let (Ok(x), Ok(y)) = (c.left.try_into(), c.top.try_into()) else {
-                return Err(FbInkError::SunxiDumpError);
-            };
-            fbink.print_raw_data(cropped.as_bytes(), width, height, x, y)
+            let x = c.left.saturating_sub(self.area.left);
+            let y = c.top.saturating_sub(self.area.top);
+            let cropped = self
+                .image
+                .crop_imm(x.into(), y.into(), c.width.into(), c.height.into());
+            let rect = Rect::<Native>::new(
+                c.left,
+                c.top,
+                cropped.width() as u16,
+                cropped.height() as u16,
+            );
+            fbink.print_raw_data(cropped.as_bytes(), rect.to(&state))
+        }
+    }
+}
+
+/// Options for capturing a [`SunxiDump`]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SunxiDumpOptions {
+    /// Where the display driver writes `workingbuffer.bmp`. A tmpfs is mounted here if there
+    /// isn't one already, so the image is written to memory rather than flash storage.
+    pub mount_path: PathBuf,
+    /// Size in bytes of the tmpfs to mount. Must be large enough to hold the BMP.
+    pub tmpfs_size: usize,
+    /// The sysfs file that triggers the working buffer dump when read
+    pub sysfs_path: PathBuf,
+    /// Only keep this region of the screen instead of the whole thing. Like the dump's
+    /// area, it's in the framebuffer's layout for its current rotation.
+    pub region: Option<Rect<Native>>,
+}
+
+impl Default for SunxiDumpOptions {
+    fn default() -> Self {
+        Self {
+            mount_path: PathBuf::from("/mnt/flash"),
+            tmpfs_size: 3 * 1024 * 1024,
+            sysfs_path: PathBuf::from("/sys/devices/virtual/disp/disp/waveform/get_working_buffer"),
+            region: None,
+        }
+    }
+}
+
+/// A tmpfs we mounted ourselves. Unmounted on drop if [`TmpfsMount::unmount`] wasn't called
+struct TmpfsMount {
+    path: PathBuf,
+    c_path: CString,
+    is_mounted: bool,
+}
+
+impl TmpfsMount {
+    fn mount(path: &Path, size: usize) -> Result<Self, FbInkError> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let options = CString::new(format!("size={size}"))?;
+        let rv = unsafe {
+            libc::mount(
+                c"tmpfs".as_ptr(),
+                c_path.as_ptr(),
+                c"tmpfs".as_ptr(),
+                libc::MS_NOATIME,
+                options.as_ptr() as *const libc::c_void,
+            )
+        };
+        if rv != 0 {
+            let e = io::Error::last_os_error();
+            return Err(FbInkError::SunxiMount(path.to_path_buf(), e));
         }
+        Ok(Self {
+            path: path.to_path_buf(),
+            c_path,
+            is_mounted: true,
+        })
+    }
+
+    /// Unmount the tmpfs. If it's busy, it will still be lazily detached on drop.
+    fn unmount(mut self) -> Result<(), FbInkError> {
+        if unsafe { libc::umount2(self.c_path.as_ptr(), 0) } != 0 {
+            let e = io::Error::last_os_error();
+            return Err(FbInkError::SunxiUnmount(self.path.clone(), e));
+        }
+        self.is_mounted = false;
+        Ok(())
+    }
+}
+
+impl Drop for TmpfsMount {
+    fn drop(&mut self) {
+        if self.is_mounted {
+            // Lazily detach so an error path never leaves the tmpfs behind
+            unsafe { libc::umount2(self.c_path.as_ptr(), libc::MNT_DETACH) };
+        }
+    }
+}
+
+/// A file that is removed on drop, so no path leaves it lying around
+struct RemoveOnDrop(PathBuf);
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
     }
 }
 
 impl SunxiDump {
-    pub fn new(current_rota: u8) -> Result<Self, FbInkError> {
-        let mount_path = PathBuf::from("/mnt/flash");
+    /// Capture the working buffer using the default [`SunxiDumpOptions`]
+    pub fn new(state: &FbInkState) -> Result<Self, FbInkError> {
+        Self::with_options(state, &SunxiDumpOptions::default())
+    }
+
+    pub fn with_options(
+        state: &FbInkState,
+        options: &SunxiDumpOptions,
+    ) -> Result<Self, FbInkError> {
+        let mount_path = &options.mount_path;
         let bmp_path = mount_path.join("workingbuffer.bmp");
-        fs::create_dir_all(&mount_path)?;
+        fs::create_dir_all(mount_path)?;
 
         // Ensure a tmpfs is mounted so we write the image to memory
-        if !MountIter::new()?.any(|r| r.is_ok_and(|m| m.dest == mount_path)) {
-            Command::new("mount")
-                .arg("-t")
-                .arg("tmpfs")
-                .arg("tmpfs")
-                .arg(mount_path)
-                .arg("-o")
-                .arg("noatime,size=3M")
-                .output()?;
-        }
+        let is_mounted = MountIter::new()?.any(|r| r.is_ok_and(|m| &m.dest == mount_path));
+        let tmpfs = if is_mounted {
+            None
+        } else {
+            Some(TmpfsMount::mount(mount_path, options.tmpfs_size)?)
+        };
+
+        // Declared after the tmpfs so it's dropped first, and the BMP never outlives the dump,
+        // even in someone else's tmpfs
+        let bmp = RemoveOnDrop(bmp_path);
 
         // Trigger a dump of the framebuffer by reading from the sysfs file
-        let sysfs_path = "/sys/devices/virtual/disp/disp/waveform/get_working_buffer";
-        let rv = fs::read_to_string(sysfs_path)?.trim().parse::<i32>();
-        if rv != Ok(0) {
-            return Err(FbInkError::SunxiDumpError);
+        let rv = fs::read_to_string(&options.sysfs_path).map_err(FbInkError::SunxiWorkingBuffer)?;
+        if rv.trim() != "0" {
+            return Err(FbInkError::SunxiDumpFailed(rv.trim().to_string()));
+        }
+
+        let decoded = image::io::Reader::open(&bmp.0)
+            .map_err(|e| FbInkError::SunxiDecode(bmp.0.clone(), e.into()))?
+            .decode()
+            .map_err(|e| FbInkError::SunxiDecode(bmp.0.clone(), e));
+        drop(bmp);
+        if let Some(tmpfs) = tmpfs {
+            tmpfs.unmount()?;
         }
+        let mut decoded = decoded?;
 
-        let mut decoded = image::io::Reader::open(bmp_path)?.decode()?;
+        // The BMP is stored bottom-up in the panel's native layout
         imageops::flip_vertical_in_place(&mut decoded);
-        let decoded = match current_rota {
-            0 => decoded.rotate270(),
-            2 => decoded.rotate90(),
-            3 => decoded.rotate180(),
-            _ => decoded,
-        };
-        let area = FbInkRect {
-            left: 0,
-            top: 0,
-            width: decoded.width() as u16,
-            height: decoded.height() as u16,
-        };
+        // The working buffer is always in the layout of native rotation 1
+        let mut decoded = rotate_image(decoded, state.quarter_turns_from(1));
+
+        let mut area = Rect::new(0, 0, decoded.width() as u16, decoded.height() as u16);
+        if let Some(region) = options.region {
+            if region.is_empty()
+                || rect_right(region.into()) > decoded.width()
+                || rect_bottom(region.into()) > decoded.height()
+            {
+                let msg = format!(
+                    "{region:?} is outside the {}x{} screen",
+                    area.width, area.height
+                );
+                return Err(FbInkError::OutOfRange(msg));
+            }
+            let (x, y) = (region.left.into(), region.top.into());
+            decoded = decoded.crop_imm(x, y, region.width.into(), region.height.into());
+            area = region;
+        }
         Ok(Self {
             image: decoded,
-            clip: FbInkRect::default(),
+            clip: Rect::default(),
             area,
-            rota: current_rota,
-            bpp: 24,
+            rota: state.current_rota,
             is_full: true,
         })
     }
 }
+
+/// Rotate an image clockwise by the given number of quarter turns
+pub(crate) fn rotate_image(image: DynamicImage, turns: u8) -> DynamicImage {
+    match turns % 4 {
+        1 => image.rotate90(),
+        2 => image.rotate180(),
+        3 => image.rotate270(),
+        _ => image,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    fn blank(width: u16, height: u16, bpp: u8) -> OwnedDump {
+        let stride = (usize::from(width) * usize::from(bpp)).div_ceil(8);
+        let area = Rect::new(0, 0, width, height);
+        OwnedDump::new(vec![0; stride * usize::from(height)], stride, area, 0, bpp).unwrap()
+    }
+
+    fn set_byte(dump: &OwnedDump, offset: usize, value: u8) -> OwnedDump {
+        let mut data = dump.data().to_vec();
+        data[offset] = value;
+        OwnedDump::new(data, dump.stride(), dump.area(), 0, dump.bpp()).unwrap()
+    }
+
+    fn diff(a: &dyn Dump, b: &dyn Dump, options: &DiffOptions) -> Vec<Rect<Native>> {
+        let mut rects = Vec::from(a.diff_with(b, options));
+        rects.sort_by_key(|r| (r.top, r.left));
+        rects
+    }
+
+    #[test]
+    fn diff_identical() {
+        let a = blank(64, 64, 8);
+        assert!(a.diff(&a.clone()).is_empty());
+    }
+
+    #[test]
+    fn diff_single_pixels() {
+        let a = blank(64, 64, 8);
+        let b = set_byte(&a, 10 * 64 + 5, 0xff);
+        assert_eq!(
+            diff(&a, &b, &DiffOptions::default()),
+            [Rect::new(5, 10, 1, 1)]
+        );
+        // Sub-byte pixels are compared a byte at a time, so both pixels in it are included
+        let a = blank(64, 64, 4);
+        let b = set_byte(&a, 2 * 32 + 3, 0x0f);
+        let options = DiffOptions::default();
+        assert_eq!(diff(&a, &b, &options), [Rect::new(6, 2, 2, 1)]);
+        // 32bpp pixels span four bytes
+        let a = blank(16, 16, 32);
+        let b = set_byte(&a, 3 * 64 + 4 * 4 + 2, 0x80);
+        assert_eq!(diff(&a, &b, &options), [Rect::new(4, 3, 1, 1)]);
+    }
+
+    #[test]
+    fn diff_merges_by_threshold() {
+        let a = blank(64, 64, 8);
+        let b = set_byte(&set_byte(&a, 0, 1), 63 * 64 + 63, 1);
+        let tight = DiffOptions {
+            tile_size: 16,
+            merge_threshold: 0.0,
+        };
+        let expected = [Rect::new(0, 0, 1, 1), Rect::new(63, 63, 1, 1)];
+        assert_eq!(diff(&a, &b, &tight), expected);
+        let loose = DiffOptions {
+            merge_threshold: 1.0,
+            ..tight
+        };
+        assert_eq!(diff(&a, &b, &loose), [Rect::new(0, 0, 64, 64)]);
+        // Changes across neighbouring tiles join up
+        let b = set_byte(&set_byte(&a, 15, 1), 16, 1);
+        assert_eq!(diff(&a, &b, &tight), [Rect::new(15, 0, 2, 1)]);
+    }
+
+    #[test]
+    fn diff_offsets_by_area() {
+        // A dump of part of the screen reports rects in screen coordinates
+        let area = Rect::new(8, 4, 16, 16);
+        let a = OwnedDump::new(vec![0; 16 * 16], 16, area, 0, 8).unwrap();
+        let b = set_byte(&a, 2 * 16 + 3, 1);
+        assert_eq!(
+            diff(&a, &b, &DiffOptions::default()),
+            [Rect::new(11, 6, 1, 1)]
+        );
+    }
+
+    #[test]
+    fn diff_mismatched_geometry() {
+        let a = blank(64, 64, 8);
+        let area = Rect::new(0, 0, 64, 64);
+        assert_eq!(diff(&a, &blank(32, 64, 8), &DiffOptions::default()), [area]);
+        assert_eq!(
+            diff(&a, &blank(64, 64, 16), &DiffOptions::default()),
+            [area]
+        );
+    }
+
+    fn dump(data: Vec<u8>, width: u16, bpp: u8, format: PixelFormat) -> OwnedDump {
+        let stride = (usize::from(width) * usize::from(bpp)).div_ceil(8);
+        let height = (data.len() / stride) as u16;
+        let area = Rect::new(0, 0, width, height);
+        let dump = OwnedDump::new(data, stride, area, 0, bpp).unwrap();
+        dump.with_pixel_format(format)
+    }
+
+    #[test]
+    fn image_from_4bpp() {
+        let image = dump(vec![0x0f, 0x80], 4, 4, PixelFormat::Y4)
+            .dynamic_image()
+            .unwrap();
+        let pixels: Vec<_> = image.to_luma8().pixels().map(|p| p[0]).collect();
+        assert_eq!(pixels, [0x00, 0xff, 0x88, 0x00]);
+        // Encoding goes through the same decoder
+        let png = dump(vec![0x0f, 0x80], 4, 4, PixelFormat::Y4)
+            .encode(ImageFormat::Png)
+            .unwrap();
+        assert_eq!(image::load_from_memory(&png).unwrap(), image);
+    }
+
+    #[test]
+    fn image_from_565() {
+        // Pure red in RGB565, which is pure blue in BGR565
+        let data = 0xf800u16.to_le_bytes().to_vec();
+        let rgb = dump(data.clone(), 1, 16, PixelFormat::Rgb565).dynamic_image();
+        assert_eq!(rgb.unwrap().to_rgb8().get_pixel(0, 0), &Rgb([255, 0, 0]));
+        let bgr = dump(data, 1, 16, PixelFormat::Bgr565).dynamic_image();
+        assert_eq!(bgr.unwrap().to_rgb8().get_pixel(0, 0), &Rgb([0, 0, 255]));
+    }
+
+    #[test]
+    fn image_from_bgra() {
+        // Framebuffers often leave alpha at 0, which shouldn't make the image transparent
+        let image = dump(vec![1, 2, 3, 0], 1, 32, PixelFormat::Bgra)
+            .dynamic_image()
+            .unwrap();
+        assert_eq!(image.to_rgba8().get_pixel(0, 0), &Rgba([3, 2, 1, 255]));
+    }
+
+    #[test]
+    fn cropped_image_keeps_pixel_format() {
+        let data = [0x001fu16, 0xf800].map(u16::to_le_bytes).concat();
+        let mut dump = dump(data, 2, 16, PixelFormat::Bgr565);
+        dump.crop_rect(Rect::new(1, 0, 1, 1));
+        let image = dump.dynamic_image().unwrap().to_rgb8();
+        assert_eq!(image.dimensions(), (1, 1));
+        assert_eq!(image.get_pixel(0, 0), &Rgb([0, 0, 255]));
+    }
+
+    /// A dump allocated like FBInk's, so it can be freed the same way
+    fn fbink_dump(data: &[u8], width: u16, bpp: u8) -> FbInkDump {
+        let stride = (usize::from(width) * usize::from(bpp)).div_ceil(8);
+        let copy = unsafe { libc::malloc(data.len()) } as *mut u8;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), copy, data.len()) };
+        let height = (data.len() / stride) as u16;
+        FbInkDump::new(raw::FBInkDump {
+            data: copy,
+            stride,
+            size: data.len(),
+            area: Rect::<Native>::new(0, 0, width, height).into(),
+            bpp,
+            is_full: true,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn reject_undecodable_bpp() {
+        let area = Rect::new(0, 0, 2, 1);
+        assert!(matches!(
+            OwnedDump::new(vec![0; 4], 4, area, 0, 12),
+            Err(FbInkError::NotSupported(_))
+        ));
+        assert!(matches!(
+            OwnedDump::new(vec![0; 3], 3, area, 0, 16),
+            Err(FbInkError::InvalidArgument(_))
+        ));
+        let raw = raw::FBInkDump {
+            area: area.into(),
+            bpp: 12,
+            is_full: true,
+            ..Default::default()
+        };
+        assert!(FbInkDump::new(raw).is_err());
+    }
+
+    #[test]
+    fn view_of_cropped_dump() {
+        let mut dump = fbink_dump(&[0, 1, 2, 3, 4, 5], 3, 8);
+        assert_eq!(dump.dimensions(), (3, 2));
+        dump.crop_rect(Rect::new(1, 0, 2, 2));
+        assert_eq!(dump.dimensions(), (2, 2));
+        assert_eq!(dump.get_pixel(1, 1), Rgba([5, 5, 5, 255]));
+        let image = dump.dynamic_image().unwrap().to_rgba8();
+        assert_eq!(image, imageops::crop_imm(&dump, 0, 0, 2, 2).to_image());
+        // A clip reaching past the area is limited to it
+        dump.crop_rect(Rect::new(2, 1, 5, 5));
+        assert_eq!(dump.dimensions(), (1, 1));
+        assert_eq!(dump.dynamic_image().unwrap().to_luma8().into_raw(), [5]);
+    }
+
+    #[test]
+    fn sunxi_layout_follows_image() {
+        let sunxi = |image| SunxiDump {
+            image,
+            clip: Rect::default(),
+            area: Rect::new(0, 0, 5, 2),
+            rota: 0,
+            is_full: true,
+        };
+        let rgb = sunxi(DynamicImage::new_rgb8(5, 2));
+        assert_eq!((rgb.bpp(), rgb.stride()), (24, 15));
+        let rgba = sunxi(DynamicImage::new_rgba8(5, 2));
+        assert_eq!((rgba.bpp(), rgba.stride()), (32, 20));
+        assert_eq!(rgba.stride() * 2, rgba.size());
+    }
+
+    #[test]
+    fn sunxi_bmp_removed_on_drop() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("workingbuffer.bmp");
+        fs::write(&path, b"BM").unwrap();
+        drop(RemoveOnDrop(path.clone()));
+        assert!(!path.exists());
+        // Nothing to remove is fine too
+        drop(RemoveOnDrop(path));
+    }
+}
@@ -1,6 +1,9 @@
+use crate::config::ConfigIssue;
+
+use strum::AsRefStr;
 use thiserror::Error;
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, AsRefStr)]
 pub enum FbInkError {
     #[error("FBInk returned EXIT_FAILURE during {0}")]
     ExitFailure(String),
@@ -16,6 +19,10 @@ pub enum FbInkError {
     // InvalidSequence,
     #[error("{0}")]
     OutOfRange(String),
+    #[error("Invalid config: {}", join_issues(.0))]
+    InvalidConfig(Vec<ConfigIssue>),
+    #[error("Invalid config profile: {0}")]
+    InvalidProfile(String),
     // NoSpace,
     // NotImplemented,
     #[error("FBInk failed with error code {0}")]
@@ -25,7 +32,37 @@ pub enum FbInkError {
     #[error(transparent)]
     ImageError(#[from] image::error::ImageError),
     #[error(transparent)]
+    PngError(#[from] png::EncodingError),
+    #[error(transparent)]
+    GifError(#[from] gif::EncodingError),
+    #[error(transparent)]
     NulStringError(#[from] std::ffi::NulError),
-    #[error("Failed to dump the working buffer")]
-    SunxiDumpError,
+    #[error("Failed to mount a tmpfs at {0}: {1}")]
+    SunxiMount(std::path::PathBuf, std::io::Error),
+    #[error("Failed to unmount the tmpfs at {0}: {1}")]
+    SunxiUnmount(std::path::PathBuf, std::io::Error),
+    #[error("Failed to trigger a dump of the working buffer: {0}")]
+    SunxiWorkingBuffer(std::io::Error),
+    #[error("The display driver failed to dump the working buffer (returned {0})")]
+    SunxiDumpFailed(String),
+    #[error("Failed to decode the working buffer dump at {0}: {1}")]
+    SunxiDecode(std::path::PathBuf, image::error::ImageError),
+}
+
+fn join_issues(issues: &[ConfigIssue]) -> String {
+    let issues: Vec<_> = issues.iter().map(ToString::to_string).collect();
+    issues.join("; ")
+}
+
+/// Serialized as the variant's name and the error message, e.g. for reporting errors to another
+/// process. Errors can't be deserialized since many of them wrap errors from other crates.
+#[cfg(feature = "serde")]
+impl serde::Serialize for FbInkError {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut error = serializer.serialize_struct("FbInkError", 2)?;
+        error.serialize_field("kind", self.as_ref())?;
+        error.serialize_field("message", &self.to_string())?;
+        error.end()
+    }
 }
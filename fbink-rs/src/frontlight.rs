@@ -0,0 +1,540 @@
+//! Control the brightness and colour temperature of the frontlight on Kobo devices.
+//! Older models only accept brightness through an ioctl on `/dev/ntx_io`, while newer ones
+//! expose sysfs nodes for the white LEDs and either a colour mixer or separate warm LEDs.
+use crate::device::DeviceInfo;
+use crate::error::FbInkError;
+use crate::state::DeviceId;
+
+use std::fs::{self, File};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+/// The ioctl that sets the frontlight brightness on older Kobos
+const CM_FRONT_LIGHT_SET: u32 = 241;
+
+/// How a device's frontlight colour temperature is adjusted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Warmth {
+    /// A node taking a colour value between `min` and `max`. When `inverted`, `min` is the
+    /// warmest value rather than the coolest.
+    Mixer {
+        path: &'static str,
+        min: u32,
+        max: u32,
+        inverted: bool,
+    },
+    /// Warm LEDs whose brightness is balanced against the white ones
+    Leds(&'static [&'static str]),
+}
+
+/// How a device's frontlight is controlled. Paths are relative to the sysfs root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Mechanism {
+    /// The NTX ioctl, which can't be read back
+    Ntx,
+    /// A backlight class device for the white LEDs and optionally a way to set the warmth
+    Sysfs {
+        white: &'static str,
+        warmth: Option<Warmth>,
+    },
+}
+
+const NTX_IO: &str = "dev/ntx_io";
+const MSP430: &str = "sys/class/backlight/mxc_msp430.0";
+const LM3630A_MIXER: Warmth = Warmth::Mixer {
+    path: "sys/class/backlight/lm3630a_led/color",
+    min: 0,
+    max: 10,
+    inverted: true,
+};
+const AW99703_MIXER: Warmth = Warmth::Mixer {
+    path: "sys/class/leds/aw99703-bl_FL1/color",
+    min: 0,
+    max: 10,
+    inverted: true,
+};
+
+impl Mechanism {
+    /// The mechanism used by a device, if it has a frontlight that can be controlled
+    pub fn for_device(id: DeviceId) -> Option<Self> {
+        use DeviceId::*;
+        let sysfs = |white, warmth| Self::Sysfs { white, warmth };
+        let mechanism = match id {
+            KoboGlo | KoboGloHd | TolinoShine2Hd | KoboAura | KoboAuraHd | KoboAuraH2o
+            | KoboAuraSe | KoboAuraSeR2 | KoboNia => Self::Ntx,
+            KoboAuraH2o2 => sysfs(
+                "sys/class/backlight/lm3630a_ledb",
+                Some(Warmth::Leds(&[
+                    "sys/class/backlight/lm3630a_led",
+                    "sys/class/backlight/lm3630a_leda",
+                ])),
+            ),
+            KoboAuraOne | KoboAuraOneLe => sysfs(
+                "sys/class/backlight/lm3630a_led1b",
+                Some(Warmth::Leds(&[
+                    "sys/class/backlight/lm3630a_led1a",
+                    "sys/class/backlight/lm3630a_ledb",
+                ])),
+            ),
+            KoboAuraH2o2R2 => sysfs("sys/class/backlight/lm3630a_ledb", Some(LM3630A_MIXER)),
+            KoboClaraHd | TolinoShine3 | KoboForma | TolinoEpos2 | KoboForma32 | KoboLibraH2o
+            | TolinoVision5 | TolinoVision | KoboLibra2 | KoboElipsa2e => {
+                sysfs(MSP430, Some(LM3630A_MIXER))
+            }
+            KoboSage | TolinoEpos3 | KoboClara2e | KoboLibraColour | TolinoVisionColor
+            | KoboClaraBw | TolinoShineBw | KoboClaraColour | TolinoShineColor => {
+                sysfs(MSP430, Some(AW99703_MIXER))
+            }
+            KoboElipsa => sysfs(MSP430, None),
+            _ => return None,
+        };
+        Some(mechanism)
+    }
+}
+
+/// The frontlight of a Kobo. Brightness and warmth are percentages from 0 to 100.
+#[derive(Debug, Clone)]
+pub struct Frontlight {
+    root: PathBuf,
+    mechanism: Mechanism,
+    brightness: Option<u8>,
+    warmth: Option<u8>,
+}
+
+impl Frontlight {
+    /// Control the frontlight of the given device
+    pub fn new(id: DeviceId) -> Result<Self, FbInkError> {
+        Self::with_root(id, "/")
+    }
+
+    /// Like [`Frontlight::new`] but with the sysfs nodes and `/dev` under `root` instead of `/`
+    pub fn with_root(id: DeviceId, root: impl Into<PathBuf>) -> Result<Self, FbInkError> {
+        if DeviceInfo::lookup(id).is_some_and(|device| !device.has_frontlight) {
+            let msg = format!("The {id} doesn't have a frontlight");
+            return Err(FbInkError::NotSupported(msg));
+        }
+        let mechanism = Mechanism::for_device(id).ok_or_else(|| {
+            let msg = format!("Frontlight control isn't supported on the {id}");
+            FbInkError::NotSupported(msg)
+        })?;
+        Ok(Self::with_mechanism(mechanism, root))
+    }
+
+    /// Control a frontlight with an explicit mechanism, e.g. for devices that aren't known yet
+    pub fn with_mechanism(mechanism: Mechanism, root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            mechanism,
+            brightness: None,
+            warmth: None,
+        }
+    }
+
+    pub fn mechanism(&self) -> Mechanism {
+        self.mechanism
+    }
+
+    /// The root that sysfs nodes and `/dev` are looked up under
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether the colour temperature can be adjusted
+    pub fn has_warmth(&self) -> bool {
+        matches!(
+            self.mechanism,
+            Mechanism::Sysfs {
+                warmth: Some(_),
+                ..
+            }
+        )
+    }
+
+    /// The current brightness. The NTX ioctl can't be read back, so with it this is the last
+    /// brightness that was set, or an error if none was.
+    pub fn brightness(&self) -> Result<u8, FbInkError> {
+        match self.mechanism {
+            Mechanism::Ntx => self.brightness.ok_or_else(|| {
+                let msg = "The brightness can't be read back until it has been set".into();
+                FbInkError::NotSupported(msg)
+            }),
+            Mechanism::Sysfs { white, warmth } => {
+                let white = self.read_percent(white)?;
+                match warmth {
+                    Some(Warmth::Leds(leds)) => {
+                        let warm = self.read_percent(leds[0])?;
+                        Ok((u16::from(white) + u16::from(warm)).min(100) as u8)
+                    }
+                    _ => Ok(white),
+                }
+            }
+        }
+    }
+
+    pub fn set_brightness(&mut self, brightness: u8) -> Result<(), FbInkError> {
+        check_percent("brightness", brightness)?;
+        match self.mechanism {
+            Mechanism::Ntx => self.ntx_ioctl(brightness)?,
+            Mechanism::Sysfs {
+                white,
+                warmth: Some(Warmth::Leds(leds)),
+            } => {
+                // The warmth can't be read back while the LEDs are off
+                let warmth = self.warmth.map_or_else(|| self.warmth(), Ok)?;
+                self.write_leds(white, leds, brightness, warmth)?;
+            }
+            Mechanism::Sysfs { white, .. } => self.write_percent(white, brightness)?,
+        }
+        self.brightness = Some(brightness);
+        Ok(())
+    }
+
+    /// The current colour temperature, where 0 is the coolest and 100 the warmest
+    pub fn warmth(&self) -> Result<u8, FbInkError> {
+        match self.warmth_control()? {
+            Warmth::Mixer {
+                path,
+                min,
+                max,
+                inverted,
+            } => {
+                let value = self.read_value(path)?.clamp(min, max) - min;
+                let value = if inverted { max - min - value } else { value };
+                Ok(scale(value, max - min, 100) as u8)
+            }
+            Warmth::Leds(leds) => {
+                let Mechanism::Sysfs { white, .. } = self.mechanism else {
+                    unreachable!()
+                };
+                let (white, warm) = (self.read_percent(white)?, self.read_percent(leds[0])?);
+                match u32::from(white) + u32::from(warm) {
+                    0 => Ok(0),
+                    total => Ok(scale(warm.into(), total, 100) as u8),
+                }
+            }
+        }
+    }
+
+    pub fn set_warmth(&mut self, warmth: u8) -> Result<(), FbInkError> {
+        check_percent("warmth", warmth)?;
+        match self.warmth_control()? {
+            Warmth::Mixer {
+                path,
+                min,
+                max,
+                inverted,
+            } => {
+                let value = scale(warmth.into(), 100, max - min);
+                let value = if inverted { max - value } else { min + value };
+                self.write_value(path, value)?;
+            }
+            Warmth::Leds(leds) => {
+                let Mechanism::Sysfs { white, .. } = self.mechanism else {
+                    unreachable!()
+                };
+                let brightness = self.brightness()?;
+                self.write_leds(white, leds, brightness, warmth)?;
+            }
+        }
+        self.warmth = Some(warmth);
+        Ok(())
+    }
+
+    /// Gradually change the brightness to `target` over `duration`
+    pub fn ramp_brightness(&mut self, target: u8, duration: Duration) -> Result<(), FbInkError> {
+        check_percent("brightness", target)?;
+        let from = self.brightness().unwrap_or(target);
+        ramp(from, target, duration, |value| self.set_brightness(value))
+    }
+
+    /// Gradually change the warmth to `target` over `duration`
+    pub fn ramp_warmth(&mut self, target: u8, duration: Duration) -> Result<(), FbInkError> {
+        check_percent("warmth", target)?;
+        let from = self.warmth()?;
+        ramp(from, target, duration, |value| self.set_warmth(value))
+    }
+
+    fn warmth_control(&self) -> Result<Warmth, FbInkError> {
+        match self.mechanism {
+            Mechanism::Sysfs {
+                warmth: Some(warmth),
+                ..
+            } => Ok(warmth),
+            _ => {
+                let msg = "This frontlight's colour temperature can't be adjusted".into();
+                Err(FbInkError::NotSupported(msg))
+            }
+        }
+    }
+
+    fn ntx_ioctl(&self, brightness: u8) -> Result<(), FbInkError> {
+        let file = File::open(self.root.join(NTX_IO))?;
+        let request = CM_FRONT_LIGHT_SET as _;
+        let rv = unsafe { libc::ioctl(file.as_raw_fd(), request, libc::c_ulong::from(brightness)) };
+        match rv {
+            -1 => Err(std::io::Error::last_os_error().into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Split the brightness between the white and warm LEDs according to the warmth
+    fn write_leds(
+        &self,
+        white: &str,
+        leds: &[&str],
+        brightness: u8,
+        warmth: u8,
+    ) -> Result<(), FbInkError> {
+        let warm = scale(u32::from(brightness) * u32::from(warmth), 100 * 100, 100) as u8;
+        self.write_percent(white, brightness - warm)?;
+        for led in leds {
+            self.write_percent(led, warm)?;
+        }
+        Ok(())
+    }
+
+    /// The brightness of a backlight class device as a percentage of its max_brightness
+    fn read_percent(&self, device: &str) -> Result<u8, FbInkError> {
+        let max = self.max_brightness(device)?;
+        let value = self.read_value(&format!("{device}/brightness"))?.min(max);
+        Ok(scale(value, max, 100) as u8)
+    }
+
+    fn write_percent(&self, device: &str, percent: u8) -> Result<(), FbInkError> {
+        let max = self.max_brightness(device)?;
+        let value = scale(percent.into(), 100, max);
+        self.write_value(&format!("{device}/brightness"), value)
+    }
+
+    fn max_brightness(&self, device: &str) -> Result<u32, FbInkError> {
+        self.read_value(&format!("{device}/max_brightness"))
+    }
+
+    fn read_value(&self, node: &str) -> Result<u32, FbInkError> {
+        let path = self.root.join(node);
+        let contents = fs::read_to_string(&path)?;
+        contents.trim().parse().map_err(|_| {
+            let msg = format!("{} contains {contents:?}", path.display());
+            FbInkError::InvalidArgument(msg)
+        })
+    }
+
+    fn write_value(&self, node: &str, value: u32) -> Result<(), FbInkError> {
+        Ok(fs::write(self.root.join(node), value.to_string())?)
+    }
+}
+
+fn check_percent(name: &str, value: u8) -> Result<(), FbInkError> {
+    match value {
+        0..=100 => Ok(()),
+        _ => Err(FbInkError::OutOfRange(format!(
+            "{value} is not a valid {name}, it must be between 0 and 100"
+        ))),
+    }
+}
+
+/// Rescale `value` from `0..=from` to `0..=to`, rounding to the nearest integer
+fn scale(value: u32, from: u32, to: u32) -> u32 {
+    match from {
+        0 => 0,
+        _ => (value * to + from / 2) / from,
+    }
+}
+
+/// Step one percent at a time from `from` to `to`, spread evenly over `duration`
+fn ramp(
+    from: u8,
+    to: u8,
+    duration: Duration,
+    mut set: impl FnMut(u8) -> Result<(), FbInkError>,
+) -> Result<(), FbInkError> {
+    let steps = from.abs_diff(to);
+    if steps == 0 {
+        return set(to);
+    }
+    let interval = duration / u32::from(steps);
+    let values: Box<dyn Iterator<Item = u8>> = match from < to {
+        true => Box::new(from + 1..=to),
+        false => Box::new((to..from).rev()),
+    };
+    for value in values {
+        set(value)?;
+        sleep(interval);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    /// A fresh directory to stand in for `/`
+    fn root() -> TempDir {
+        TempDir::new().unwrap()
+    }
+
+    fn write(root: &Path, node: &str, value: u32) {
+        let path = root.join(node);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, format!("{value}\n")).unwrap();
+    }
+
+    fn read(root: &Path, node: &str) -> u32 {
+        fs::read_to_string(root.join(node))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap()
+    }
+
+    /// A backlight class device at `brightness` out of `max`
+    fn backlight(root: &Path, device: &str, brightness: u32, max: u32) {
+        write(root, &format!("{device}/brightness"), brightness);
+        write(root, &format!("{device}/max_brightness"), max);
+    }
+
+    #[test]
+    fn inverted_mixer() {
+        let root = root();
+        let root = root.path();
+        backlight(root, MSP430, 50, 100);
+        let Warmth::Mixer { path, .. } = LM3630A_MIXER else {
+            unreachable!()
+        };
+        write(root, path, 10);
+        let mut frontlight = Frontlight::with_root(DeviceId::KoboClaraHd, root).unwrap();
+        assert_eq!(frontlight.brightness().unwrap(), 50);
+        assert_eq!(frontlight.warmth().unwrap(), 0);
+        frontlight.set_warmth(100).unwrap();
+        assert_eq!(read(root, path), 0);
+        frontlight.set_warmth(30).unwrap();
+        assert_eq!(read(root, path), 7);
+        assert_eq!(frontlight.warmth().unwrap(), 30);
+        frontlight.set_brightness(25).unwrap();
+        assert_eq!(read(root, &format!("{MSP430}/brightness")), 25);
+    }
+
+    #[test]
+    fn mixer() {
+        let root = root();
+        let root = root.path();
+        let mechanism = Mechanism::Sysfs {
+            white: "white",
+            warmth: Some(Warmth::Mixer {
+                path: "mixer",
+                min: 2,
+                max: 12,
+                inverted: false,
+            }),
+        };
+        backlight(root, "white", 0, 10);
+        write(root, "mixer", 2);
+        let mut frontlight = Frontlight::with_mechanism(mechanism, root);
+        assert_eq!(frontlight.warmth().unwrap(), 0);
+        frontlight.set_warmth(100).unwrap();
+        assert_eq!(read(root, "mixer"), 12);
+        frontlight.set_warmth(50).unwrap();
+        assert_eq!(read(root, "mixer"), 7);
+        assert_eq!(frontlight.warmth().unwrap(), 50);
+        // Values outside the range are clamped
+        write(root, "mixer", 20);
+        assert_eq!(frontlight.warmth().unwrap(), 100);
+    }
+
+    #[test]
+    fn leds() {
+        let root = root();
+        let root = root.path();
+        let white = "sys/class/backlight/lm3630a_ledb";
+        let warm = [
+            "sys/class/backlight/lm3630a_led",
+            "sys/class/backlight/lm3630a_leda",
+        ];
+        for device in [white, warm[0], warm[1]] {
+            backlight(root, device, 0, 255);
+        }
+        let mut frontlight = Frontlight::with_root(DeviceId::KoboAuraH2o2, root).unwrap();
+        assert_eq!(frontlight.warmth().unwrap(), 0);
+        frontlight.set_brightness(80).unwrap();
+        assert_eq!(read(root, &format!("{white}/brightness")), 204);
+        frontlight.set_warmth(25).unwrap();
+        assert_eq!(read(root, &format!("{white}/brightness")), 153);
+        for device in warm {
+            assert_eq!(read(root, &format!("{device}/brightness")), 51);
+        }
+        assert_eq!(frontlight.brightness().unwrap(), 80);
+        assert_eq!(frontlight.warmth().unwrap(), 25);
+        // Turning the LEDs off and back on keeps the warmth
+        frontlight.set_brightness(0).unwrap();
+        frontlight.set_brightness(40).unwrap();
+        assert_eq!(frontlight.brightness().unwrap(), 40);
+        assert_eq!(frontlight.warmth().unwrap(), 25);
+    }
+
+    #[test]
+    fn unsupported() {
+        let root = root();
+        let frontlight = Frontlight::with_root(DeviceId::KoboElipsa, root.path()).unwrap();
+        assert!(!frontlight.has_warmth());
+        assert!(matches!(
+            frontlight.warmth(),
+            Err(FbInkError::NotSupported(_))
+        ));
+        let mut frontlight = Frontlight::with_root(DeviceId::KoboNia, root.path()).unwrap();
+        assert!(frontlight.brightness().is_err());
+        assert!(matches!(
+            frontlight.set_brightness(101),
+            Err(FbInkError::OutOfRange(_))
+        ));
+    }
+
+    #[test]
+    fn scale_rounds() {
+        assert_eq!(scale(5, 0, 100), 0);
+        assert_eq!(scale(3, 10, 100), 30);
+        assert_eq!(scale(50, 100, 255), 128);
+        assert_eq!(scale(128, 255, 100), 50);
+        assert_eq!(scale(100, 100, 255), 255);
+    }
+
+    #[test]
+    fn ramp_steps_towards_target() {
+        let steps = |from, to| {
+            let mut values = Vec::new();
+            ramp(from, to, Duration::ZERO, |value| {
+                values.push(value);
+                Ok(())
+            })
+            .unwrap();
+            values
+        };
+        assert_eq!(steps(5, 8), [6, 7, 8]);
+        assert_eq!(steps(8, 5), [7, 6, 5]);
+        assert_eq!(steps(5, 5), [5]);
+        assert_eq!(steps(0, 100).len(), 100);
+    }
+
+    #[test]
+    fn ramp_brightness() {
+        let root = root();
+        let root = root.path();
+        backlight(root, MSP430, 90, 100);
+        let mut frontlight = Frontlight::with_mechanism(
+            Mechanism::Sysfs {
+                white: MSP430,
+                warmth: None,
+            },
+            root,
+        );
+        frontlight.ramp_brightness(20, Duration::ZERO).unwrap();
+        assert_eq!(frontlight.brightness().unwrap(), 20);
+        frontlight.ramp_brightness(60, Duration::ZERO).unwrap();
+        assert_eq!(frontlight.brightness().unwrap(), 60);
+    }
+}
@@ -0,0 +1,552 @@
+//! Record every drawing operation with the config it used and what it returned, so a screen
+//! that looked wrong on one device can be replayed on another or on a
+//! [`VirtualFbInk`](crate::virtual_fbink::VirtualFbInk). Journals are stored as JSON Lines,
+//! one [`JournalEntry`] per line.
+use crate::coords::{Native, Rect, Rotated, View};
+use crate::display::Display;
+use crate::dump::{Dump, OwnedDump};
+use crate::error::FbInkError;
+use crate::region::Region;
+use crate::thin::ReinitResult;
+use crate::{FbInkConfig, FbInkState};
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// Pixel data passed to FBInk. The checksum is always recorded, the data itself only when
+/// [`JournalOptions::copy_data`] is set.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct JournalData {
+    pub len: usize,
+    pub crc32: u32,
+    pub data: Option<Vec<u8>>,
+}
+
+impl JournalData {
+    fn new(data: &[u8], copy: bool) -> Self {
+        Self {
+            len: data.len(),
+            crc32: crc32fast::hash(data),
+            data: copy.then(|| data.to_vec()),
+        }
+    }
+
+    fn bytes(&self) -> Result<&[u8], FbInkError> {
+        self.data.as_deref().ok_or_else(|| {
+            FbInkError::NotSupported("the journal only has a checksum of the data".into())
+        })
+    }
+}
+
+/// An operation and its arguments
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "op")]
+pub enum Operation {
+    Print {
+        msg: String,
+    },
+    PrintCoords {
+        msg: String,
+        x: i16,
+        y: i16,
+    },
+    PrintRawData {
+        data: JournalData,
+        rect: Rect<View>,
+    },
+    Refresh {
+        rect: Rect<Rotated>,
+    },
+    RefreshRect {
+        rect: Rect<Rotated>,
+    },
+    Cls,
+    ClsRect {
+        rect: Rect<Rotated>,
+    },
+    Restore {
+        data: JournalData,
+        stride: usize,
+        area: Rect<Native>,
+        clip: Option<Rect<Native>>,
+        rota: u8,
+        bpp: u8,
+    },
+    Reinit,
+}
+
+/// What an operation returned. Errors are kept as their message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Done,
+    /// The number of rows printed
+    Rows(i32),
+    /// The raw [`ReinitChanges`](crate::thin::ReinitChanges) flags, or 0 if nothing changed
+    Reinit(u32),
+    Error(String),
+}
+
+impl Outcome {
+    fn new<T>(result: &Result<T, FbInkError>, ok: impl FnOnce(&T) -> Self) -> Self {
+        match result {
+            Ok(value) => ok(value),
+            Err(e) => Self::Error(e.to_string()),
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Self::Error(_))
+    }
+}
+
+/// A single journaled operation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    /// Position in the journal, starting at 0
+    pub seq: u64,
+    /// When the operation started, relative to the start of the journal
+    pub start: Duration,
+    /// How long the operation took
+    pub duration: Duration,
+    /// The config in effect when the operation was called
+    pub config: FbInkConfig,
+    #[serde(flatten)]
+    pub operation: Operation,
+    pub outcome: Outcome,
+    /// The result of `get_last_rect` after the operation
+    pub last_rect: Rect<Rotated>,
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct JournalOptions {
+    /// Store the data passed to print_raw_data and restore instead of just a checksum,
+    /// which makes those operations replayable at the cost of a much larger journal
+    pub copy_data: bool,
+}
+
+enum Sink {
+    Memory(Vec<JournalEntry>),
+    Writer(Box<dyn Write + Send>),
+}
+
+struct Recording {
+    sink: Sink,
+    next_seq: u64,
+    epoch: Instant,
+    /// The first error writing an entry, kept until taken
+    error: Option<FbInkError>,
+}
+
+impl std::fmt::Debug for Recording {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let sink = match &self.sink {
+            Sink::Memory(entries) => format!("Memory({} entries)", entries.len()),
+            Sink::Writer(_) => "Writer".to_string(),
+        };
+        f.debug_struct("Recording")
+            .field("sink", &sink)
+            .field("next_seq", &self.next_seq)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// Wraps a [`Display`] and journals the operations that change what's on screen.
+/// Everything else is passed through as is.
+#[derive(Debug)]
+pub struct Journaled<D> {
+    inner: D,
+    options: JournalOptions,
+    recording: Mutex<Recording>,
+}
+
+impl<D: Display> Journaled<D> {
+    /// Keep the journal in memory, to be retrieved with [`Journaled::take_entries`]
+    pub fn new(inner: D, options: JournalOptions) -> Self {
+        Self::with_sink(inner, options, Sink::Memory(Vec::new()))
+    }
+
+    /// Write each entry to `writer` as soon as its operation has completed
+    pub fn with_writer(
+        inner: D,
+        options: JournalOptions,
+        writer: impl Write + Send + 'static,
+    ) -> Self {
+        Self::with_sink(inner, options, Sink::Writer(Box::new(writer)))
+    }
+
+    /// Write the journal to a newly created file
+    pub fn create<P: AsRef<Path>>(
+        inner: D,
+        options: JournalOptions,
+        path: P,
+    ) -> Result<Self, FbInkError> {
+        let file = BufWriter::new(File::create(path)?);
+        Ok(Self::with_writer(inner, options, file))
+    }
+
+    fn with_sink(inner: D, options: JournalOptions, sink: Sink) -> Self {
+        Self {
+            inner,
+            options,
+            recording: Mutex::new(Recording {
+                sink,
+                next_seq: 0,
+                epoch: Instant::now(),
+                error: None,
+            }),
+        }
+    }
+
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut D {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Remove and return the entries recorded so far. Always empty when writing to a file
+    pub fn take_entries(&self) -> Vec<JournalEntry> {
+        match &mut self.recording().sink {
+            Sink::Memory(entries) => std::mem::take(entries),
+            Sink::Writer(_) => Vec::new(),
+        }
+    }
+
+    /// Return the first error writing an entry since the last call, if any. Failing to
+    /// journal an operation doesn't fail the operation itself, so check this to make sure the
+    /// journal is complete.
+    pub fn take_error(&self) -> Option<FbInkError> {
+        self.recording().error.take()
+    }
+
+    /// Flush any entries buffered by the writer
+    pub fn flush(&self) -> Result<(), FbInkError> {
+        if let Sink::Writer(writer) = &mut self.recording().sink {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn recording(&self) -> MutexGuard<'_, Recording> {
+        self.recording.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run an operation and journal it
+    fn record<T>(
+        &self,
+        operation: Operation,
+        run: impl FnOnce(&D) -> Result<T, FbInkError>,
+        outcome: impl FnOnce(&T) -> Outcome,
+    ) -> Result<T, FbInkError> {
+        let config = *self.inner.config();
+        let start = Instant::now();
+        let result = run(&self.inner);
+        let duration = start.elapsed();
+        let mut recording = self.recording();
+        let entry = JournalEntry {
+            seq: recording.next_seq,
+            start: start.duration_since(recording.epoch),
+            duration,
+            config,
+            operation,
+            outcome: Outcome::new(&result, outcome),
+            last_rect: self.inner.get_last_rect(),
+        };
+        recording.next_seq += 1;
+        let recording = &mut *recording;
+        match &mut recording.sink {
+            Sink::Memory(entries) => entries.push(entry),
+            // Failing to journal shouldn't fail the operation itself
+            Sink::Writer(writer) => {
+                if let Err(e) = write_entry(writer, &entry) {
+                    recording.error.get_or_insert(e);
+                }
+            }
+        }
+        result
+    }
+}
+
+impl<D: Display> Display for Journaled<D> {
+    fn state(&self) -> FbInkState {
+        self.inner.state()
+    }
+    fn config(&self) -> &FbInkConfig {
+        self.inner.config()
+    }
+    fn config_mut(&mut self) -> &mut FbInkConfig {
+        self.inner.config_mut()
+    }
+    fn reinit(&self) -> ReinitResult {
+        self.record(
+            Operation::Reinit,
+            |d| d.reinit(),
+            |changes| Outcome::Reinit(changes.map_or(0, |c| c.bits())),
+        )
+    }
+    fn print(&self, msg: &str) -> Result<i32, FbInkError> {
+        let operation = Operation::Print { msg: msg.into() };
+        self.record(operation, |d| d.print(msg), |&rows| Outcome::Rows(rows))
+    }
+    fn print_coords(&self, msg: &str, x: i16, y: i16) -> Result<i32, FbInkError> {
+        let operation = Operation::PrintCoords {
+            msg: msg.into(),
+            x,
+            y,
+        };
+        let run = |d: &D| d.print_coords(msg, x, y);
+        self.record(operation, run, |&rows| Outcome::Rows(rows))
+    }
+    fn print_raw_data(&self, data: &[u8], rect: Rect<View>) -> Result<(), FbInkError> {
+        let operation = Operation::PrintRawData {
+            data: JournalData::new(data, self.options.copy_data),
+            rect,
+        };
+        let run = |d: &D| d.print_raw_data(data, rect);
+        self.record(operation, run, |_| Outcome::Done)
+    }
+    fn refresh(&self, rect: Rect<Rotated>) -> Result<(), FbInkError> {
+        let operation = Operation::Refresh { rect };
+        self.record(operation, |d| d.refresh(rect), |_| Outcome::Done)
+    }
+    /// Records each merged rect of the region as a separate operation
+    fn refresh_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        for rect in region.fbink_rects() {
+            let operation = Operation::RefreshRect { rect };
+            let run = |d: &D| d.refresh_rect(&rect.into());
+            self.record(operation, run, |_| Outcome::Done)?;
+        }
+        Ok(())
+    }
+    fn cls(&self) -> Result<(), FbInkError> {
+        self.record(Operation::Cls, |d| d.cls(), |_| Outcome::Done)
+    }
+    /// Records each merged rect of the region as a separate operation
+    fn cls_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        for rect in region.fbink_rects() {
+            let operation = Operation::ClsRect { rect };
+            let run = |d: &D| d.cls_rect(&rect.into());
+            self.record(operation, run, |_| Outcome::Done)?;
+        }
+        Ok(())
+    }
+    fn dump(&self) -> Result<Box<dyn Dump>, FbInkError> {
+        self.inner.dump()
+    }
+    fn rect_dump(&self, region: &Region<Native>) -> Result<Box<dyn Dump>, FbInkError> {
+        self.inner.rect_dump(region)
+    }
+    fn restore(&self, dump: &dyn Dump) -> Result<(), FbInkError> {
+        let operation = Operation::Restore {
+            data: JournalData::new(dump.data(), self.options.copy_data),
+            stride: dump.stride(),
+            area: dump.area(),
+            clip: (!dump.is_full()).then(|| dump.clip()),
+            rota: dump.rota(),
+            bpp: dump.bpp(),
+        };
+        self.record(operation, |d| d.restore(dump), |_| Outcome::Done)
+    }
+    fn get_last_rect(&self) -> Rect<Rotated> {
+        self.inner.get_last_rect()
+    }
+    fn get_last_native_rect(&self) -> Rect<Native> {
+        self.inner.get_last_native_rect()
+    }
+    fn get_last_marker(&self) -> u32 {
+        self.inner.get_last_marker()
+    }
+    fn wait_for_complete(&self, marker: u32) -> Result<(), FbInkError> {
+        self.inner.wait_for_complete(marker)
+    }
+}
+
+fn write_entry(writer: &mut dyn Write, entry: &JournalEntry) -> Result<(), FbInkError> {
+    serde_json::to_writer(&mut *writer, entry).map_err(std::io::Error::from)?;
+    writer.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Write entries to a file in the same format as [`Journaled::create`]
+pub fn save<P: AsRef<Path>>(path: P, entries: &[JournalEntry]) -> Result<(), FbInkError> {
+    let mut file = BufWriter::new(File::create(path)?);
+    for entry in entries {
+        write_entry(&mut file, entry)?;
+    }
+    file.flush()?;
+    Ok(())
+}
+
+/// Read a journal file. Blank lines are skipped
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<JournalEntry>, FbInkError> {
+    let file = BufReader::new(File::open(path)?);
+    let mut entries = Vec::new();
+    for (i, line) in file.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| FbInkError::InvalidArgument(format!("journal line {}: {e}", i + 1)))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ReplayOptions {
+    /// Sleep between operations to reproduce the journal's original timing
+    pub realtime: bool,
+    /// Stop at the first operation that can't be replayed or whose outcome differs
+    pub stop_on_mismatch: bool,
+}
+
+/// How a replayed operation compared to the journal
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayResult {
+    pub seq: u64,
+    pub outcome: Outcome,
+    pub last_rect: Rect<Rotated>,
+    /// Whether the outcome and last rect were the same as when the journal was recorded
+    pub matches: bool,
+}
+
+/// Replay journaled operations, applying each entry's config to `target` before running it.
+/// print_raw_data and restore can only be replayed if their data was copied into the journal.
+/// The target's config is left as set by the last entry.
+pub fn replay<D: Display + ?Sized>(
+    entries: &[JournalEntry],
+    target: &mut D,
+    options: ReplayOptions,
+) -> Vec<ReplayResult> {
+    let epoch = Instant::now();
+    let mut results = Vec::with_capacity(entries.len());
+    for entry in entries {
+        if options.realtime {
+            if let Some(wait) = entry.start.checked_sub(epoch.elapsed()) {
+                std::thread::sleep(wait);
+            }
+        }
+        *target.config_mut() = entry.config;
+        let outcome = replay_operation(&entry.operation, target);
+        let last_rect = target.get_last_rect();
+        let matches = outcome == entry.outcome && last_rect == entry.last_rect;
+        results.push(ReplayResult {
+            seq: entry.seq,
+            outcome,
+            last_rect,
+            matches,
+        });
+        if options.stop_on_mismatch && !matches {
+            break;
+        }
+    }
+    results
+}
+
+fn replay_operation<D: Display + ?Sized>(operation: &Operation, target: &D) -> Outcome {
+    let done = |_: &()| Outcome::Done;
+    let rows = |&rows: &i32| Outcome::Rows(rows);
+    match operation {
+        Operation::Print { msg } => Outcome::new(&target.print(msg), rows),
+        Operation::PrintCoords { msg, x, y } => {
+            Outcome::new(&target.print_coords(msg, *x, *y), rows)
+        }
+        Operation::PrintRawData { data, rect } => {
+            let result = data
+                .bytes()
+                .and_then(|data| target.print_raw_data(data, *rect));
+            Outcome::new(&result, done)
+        }
+        Operation::Refresh { rect } => Outcome::new(&target.refresh(*rect), done),
+        Operation::RefreshRect { rect } => {
+            Outcome::new(&target.refresh_rect(&(*rect).into()), done)
+        }
+        Operation::Cls => Outcome::new(&target.cls(), done),
+        Operation::ClsRect { rect } => Outcome::new(&target.cls_rect(&(*rect).into()), done),
+        Operation::Restore {
+            data,
+            stride,
+            area,
+            clip,
+            rota,
+            bpp,
+        } => {
+            let result = data.bytes().and_then(|data| {
+                let mut dump = OwnedDump::new(data.to_vec(), *stride, *area, *rota, *bpp)?;
+                if let Some(clip) = clip {
+                    dump.crop_rect(*clip);
+                }
+                target.restore(&dump)
+            });
+            Outcome::new(&result, done)
+        }
+        Operation::Reinit => Outcome::new(&target.reinit(), |changes| {
+            Outcome::Reinit(changes.map_or(0, |c| c.bits()))
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+
+    /// A writer that fails every write
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn virtual_fbink() -> VirtualFbInk {
+        let device = VirtualDevice {
+            width: 64,
+            height: 32,
+            ..Default::default()
+        };
+        VirtualFbInk::new(device, FbInkConfig::default()).unwrap()
+    }
+
+    #[test]
+    fn write_errors_are_kept() {
+        let journaled = Journaled::with_writer(virtual_fbink(), Default::default(), Broken);
+        journaled.cls().unwrap();
+        journaled.refresh(Rect::new(0, 0, 8, 8)).unwrap();
+        assert!(matches!(
+            journaled.take_error(),
+            Some(FbInkError::IoError(_))
+        ));
+        assert!(journaled.take_error().is_none());
+    }
+
+    #[test]
+    fn rects_serialize_with_their_fields() {
+        let journaled = Journaled::new(virtual_fbink(), Default::default());
+        journaled.refresh(Rect::new(1, 2, 3, 4)).unwrap();
+        let entries = journaled.take_entries();
+        let json = serde_json::to_value(&entries[0]).unwrap();
+        let rect = serde_json::json!({ "left": 1, "top": 2, "width": 3, "height": 4 });
+        assert_eq!(json["op"], "refresh");
+        assert_eq!(json["rect"], rect);
+        let entry: JournalEntry = serde_json::from_value(json).unwrap();
+        assert_eq!(entry.operation, entries[0].operation);
+    }
+}
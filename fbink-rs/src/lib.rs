@@ -1,18 +1,56 @@
+use crate::capabilities::{Capabilities, Feature};
 pub use crate::config::FbInkConfig;
-use crate::dump::{Dump, FbInkDump, SunxiDump};
+#[cfg(feature = "opentype")]
+use crate::config::{FbInkOtConfig, FontStyle};
+#[cfg(feature = "image")]
+use crate::coords::View;
+use crate::coords::{Native, Rect, Rotated};
+#[cfg(feature = "image")]
+use crate::dump::{Dump, FbInkDump, SunxiDump, SunxiDumpOptions};
 use crate::error::FbInkError;
+use crate::region::Region;
+#[cfg(feature = "image")]
+use crate::screenshot::{write_png, ScreenshotOptions};
+#[cfg(feature = "image")]
+use crate::state::PixelFormat;
 use crate::state::SunxiForceRotation;
 pub use crate::state::{CanonicalRotation, FbInkState};
+#[cfg(any(feature = "bitmap", feature = "opentype"))]
+use crate::text::TextMetrics;
 use crate::thin::*;
 
 pub use fbink_sys::FBInkRect as FbInkRect;
 pub use image;
 
+#[cfg(feature = "image")]
+use std::io::{Cursor, Write};
+#[cfg(feature = "opentype")]
+use std::path::Path;
+
+pub mod cache;
+pub mod capabilities;
 pub mod config;
+pub mod coords;
+pub mod device;
+pub mod display;
 pub mod dump;
 pub mod error;
+pub mod frontlight;
+#[cfg(feature = "journal")]
+pub mod journal;
+#[cfg(feature = "profiles")]
+pub mod profiles;
+#[cfg(feature = "image")]
+pub mod recorder;
+pub mod region;
+pub mod screenshot;
+pub mod simulator;
 pub mod state;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod text;
 pub mod thin;
+pub mod virtual_fbink;
 
 /// An incomplete attempt at a more ergonomic Rust interface to FBInk. It wraps the functions
 /// from [`crate::thin`] to avoid having to pass the fd and config every function call, and
@@ -21,6 +59,8 @@ pub mod thin;
 pub struct FbInk {
     pub config: FbInkConfig,
     pub fbfd: std::os::raw::c_int,
+    capabilities: Capabilities,
+    enforce_config: bool,
 }
 
 impl Drop for FbInk {
@@ -34,7 +74,39 @@ impl FbInk {
     pub fn new(config: FbInkConfig) -> Result<Self, FbInkError> {
         let fbfd = fbink_open()?;
         fbink_init(fbfd, &config)?;
-        Ok(Self { config, fbfd })
+        let capabilities = fbink_get_state(&config).capabilities();
+        Ok(Self {
+            config,
+            fbfd,
+            capabilities,
+            enforce_config: false,
+        })
+    }
+
+    /// Validate the config against the current state before every call that uses it, failing
+    /// with [`FbInkError::InvalidConfig`] instead of letting FBInk clamp or ignore options.
+    /// See [`FbInkConfig::validate`]. Calls that don't pass the config to FBInk, like
+    /// [`FbInk::dump`], [`FbInk::rect_dump`], the `wait_for_*` family and loading OpenType
+    /// fonts, aren't affected.
+    pub fn set_enforce_config(&mut self, enforce: bool) {
+        self.enforce_config = enforce;
+    }
+
+    /// Options the device doesn't support are always refused, see
+    /// [`Capabilities::check_config`]
+    pub(crate) fn check_config(&self, config: &FbInkConfig) -> Result<(), FbInkError> {
+        self.capabilities.check_config(config)?;
+        if !self.enforce_config {
+            return Ok(());
+        }
+        config
+            .validate(&self.state())
+            .map_err(FbInkError::InvalidConfig)
+    }
+
+    /// What the device and the FBInk build support, as detected when FBInk was initialized
+    pub fn capabilities(&self) -> &Capabilities {
+        &self.capabilities
     }
 
     /// Return FBInk's current internal state
@@ -49,14 +121,18 @@ impl FbInk {
         // private and add setters for all the fields, having them call reinit when necessary.
         // To avoid multiple calls to reinit, we could set a needs_reinit field instead,
         // and then act on that in any methods that might require a reinit
+        self.check_config(&self.config)?;
         fbink_reinit(self.fbfd, &self.config)
     }
 
+    #[cfg(feature = "bitmap")]
     /// Print text with the current configuration. Returns number of rows printed on success
     pub fn print(&self, msg: &str) -> Result<i32, FbInkError> {
+        self.check_config(&self.config)?;
         fbink_print(self.fbfd, &self.config, msg)
     }
 
+    #[cfg(feature = "bitmap")]
     /// Print text at the given coordinates. Returns number of rows printed on success
     pub fn print_coords(&self, msg: &str, x: i16, y: i16) -> Result<i32, FbInkError> {
         let mut config = self.config;
@@ -69,77 +145,199 @@ impl FbInk {
         config.is_halfway = false;
         config.is_padded = false;
         config.is_rpadded = false;
+        self.check_config(&config)?;
         fbink_print(self.fbfd, &config, msg)
     }
 
-    /// Refresh the screen at the given coordinates. If all arguments are 0, performs a full refresh
-    pub fn refresh(&self, top: u32, left: u32, width: u32, height: u32) -> Result<(), FbInkError> {
+    #[cfg(feature = "bitmap")]
+    /// Work out how many rows printing the text with the config would take and the area it
+    /// would cover, without printing it. The font and fontmult are those FBInk was last
+    /// (re)initialized with, whatever the config says.
+    pub fn measure(&self, msg: &str, config: &FbInkConfig) -> Result<TextMetrics, FbInkError> {
+        self.check_config(config)?;
+        text::measure(config, &self.state(), msg)
+    }
+
+    #[cfg(feature = "opentype")]
+    /// Load an OpenType font to print with in the given style. Doesn't use the config, so
+    /// isn't subject to [`FbInk::set_enforce_config`]
+    pub fn add_ot_font(&self, path: impl AsRef<Path>, style: FontStyle) -> Result<(), FbInkError> {
+        fbink_add_ot_font(path, style)
+    }
+
+    #[cfg(feature = "opentype")]
+    /// Free all the OpenType fonts that have been added
+    pub fn free_ot_fonts(&self) -> Result<(), FbInkError> {
+        fbink_free_ot_fonts()
+    }
+
+    #[cfg(feature = "opentype")]
+    /// Print text with the OpenType fonts that have been added. Returns the top margin to print
+    /// any following text at
+    pub fn print_ot(&self, msg: &str, ot_config: &FbInkOtConfig) -> Result<i32, FbInkError> {
+        self.check_config(&self.config)?;
+        Ok(fbink_print_ot(self.fbfd, msg, ot_config, &self.config)?.0)
+    }
+
+    #[cfg(feature = "opentype")]
+    /// Like [`FbInk::measure`] but for OpenType text, which FBInk lays out without drawing
+    pub fn measure_ot(
+        &self,
+        msg: &str,
+        ot_config: &FbInkOtConfig,
+    ) -> Result<TextMetrics, FbInkError> {
+        self.check_config(&self.config)?;
+        let ot_config = FbInkOtConfig {
+            compute_only: true,
+            ..*ot_config
+        };
+        Ok(fbink_print_ot(self.fbfd, msg, &ot_config, &self.config)?
+            .1
+            .into())
+    }
+
+    /// Refresh a rect of the screen in the current rotation. An empty rect performs a full
+    /// refresh.
+    pub fn refresh(&self, rect: Rect<Rotated>) -> Result<(), FbInkError> {
+        self.capabilities.check(Feature::Refresh)?;
+        self.check_config(&self.config)?;
+        let (top, left) = (rect.top.into(), rect.left.into());
+        let (width, height) = (rect.width.into(), rect.height.into());
         fbink_refresh(self.fbfd, &self.config, top, left, width, height)
     }
 
-    /// Refresh the screen using a FbInkRect for coordinates
-    pub fn refresh_rect(&self, rect: FbInkRect) -> Result<(), FbInkError> {
-        fbink_refresh_rect(self.fbfd, &self.config, rect)
+    /// Refresh a rect or region of the screen in the current rotation. Nearby rects are merged
+    /// per [`region::MERGE_THRESHOLD`] and then refreshed one call each. An empty region
+    /// refreshes the whole screen, as in FBInk.
+    pub fn refresh_rect(&self, region: impl Into<Region<Rotated>>) -> Result<(), FbInkError> {
+        self.capabilities.check(Feature::Refresh)?;
+        self.check_config(&self.config)?;
+        for rect in region.into().fbink_rects() {
+            fbink_refresh_rect(self.fbfd, &self.config, rect.into())?;
+        }
+        Ok(())
     }
 
     /// Refresh the screen using grid coordinates with the same positioning trickery as fbink_print
     pub fn grid_refresh(&self, cols: u16, rows: u16) -> Result<(), FbInkError> {
+        self.capabilities.check(Feature::Refresh)?;
+        self.check_config(&self.config)?;
         fbink_grid_refresh(self.fbfd, &self.config, cols, rows)
     }
 
+    #[cfg(feature = "draw")]
     /// Clear the entire screen using the background pen color
     pub fn cls(&self) -> Result<(), FbInkError> {
+        self.check_config(&self.config)?;
         fbink_cls(self.fbfd, &self.config, Default::default(), false)
     }
 
-    /// Clear a specific region of the screen using the background pen color
-    pub fn cls_rect(&self, rect: FbInkRect, no_rota: bool) -> Result<(), FbInkError> {
-        fbink_cls(self.fbfd, &self.config, rect, no_rota)
+    #[cfg(feature = "draw")]
+    /// Clear a rect or region of the screen using the background pen color, merging nearby
+    /// rects like [`FbInk::refresh_rect`]. An empty region clears the whole screen. Convert a
+    /// rect in another space with [`Rect::to`] rather than relying on FBInk's `no_rota`.
+    pub fn cls_rect(&self, region: impl Into<Region<Rotated>>) -> Result<(), FbInkError> {
+        self.check_config(&self.config)?;
+        for rect in region.into().fbink_rects() {
+            fbink_cls(self.fbfd, &self.config, rect.into(), false)?;
+        }
+        Ok(())
     }
 
+    #[cfg(feature = "draw")]
     /// Clear the screen using grid coordinates with the same positioning trickery as fbink_print
     pub fn grid_clear(&self, cols: u16, rows: u16) -> Result<(), FbInkError> {
+        self.check_config(&self.config)?;
         fbink_grid_clear(self.fbfd, &self.config, cols, rows)
     }
 
+    #[cfg(feature = "draw")]
+    /// Print a full-width progress bar filled up to `percentage`, positioned by the config's
+    /// row like text
+    pub fn print_progress_bar(&self, percentage: u8) -> Result<(), FbInkError> {
+        if percentage > 100 {
+            let msg = format!("{percentage}% is not a valid progress");
+            return Err(FbInkError::OutOfRange(msg));
+        }
+        self.check_config(&self.config)?;
+        fbink_print_progress_bar(self.fbfd, percentage, &self.config)
+    }
+
+    #[cfg(feature = "draw")]
+    /// Print a step of an activity bar, an indeterminate progress bar that's animated by
+    /// printing each step from 0 to 16 in turn
+    pub fn print_activity_bar(&self, step: u8) -> Result<(), FbInkError> {
+        if step > 16 {
+            let msg = format!("{step} is not a valid activity bar step, it must be 0 to 16");
+            return Err(FbInkError::OutOfRange(msg));
+        }
+        self.check_config(&self.config)?;
+        fbink_print_activity_bar(self.fbfd, step, &self.config)
+    }
+
+    #[cfg(feature = "image")]
     /// Dump the contents of the framebuffer
     pub fn dump(&self) -> Result<FbInkDump, FbInkError> {
-        fbink_dump(self.fbfd)
+        Ok(fbink_dump(self.fbfd)?.with_pixel_format(self.state().pixel_format))
     }
 
+    #[cfg(feature = "image")]
     /// Dump the contents of the framebuffer, using a workaround for Sunxi SoCs that's less
     /// efficient than a standard dump but allows capturing content you haven't drawn yourself.
     pub fn dump_workaround_sunxi(&self) -> Result<Box<dyn Dump>, FbInkError> {
         let state = self.state();
         let dump: Box<dyn Dump> = if state.is_sunxi {
-            Box::new(SunxiDump::new(state.current_rota)?)
+            Box::new(SunxiDump::new(&state)?)
         } else {
             Box::new(self.dump()?)
         };
         Ok(dump)
     }
 
-    /// Dump the contents of a specific region of the framebuffer
-    pub fn region_dump(
-        &self,
-        x: i16,
-        y: i16,
-        width: u16,
-        height: u16,
-    ) -> Result<FbInkDump, FbInkError> {
-        fbink_region_dump(self.fbfd, &self.config, x, y, width, height)
+    #[cfg(feature = "image")]
+    /// Dump the working buffer on a Sunxi SoC with the given options. Allows capturing a
+    /// region of the screen or using a different tmpfs for the intermediate BMP.
+    pub fn sunxi_dump(&self, options: &SunxiDumpOptions) -> Result<SunxiDump, FbInkError> {
+        self.capabilities.check(Feature::WorkingBufferDump)?;
+        SunxiDump::with_options(&self.state(), options)
+    }
+
+    #[cfg(feature = "image")]
+    /// Dump the contents of a rect of the viewport, positioned like fbink_print_image so the
+    /// config's halign and valign apply
+    pub fn region_dump(&self, rect: Rect<View>) -> Result<FbInkDump, FbInkError> {
+        self.check_config(&self.config)?;
+        let (Ok(x), Ok(y)) = (rect.left.try_into(), rect.top.try_into()) else {
+            let msg = format!("region offset {},{} is too large", rect.left, rect.top);
+            return Err(FbInkError::OutOfRange(msg));
+        };
+        let dump = fbink_region_dump(self.fbfd, &self.config, x, y, rect.width, rect.height)?;
+        Ok(dump.with_pixel_format(self.state().pixel_format))
     }
 
-    /// Like region_dump but takes a FbInkRect and doesn't apply any rotation/positioning tricks
-    pub fn rect_dump(&self, rect: FbInkRect) -> Result<FbInkDump, FbInkError> {
-        fbink_rect_dump(self.fbfd, rect)
+    #[cfg(feature = "image")]
+    /// Like region_dump but takes a rect in the framebuffer's memory layout, so doesn't apply
+    /// any rotation/positioning tricks. Given a region, dumps its bounds. Doesn't use the
+    /// config, so isn't subject to [`FbInk::set_enforce_config`]
+    pub fn rect_dump(&self, region: impl Into<Region<Native>>) -> Result<FbInkDump, FbInkError> {
+        let Some(rect) = region.into().bounds() else {
+            return Err(FbInkError::InvalidArgument("empty region".into()));
+        };
+        let dump = fbink_rect_dump(self.fbfd, rect.into())?;
+        Ok(dump.with_pixel_format(self.state().pixel_format))
     }
 
     /// Get the coordinates & dimensions of the last thing drawn on the framebuffer
-    pub fn get_last_rect(&self, rotated: bool) -> FbInkRect {
-        fbink_get_last_rect(rotated)
+    pub fn get_last_rect(&self) -> Rect<Rotated> {
+        Rect::from_raw(fbink_get_last_rect(false))
     }
 
+    /// Like get_last_rect but after any rotation tricks, as it was sent to the display driver
+    pub fn get_last_native_rect(&self) -> Rect<Native> {
+        Rect::from_raw(fbink_get_last_rect(true))
+    }
+
+    #[cfg(feature = "image")]
     /// Restore the contents of a dump back to the framebuffer
     pub fn restore(&self, dump: &dyn Dump) -> Result<(), FbInkError> {
         dump.restore(self)
@@ -156,29 +354,71 @@ impl FbInk {
     //     fbink_print_image(self.fbfd, &self.config, path, x_off, y_off)
     // }
 
-    /// Print raw scanlines on the screen (packed pixels).
-    pub fn print_raw_data(
-        &self,
-        data: &[u8],
-        w: i32,
-        h: i32,
-        x_off: i16,
-        y_off: i16,
-    ) -> Result<(), FbInkError> {
-        fbink_print_raw_data(self.fbfd, &self.config, data, w, h, x_off, y_off)
+    #[cfg(feature = "image")]
+    /// Print raw scanlines (packed pixels) into a rect of the viewport, positioned like
+    /// fbink_print_image so the config's halign and valign apply
+    pub fn print_raw_data(&self, data: &[u8], rect: Rect<View>) -> Result<(), FbInkError> {
+        self.check_config(&self.config)?;
+        let (Ok(x), Ok(y)) = (rect.left.try_into(), rect.top.try_into()) else {
+            let msg = format!("raw data offset {},{} is too large", rect.left, rect.top);
+            return Err(FbInkError::OutOfRange(msg));
+        };
+        let (w, h) = (rect.width.into(), rect.height.into());
+        fbink_print_raw_data(self.fbfd, &self.config, data, w, h, x, y)
     }
 
-    /// Take a screenshot of the framebuffer. Returns the encoded image as bytes
+    #[cfg(feature = "image")]
+    /// Take a screenshot of the framebuffer, rotated to the orientation the user sees.
+    /// Returns the encoded image as bytes
     pub fn screenshot(&self, encoding: image::ImageFormat) -> Result<Vec<u8>, FbInkError> {
+        let mut bytes = Vec::new();
+        self.screenshot_to(&mut bytes, encoding, &ScreenshotOptions::default())?;
+        Ok(bytes)
+    }
+
+    #[cfg(feature = "image")]
+    /// Take a screenshot of the framebuffer and write it to `writer`. PNGs are encoded directly
+    /// from the dump one row at a time, as grayscale on grayscale panels unless configured
+    /// otherwise. Other formats are encoded in memory first and only use the orientation.
+    pub fn screenshot_to<W: Write>(
+        &self,
+        mut writer: W,
+        encoding: image::ImageFormat,
+        options: &ScreenshotOptions,
+    ) -> Result<(), FbInkError> {
         let state = self.state();
+        let (dump, pixel_format) = self.screenshot_dump(&state)?;
+        if encoding == image::ImageFormat::Png {
+            write_png(&*dump, pixel_format, &state, writer, options)
+        } else {
+            let bytes = match options.turns(&*dump, &state) {
+                0 => dump.encode(encoding)?,
+                _ => {
+                    let mut bytes = Cursor::new(Vec::new());
+                    dump.canonical_image(&state)?
+                        .write_to(&mut bytes, encoding)?;
+                    bytes.into_inner()
+                }
+            };
+            writer.write_all(&bytes)?;
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "image")]
+    /// Dump the visible framebuffer, returning the pixel format of the dump's data
+    fn screenshot_dump(
+        &self,
+        state: &FbInkState,
+    ) -> Result<(Box<dyn Dump>, PixelFormat), FbInkError> {
         if state.is_sunxi {
-            let dump = self.dump_workaround_sunxi()?;
-            Ok(dump.encode(encoding)?)
+            // The working buffer is decoded from a BMP
+            Ok((self.dump_workaround_sunxi()?, PixelFormat::Rgb24))
         } else {
             // On some devices the dump contains junk pixels outside the visible framebuffer
             let (width, height) = (state.view_width as u16, state.view_height as u16);
-            let dump = self.region_dump(0, 0, width, height)?;
-            Ok(dump.encode(encoding)?)
+            let dump = self.region_dump(Rect::new(0, 0, width, height))?;
+            Ok((Box::new(dump), state.pixel_format))
         }
     }
 
@@ -190,16 +430,26 @@ impl FbInk {
 
     /// Control how fbink_init & fbink_reinit handle rotation on Sunxi SoCs
     pub fn sunxi_ntx_enforce_rota(&self, mode: SunxiForceRotation) -> ReinitResult {
+        self.capabilities.check(Feature::SunxiRotation)?;
+        self.check_config(&self.config)?;
         fbink_sunxi_ntx_enforce_rota(self.fbfd, &self.config, mode)
     }
 
+    /// Wait for the refresh with the given marker to complete. Like the other `wait_for_*`
+    /// calls, doesn't use the config, so isn't subject to [`FbInk::set_enforce_config`]
     pub fn wait_for_complete(&self, marker: u32) -> Result<(), FbInkError> {
+        self.capabilities.check(Feature::WaitForComplete)?;
         fbink_wait_for_complete(self.fbfd, marker)
     }
     pub fn wait_for_last_complete(&self) -> Result<(), FbInkError> {
         self.wait_for_complete(fbink_sys::LAST_MARKER)
     }
     pub fn wait_for_any_complete(&self) -> Result<(), FbInkError> {
+        self.capabilities.check(Feature::WaitForComplete)?;
         fbink_wait_for_any_complete(self.fbfd)
     }
+    /// The marker of the last refresh FBInk requested in this process
+    pub fn get_last_marker(&self) -> u32 {
+        fbink_get_last_marker()
+    }
 }
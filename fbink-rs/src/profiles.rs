@@ -0,0 +1,390 @@
+//! Named [`FbInkConfig`] presets loaded from TOML or JSON, so settings can be tweaked without
+//! recompiling. A preset only lists the settings it changes, and everything else keeps its
+//! default or is taken from the preset it inherits from. Presets can override settings per
+//! device family, matched against the vendor, the platform (e.g. `Mark 7`) or the codename.
+//!
+//! ```toml
+//! [status_line]
+//! row = -1
+//! font = "Terminus"
+//! is_padded = true
+//!
+//! [fullscreen_image]
+//! is_flashing = true
+//! wfm_mode = "GC16"
+//!
+//! [night]
+//! inherits = "fullscreen_image"
+//! is_nightmode = true
+//!
+//! [night.devices."Mark 7"]
+//! is_nightmode = false
+//! is_inverted = true
+//! ```
+use crate::config::FbInkConfig;
+use crate::error::FbInkError;
+use crate::state::FbInkState;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+type Settings = Map<String, Value>;
+
+#[derive(Debug, Clone, Deserialize)]
+struct Preset {
+    #[serde(default)]
+    inherits: Option<String>,
+    /// Settings applied on top of the preset's own on matching devices
+    #[serde(default)]
+    devices: BTreeMap<String, Settings>,
+    #[serde(flatten)]
+    settings: Settings,
+}
+
+/// A set of named config presets
+#[derive(Debug, Clone, Default)]
+pub struct Profiles {
+    presets: BTreeMap<String, Preset>,
+}
+
+impl Profiles {
+    pub fn from_toml(toml: &str) -> Result<Self, FbInkError> {
+        let presets = toml::from_str(toml).map_err(|e| invalid(&e.to_string()))?;
+        Self::new(presets)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, FbInkError> {
+        let presets = serde_json::from_str(json).map_err(|e| invalid(&e.to_string()))?;
+        Self::new(presets)
+    }
+
+    /// Load presets from a file, which is parsed as JSON if it has a `.json` extension and
+    /// as TOML otherwise
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, FbInkError> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        match path.extension().is_some_and(|ext| ext == "json") {
+            true => Self::from_json(&contents),
+            false => Self::from_toml(&contents),
+        }
+    }
+
+    /// Check every preset up front so that mistakes show up when the file is loaded rather
+    /// than when a preset is first used
+    fn new(presets: BTreeMap<String, Preset>) -> Result<Self, FbInkError> {
+        let profiles = Self { presets };
+        for (name, preset) in &profiles.presets {
+            profiles.get(name)?;
+            for (family, settings) in &preset.devices {
+                build_config(settings.clone())
+                    .map_err(|e| invalid(&format!("{name} on {family}: {e}")))?;
+            }
+        }
+        Ok(profiles)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.presets.keys().map(String::as_str)
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.presets.contains_key(name)
+    }
+
+    /// The config for a preset, without any per-device overrides
+    pub fn get(&self, name: &str) -> Result<FbInkConfig, FbInkError> {
+        self.build(name, None)
+    }
+
+    /// The config for a preset with the overrides for the device applied
+    pub fn get_for(&self, name: &str, state: &FbInkState) -> Result<FbInkConfig, FbInkError> {
+        self.build(name, Some(state))
+    }
+
+    fn build(&self, name: &str, state: Option<&FbInkState>) -> Result<FbInkConfig, FbInkError> {
+        let settings = self.settings(name, state, &mut Vec::new())?;
+        build_config(settings).map_err(|e| invalid(&format!("{name}: {e}")))
+    }
+
+    /// The preset's settings merged over those of the presets it inherits from
+    fn settings<'a>(
+        &'a self,
+        name: &'a str,
+        state: Option<&FbInkState>,
+        chain: &mut Vec<&'a str>,
+    ) -> Result<Settings, FbInkError> {
+        if chain.contains(&name) {
+            chain.push(name);
+            return Err(invalid(&format!(
+                "circular inheritance ({})",
+                chain.join(" -> ")
+            )));
+        }
+        let preset = self
+            .presets
+            .get(name)
+            .ok_or_else(|| invalid(&format!("no preset named {name}")))?;
+        chain.push(name);
+        let mut settings = match &preset.inherits {
+            Some(parent) => self.settings(parent, state, chain)?,
+            None => Settings::new(),
+        };
+        settings.extend(preset.settings.clone());
+        if let Some(state) = state {
+            // From least to most specific, so a codename beats a platform beats a vendor
+            let vendor = state.device_info().map(|info| info.vendor.as_ref());
+            let families = [
+                vendor,
+                Some(&*state.device_platform),
+                Some(&*state.device_codename),
+            ];
+            for family in families.into_iter().flatten() {
+                let overrides = preset
+                    .devices
+                    .iter()
+                    .filter(|(key, _)| key.eq_ignore_ascii_case(family));
+                for (_, overrides) in overrides {
+                    settings.extend(overrides.clone());
+                }
+            }
+        }
+        Ok(settings)
+    }
+}
+
+/// Apply the settings to the default config, rejecting any that aren't config fields
+fn build_config(settings: Settings) -> Result<FbInkConfig, String> {
+    let Ok(Value::Object(mut config)) = serde_json::to_value(FbInkConfig::default()) else {
+        unreachable!("FbInkConfig serializes as a map");
+    };
+    for (key, value) in settings {
+        match config.get_mut(&key) {
+            Some(field) => *field = value,
+            None => return Err(format!("unknown setting {key}")),
+        }
+    }
+    serde_json::from_value(Value::Object(config)).map_err(|e| e.to_string())
+}
+
+fn invalid(msg: &str) -> FbInkError {
+    FbInkError::InvalidProfile(msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Font, WaveformMode};
+    use crate::display::Display;
+    use crate::state::DeviceId;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+
+    const TOML: &str = r#"
+        [base]
+        row = -1
+        font = "Terminus"
+        is_padded = true
+
+        [child]
+        inherits = "base"
+        is_padded = false
+        fontmult = 2
+
+        [grandchild]
+        inherits = "child"
+        wfm_mode = "GC16"
+
+        [night]
+        inherits = "base"
+        is_nightmode = true
+
+        [night.devices.kobo]
+        is_nightmode = false
+        is_inverted = true
+        fontmult = 2
+
+        [night.devices."Mark 8"]
+        fontmult = 3
+        row = 2
+
+        [night.devices.io]
+        row = 3
+    "#;
+
+    const JSON: &str = r#"{
+        "base": { "row": -1, "font": "Terminus", "is_padded": true },
+        "child": { "inherits": "base", "is_padded": false, "fontmult": 2 },
+        "grandchild": { "inherits": "child", "wfm_mode": "GC16" },
+        "night": {
+            "inherits": "base",
+            "is_nightmode": true,
+            "devices": {
+                "kobo": { "is_nightmode": false, "is_inverted": true, "fontmult": 2 },
+                "Mark 8": { "fontmult": 3, "row": 2 },
+                "io": { "row": 3 }
+            }
+        }
+    }"#;
+
+    /// A Kobo Libra 2, whose platform is Mark 8 and codename io
+    fn libra2() -> FbInkState {
+        let fbink = VirtualFbInk::new(VirtualDevice::default(), FbInkConfig::default()).unwrap();
+        let mut state = fbink.state();
+        state.device_platform = "Mark 8".into();
+        state.device_codename = "io".into();
+        state
+    }
+
+    fn both() -> [Profiles; 2] {
+        [
+            Profiles::from_toml(TOML).unwrap(),
+            Profiles::from_json(JSON).unwrap(),
+        ]
+    }
+
+    fn error(result: Result<Profiles, FbInkError>) -> String {
+        match result {
+            Err(FbInkError::InvalidProfile(msg)) => msg,
+            other => panic!("expected an invalid profile, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn inherits_chain() {
+        for profiles in both() {
+            let names: Vec<_> = profiles.names().collect();
+            assert_eq!(names, ["base", "child", "grandchild", "night"]);
+            let config = profiles.get("grandchild").unwrap();
+            // From base, overridden by child, then grandchild's own
+            assert_eq!(config.row, -1);
+            assert_eq!(config.font, Font::Terminus);
+            assert!(!config.is_padded);
+            assert_eq!(config.fontmult, 2);
+            assert_eq!(config.wfm_mode, WaveformMode::GC16);
+            // Anything not set keeps its default
+            assert_eq!(config.col, 0);
+            assert!(!config.is_flashing);
+        }
+    }
+
+    #[test]
+    fn device_overrides() {
+        let state = libra2();
+        for profiles in both() {
+            let base = profiles.get("night").unwrap();
+            assert!(base.is_nightmode);
+            assert!(!base.is_inverted);
+            assert_eq!((base.row, base.fontmult), (-1, 0));
+
+            let config = profiles.get_for("night", &state).unwrap();
+            // The vendor override beats the preset, and is matched ignoring case
+            assert!(!config.is_nightmode);
+            assert!(config.is_inverted);
+            // The platform beats the vendor, and the codename beats the platform
+            assert_eq!(config.fontmult, 3);
+            assert_eq!(config.row, 3);
+            // Settings the overrides don't touch still come from the preset chain
+            assert_eq!(config.font, Font::Terminus);
+        }
+    }
+
+    #[test]
+    fn other_devices_keep_the_preset() {
+        let mut state = libra2();
+        state.device_id = DeviceId::PocketbookEra;
+        state.device_platform = "Virtual".into();
+        state.device_codename = "virtual".into();
+        for profiles in both() {
+            let config = profiles.get_for("night", &state).unwrap();
+            assert!(config.is_nightmode);
+            assert!(!config.is_inverted);
+            assert_eq!((config.row, config.fontmult), (-1, 0));
+        }
+    }
+
+    #[test]
+    fn module_example() {
+        let profiles = Profiles::from_toml(
+            r#"
+            [fullscreen_image]
+            is_flashing = true
+            wfm_mode = "GC16"
+
+            [night]
+            inherits = "fullscreen_image"
+            is_nightmode = true
+
+            [night.devices."Mark 7"]
+            is_nightmode = false
+            is_inverted = true
+            "#,
+        )
+        .unwrap();
+        let mut state = libra2();
+        state.device_platform = "Mark 7".into();
+        let config = profiles.get_for("night", &state).unwrap();
+        assert!(config.is_flashing);
+        assert!(!config.is_nightmode);
+        assert!(config.is_inverted);
+    }
+
+    #[test]
+    fn circular_inheritance() {
+        let toml = "[a]\ninherits = \"b\"\n[b]\ninherits = \"c\"\n[c]\ninherits = \"a\"\n";
+        let msg = error(Profiles::from_toml(toml));
+        assert!(
+            msg.contains("circular inheritance (a -> b -> c -> a)"),
+            "{msg}"
+        );
+
+        let json = r#"{ "a": { "inherits": "a" } }"#;
+        let msg = error(Profiles::from_json(json));
+        assert!(msg.contains("circular inheritance (a -> a)"), "{msg}");
+    }
+
+    #[test]
+    fn missing_parent() {
+        let msg = error(Profiles::from_toml("[a]\ninherits = \"nope\"\n"));
+        assert!(msg.contains("no preset named nope"), "{msg}");
+        let profiles = Profiles::from_json("{}").unwrap();
+        assert!(!profiles.contains("a"));
+        assert!(profiles.get("a").is_err());
+    }
+
+    #[test]
+    fn unknown_setting() {
+        let msg = error(Profiles::from_toml("[a]\ncolour = \"red\"\n"));
+        assert!(msg.contains("unknown setting colour"), "{msg}");
+
+        // Overrides are checked when loading too, even if no device matches them
+        let json = r#"{ "a": { "devices": { "Mark 7": { "is_nightmod": true } } } }"#;
+        let msg = error(Profiles::from_json(json));
+        assert!(
+            msg.contains("a on Mark 7: unknown setting is_nightmod"),
+            "{msg}"
+        );
+
+        // A known setting with the wrong type is rejected as well
+        let msg = error(Profiles::from_toml("[a]\nfontmult = \"big\"\n"));
+        assert!(msg.starts_with("a: "), "{msg}");
+    }
+
+    #[test]
+    fn load_picks_the_format_from_the_extension() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let json = dir.path().join("profiles.json");
+        let toml = dir.path().join("profiles.toml");
+        fs::write(&json, JSON).unwrap();
+        fs::write(&toml, TOML).unwrap();
+        for path in [json, toml] {
+            let profiles = Profiles::load(&path).unwrap();
+            assert!(profiles.contains("grandchild"), "{path:?}");
+        }
+        // TOML isn't valid JSON
+        fs::write(dir.path().join("toml.json"), TOML).unwrap();
+        error(Profiles::load(dir.path().join("toml.json")));
+    }
+}
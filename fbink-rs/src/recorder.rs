@@ -0,0 +1,430 @@
+//! Record what's on screen to an animated GIF/APNG or a sequence of PNGs
+use crate::coords::{Native, Rect, View};
+use crate::dump::{Dump, FbInkDump};
+use crate::error::FbInkError;
+use crate::screenshot::{
+    write_png, RowConverter, ScreenshotColor, ScreenshotOptions, ScreenshotOrientation,
+};
+use crate::{FbInk, FbInkState};
+
+use std::borrow::Cow;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// When the [`Recorder`] captures a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Trigger {
+    /// Sample the framebuffer at a fixed interval
+    Interval(Duration),
+    /// Capture a frame once each refresh requested through FBInk in this process has completed,
+    /// checking for a new refresh marker at the given interval. Requires a device that supports
+    /// waiting for refreshes.
+    RefreshComplete { poll: Duration },
+}
+
+/// Where the [`Recorder`] writes its frames
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RecordingOutput {
+    /// An animated GIF. Frames are written as they're captured
+    Gif(PathBuf),
+    /// An animated PNG. Frames are kept in memory until [`Recorder::finish`] is called
+    Apng(PathBuf),
+    /// A directory of PNGs named after the frame number and the milliseconds since recording
+    /// started, e.g. `00003_001500ms.png`. Frames are written as they're captured
+    PngSequence(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RecorderOptions {
+    pub trigger: Trigger,
+    /// Only record this rect of the framebuffer's memory layout. Defaults to the visible screen.
+    pub region: Option<Rect<Native>>,
+    /// The colour type of the frames. GIFs can't be 4-bit, so use 8-bit grayscale instead.
+    pub color: ScreenshotColor,
+}
+
+impl Default for RecorderOptions {
+    fn default() -> Self {
+        Self {
+            trigger: Trigger::Interval(Duration::from_millis(500)),
+            region: None,
+            color: ScreenshotColor::Auto,
+        }
+    }
+}
+
+enum Sink {
+    Gif(gif::Encoder<BufWriter<File>>),
+    Apng {
+        path: PathBuf,
+        frames: Vec<(Vec<u8>, Duration)>,
+    },
+    PngSequence(PathBuf),
+}
+
+/// A converted frame waiting for the next one, which determines how long it's displayed
+struct PendingFrame {
+    data: Vec<u8>,
+    time: Duration,
+}
+
+/// Records the framebuffer by dumping it whenever the [`Trigger`] fires, skipping frames
+/// that are identical to the previous one.
+pub struct Recorder<'a> {
+    fbink: &'a FbInk,
+    state: FbInkState,
+    options: RecorderOptions,
+    rect: Rect<Native>,
+    converter: RowConverter,
+    sink: Sink,
+    start: Instant,
+    previous: Option<FbInkDump>,
+    pending: Option<PendingFrame>,
+    last_marker: u32,
+    frames: usize,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(
+        fbink: &'a FbInk,
+        output: RecordingOutput,
+        options: RecorderOptions,
+    ) -> Result<Self, FbInkError> {
+        let state = fbink.state();
+        let rect = options.region.unwrap_or_else(|| {
+            let (width, height) = (state.view_width as u16, state.view_height as u16);
+            Rect::<View>::new(0, 0, width, height).to(&state)
+        });
+        let mut color = options.color;
+        if matches!(output, RecordingOutput::Gif(_)) && color == ScreenshotColor::Gray4 {
+            color = ScreenshotColor::Gray8;
+        }
+        let bpp = state.bpp as u8;
+        let converter = RowConverter::new(bpp, state.pixel_format, color, &state);
+        let sink = Sink::new(output, rect)?;
+        Ok(Self {
+            fbink,
+            last_marker: fbink.get_last_marker(),
+            state,
+            options,
+            rect,
+            converter,
+            sink,
+            start: Instant::now(),
+            previous: None,
+            pending: None,
+            frames: 0,
+        })
+    }
+
+    /// Capture a frame now, regardless of the trigger. Returns false if the frame was skipped
+    /// because nothing had changed.
+    pub fn capture(&mut self) -> Result<bool, FbInkError> {
+        let time = self.start.elapsed();
+        let dump = self.fbink.rect_dump(self.rect)?;
+        if self
+            .previous
+            .as_ref()
+            .is_some_and(|p| p.data() == dump.data())
+        {
+            return Ok(false);
+        }
+        if let Sink::PngSequence(dir) = &self.sink {
+            let name = format!("{:05}_{:06}ms.png", self.frames, time.as_millis());
+            let file = BufWriter::new(File::create(dir.join(name))?);
+            let options = ScreenshotOptions {
+                color: self.converter.color(),
+                metadata: true,
+                // Match the layout of GIF and APNG frames
+                orientation: ScreenshotOrientation::Native,
+            };
+            write_png(&dump, self.state.pixel_format, &self.state, file, &options)?;
+        } else {
+            let data = convert(&self.converter, self.rect, &dump)?;
+            if let Some(previous) = self.pending.replace(PendingFrame { data, time }) {
+                let delay = time - previous.time;
+                self.sink
+                    .write_frame(self.rect, &self.converter, previous.data, delay)?;
+            }
+        }
+        self.previous = Some(dump);
+        self.frames += 1;
+        Ok(true)
+    }
+
+    /// Record until `stop` returns true, capturing frames whenever the trigger fires
+    pub fn record_until<F: FnMut() -> bool>(&mut self, mut stop: F) -> Result<(), FbInkError> {
+        while !stop() {
+            match self.options.trigger {
+                Trigger::Interval(interval) => {
+                    let next = Instant::now() + interval;
+                    self.capture()?;
+                    sleep(next.saturating_duration_since(Instant::now()));
+                }
+                Trigger::RefreshComplete { poll } => {
+                    let marker = self.fbink.get_last_marker();
+                    if marker == self.last_marker {
+                        sleep(poll);
+                        continue;
+                    }
+                    self.fbink.wait_for_complete(marker)?;
+                    self.last_marker = marker;
+                    self.capture()?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Record for the given duration
+    pub fn record_for(&mut self, duration: Duration) -> Result<(), FbInkError> {
+        let end = Instant::now() + duration;
+        self.record_until(|| Instant::now() >= end)
+    }
+
+    /// The number of frames recorded so far
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Write any remaining frames and finalize the output. The last frame is displayed for
+    /// as long as it was on screen before this was called. Returns the number of frames.
+    pub fn finish(mut self) -> Result<usize, FbInkError> {
+        if let Some(last) = self.pending.take() {
+            let delay = self.start.elapsed() - last.time;
+            self.sink
+                .write_frame(self.rect, &self.converter, last.data, delay)?;
+        }
+        self.sink.finish(self.rect, &self.converter)?;
+        Ok(self.frames)
+    }
+}
+
+impl Sink {
+    fn new(output: RecordingOutput, rect: Rect<Native>) -> Result<Self, FbInkError> {
+        let sink = match output {
+            RecordingOutput::Gif(path) => {
+                let file = BufWriter::new(File::create(path)?);
+                // Grayscale frames index straight into a palette of every gray level
+                let palette: Vec<u8> = (0..=255).flat_map(|v| [v, v, v]).collect();
+                let mut encoder = gif::Encoder::new(file, rect.width, rect.height, &palette)?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                Sink::Gif(encoder)
+            }
+            RecordingOutput::Apng(path) => Sink::Apng {
+                path,
+                frames: Vec::new(),
+            },
+            RecordingOutput::PngSequence(dir) => {
+                fs::create_dir_all(&dir)?;
+                Sink::PngSequence(dir)
+            }
+        };
+        Ok(sink)
+    }
+
+    /// Write a converted frame that was displayed for `delay`
+    fn write_frame(
+        &mut self,
+        rect: Rect<Native>,
+        converter: &RowConverter,
+        data: Vec<u8>,
+        delay: Duration,
+    ) -> Result<(), FbInkError> {
+        match self {
+            Sink::Gif(encoder) => {
+                let (width, height) = (rect.width, rect.height);
+                let mut frame = if converter.color() == ScreenshotColor::Rgb {
+                    gif::Frame::from_rgb_speed(width, height, &data, 10)
+                } else {
+                    gif::Frame {
+                        width,
+                        height,
+                        buffer: Cow::Owned(data),
+                        ..Default::default()
+                    }
+                };
+                frame.delay = delay_centis(delay);
+                encoder.write_frame(&frame)?;
+            }
+            Sink::Apng { frames, .. } => frames.push((data, delay)),
+            Sink::PngSequence(_) => (),
+        }
+        Ok(())
+    }
+
+    fn finish(self, rect: Rect<Native>, converter: &RowConverter) -> Result<(), FbInkError> {
+        match self {
+            Sink::Gif(encoder) => {
+                encoder.into_inner()?;
+            }
+            Sink::Apng { path, frames } if !frames.is_empty() => {
+                let file = BufWriter::new(File::create(path)?);
+                let mut encoder = png::Encoder::new(file, rect.width.into(), rect.height.into());
+                let (color_type, depth) = converter.png_color();
+                encoder.set_color(color_type);
+                encoder.set_depth(depth);
+                encoder.set_animated(frames.len() as u32, 0)?;
+                let mut writer = encoder.write_header()?;
+                for (data, delay) in frames {
+                    writer.set_frame_delay(delay_millis(delay), 1000)?;
+                    writer.write_image_data(&data)?;
+                }
+                writer.finish()?;
+            }
+            Sink::Apng { .. } | Sink::PngSequence(_) => (),
+        }
+        Ok(())
+    }
+}
+
+/// Convert a dump to the packed rows of a GIF or APNG frame
+fn convert(
+    converter: &RowConverter,
+    rect: Rect<Native>,
+    dump: &dyn Dump,
+) -> Result<Vec<u8>, FbInkError> {
+    let area = dump.area();
+    if (area.width, area.height) != (rect.width, rect.height) {
+        let msg = format!(
+            "dump is {}x{} but the recording is {}x{}",
+            area.width, area.height, rect.width, rect.height
+        );
+        return Err(FbInkError::InvalidArgument(msg));
+    }
+    let (width, height) = (usize::from(area.width), usize::from(area.height));
+    let row_len = converter.row_len(width);
+    let mut data = vec![0; row_len * height];
+    let src = dump.data();
+    for (y, row) in data.chunks_exact_mut(row_len.max(1)).enumerate() {
+        let src_row = src.get(y * dump.stride()..).unwrap_or_default();
+        if converter.convert(src_row, 0, width, row).is_none() {
+            let msg = "dump has less data than its area requires".into();
+            return Err(FbInkError::InvalidArgument(msg));
+        }
+    }
+    Ok(data)
+}
+
+/// APNG delays are written as milliseconds
+fn delay_millis(delay: Duration) -> u16 {
+    delay.as_millis().min(u16::MAX.into()) as u16
+}
+
+/// GIF delays are in hundredths of a second
+fn delay_centis(delay: Duration) -> u16 {
+    (delay.as_millis() / 10).min(u16::MAX.into()) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Display;
+    use crate::dump::OwnedDump;
+    use crate::state::PixelFormat;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+    use crate::FbInkConfig;
+
+    fn gray8() -> RowConverter {
+        let fbink = VirtualFbInk::new(VirtualDevice::default(), FbInkConfig::default()).unwrap();
+        let state = fbink.state();
+        RowConverter::new(8, PixelFormat::Y8, ScreenshotColor::Gray8, &state)
+    }
+
+    /// An 8bpp dump whose rows are padded to a stride of 4 bytes
+    fn padded(width: u16, height: u16) -> OwnedDump {
+        let data = (0..4 * height).map(|i| i as u8).collect();
+        let area = Rect::new(0, 0, width, height);
+        OwnedDump::new(data, 4, area, 0, 8).unwrap()
+    }
+
+    #[test]
+    fn convert_drops_padding() {
+        let data = convert(&gray8(), Rect::new(0, 0, 3, 2), &padded(3, 2)).unwrap();
+        assert_eq!(data, [0, 1, 2, 4, 5, 6]);
+    }
+
+    #[test]
+    fn convert_size_mismatch() {
+        let result = convert(&gray8(), Rect::new(0, 0, 3, 3), &padded(3, 2));
+        match result {
+            Err(FbInkError::InvalidArgument(msg)) => {
+                assert_eq!(msg, "dump is 3x2 but the recording is 3x3");
+            }
+            other => panic!("expected a size mismatch, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn delays() {
+        assert_eq!(delay_millis(Duration::from_millis(1500)), 1500);
+        assert_eq!(delay_millis(Duration::from_secs(100)), u16::MAX);
+        assert_eq!(delay_centis(Duration::from_millis(1234)), 123);
+        // Anything shorter than a hundredth of a second rounds down to no delay
+        assert_eq!(delay_centis(Duration::from_millis(9)), 0);
+        assert_eq!(delay_centis(Duration::from_secs(1000)), u16::MAX);
+    }
+
+    /// Write two 3x2 frames shown for 250ms and 1.5s
+    fn record(output: RecordingOutput) {
+        let (rect, converter) = (Rect::new(0, 0, 3, 2), gray8());
+        let mut sink = Sink::new(output, rect).unwrap();
+        for delay in [250, 1500] {
+            let data = convert(&converter, rect, &padded(3, 2)).unwrap();
+            let delay = Duration::from_millis(delay);
+            sink.write_frame(rect, &converter, data, delay).unwrap();
+        }
+        sink.finish(rect, &converter).unwrap();
+    }
+
+    #[test]
+    fn gif_delays() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("recording.gif");
+        record(RecordingOutput::Gif(path.clone()));
+        let mut decoder = gif::DecodeOptions::new()
+            .read_info(File::open(path).unwrap())
+            .unwrap();
+        let mut delays = Vec::new();
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            assert_eq!((frame.width, frame.height), (3, 2));
+            delays.push(frame.delay);
+        }
+        assert_eq!(delays, [25, 150]);
+    }
+
+    #[test]
+    fn apng_delays() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("recording.png");
+        record(RecordingOutput::Apng(path.clone()));
+        let decoder = png::Decoder::new(File::open(path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let frames = reader.info().animation_control.unwrap().num_frames;
+        assert_eq!(frames, 2);
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let mut delays = Vec::new();
+        for _ in 0..frames {
+            reader.next_frame(&mut buf).unwrap();
+            let control = reader.info().frame_control.unwrap();
+            delays.push((control.delay_num, control.delay_den));
+            assert_eq!(&buf[..6], [0, 1, 2, 4, 5, 6]);
+        }
+        assert_eq!(delays, [(250, 1000), (1500, 1000)]);
+    }
+
+    #[test]
+    fn empty_apng_isnt_written() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("recording.png");
+        let sink = Sink::new(RecordingOutput::Apng(path.clone()), Rect::new(0, 0, 3, 2)).unwrap();
+        sink.finish(Rect::new(0, 0, 3, 2), &gray8()).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,534 @@
+//! Streaming PNG encoding of dumps, converting straight from the framebuffer's pixel format
+use crate::dump::Dump;
+use crate::error::FbInkError;
+use crate::state::{FbInkState, PixelFormat};
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The colour type of a screenshot's PNG
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScreenshotColor {
+    /// 8-bit grayscale on grayscale panels, RGB on colour panels
+    #[default]
+    Auto,
+    /// 8-bit grayscale
+    Gray8,
+    /// 4-bit grayscale. Matches the 16 levels of gray most eInk panels can display, and
+    /// produces much smaller files than 8-bit.
+    Gray4,
+    /// 8-bit RGB. Alpha is always dropped
+    Rgb,
+}
+
+/// The orientation of a screenshot
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ScreenshotOrientation {
+    /// Rotated to match what the user sees, regardless of the framebuffer's native rotation
+    #[default]
+    Canonical,
+    /// The framebuffer's native layout, exactly as dumped
+    Native,
+}
+
+/// Options for [`FbInk::screenshot_to`](crate::FbInk::screenshot_to)
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScreenshotOptions {
+    pub color: ScreenshotColor,
+    /// Embed the device, rotation and time the screenshot was taken as PNG text chunks
+    pub metadata: bool,
+    pub orientation: ScreenshotOrientation,
+}
+
+impl Default for ScreenshotOptions {
+    fn default() -> Self {
+        Self {
+            color: ScreenshotColor::Auto,
+            metadata: true,
+            orientation: ScreenshotOrientation::Canonical,
+        }
+    }
+}
+
+impl ScreenshotOptions {
+    /// How many clockwise quarter turns a dump needs to match the requested orientation
+    pub(crate) fn turns(&self, dump: &dyn Dump, state: &FbInkState) -> u8 {
+        match self.orientation {
+            ScreenshotOrientation::Canonical => state.display_turns(dump.rota()),
+            ScreenshotOrientation::Native => 0,
+        }
+    }
+}
+
+/// Encode a dump as a PNG one row at a time, without copying the whole frame. `format` is
+/// the pixel format of the dump's data, which for a [`SunxiDump`](crate::dump::SunxiDump) is
+/// always RGB24 rather than the framebuffer's. Only the clipped region of a cropped dump is
+/// encoded, rotated according to the options' orientation.
+pub fn write_png<W: Write>(
+    dump: &dyn Dump,
+    format: PixelFormat,
+    state: &FbInkState,
+    writer: W,
+    options: &ScreenshotOptions,
+) -> Result<(), FbInkError> {
+    let bpp = dump.bpp();
+    if !matches!(bpp, 4 | 8 | 16 | 24 | 32) {
+        let msg = format!("Can't encode a dump with {bpp} bpp");
+        return Err(FbInkError::NotSupported(msg));
+    }
+    let area = dump.area();
+    let rect = if dump.is_full() { area } else { dump.clip() };
+    let (x0, y0) = (
+        usize::from(rect.left.saturating_sub(area.left)),
+        usize::from(rect.top.saturating_sub(area.top)),
+    );
+    let (width, height) = (usize::from(rect.width), usize::from(rect.height));
+    let converter = RowConverter::new(bpp, format, options.color, state);
+    let turns = options.turns(dump, state);
+    let (out_width, out_height) = if turns % 2 == 1 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+
+    let mut encoder = png::Encoder::new(writer, out_width as u32, out_height as u32);
+    let (color_type, depth) = converter.png_color();
+    encoder.set_color(color_type);
+    encoder.set_depth(depth);
+    if options.metadata {
+        for (keyword, text) in metadata(state) {
+            encoder.add_text_chunk(keyword.into(), text)?;
+        }
+    }
+    let mut writer = encoder.write_header()?;
+    let mut stream = writer.stream_writer()?;
+
+    let data = dump.data();
+    let stride = dump.stride();
+    let mut row = vec![0; converter.row_len(out_width)];
+    for y in 0..out_height {
+        let converted = if turns == 0 {
+            let src = data.get((y0 + y) * stride..).unwrap_or_default();
+            converter.convert(src, x0, width, &mut row)
+        } else {
+            // Map each pixel of the rotated row back to where it is in the dump
+            converter.convert_with(out_width, &mut row, |x| {
+                let (sx, sy) = match turns {
+                    1 => (y, height - 1 - x),
+                    2 => (width - 1 - x, height - 1 - y),
+                    _ => (width - 1 - y, x),
+                };
+                let src = data.get((y0 + sy) * stride..)?;
+                decode_rgb(src, x0 + sx, bpp, format)
+            })
+        };
+        if converted.is_none() {
+            let msg = "dump has less data than its area requires".into();
+            return Err(FbInkError::InvalidArgument(msg));
+        }
+        stream.write_all(&row)?;
+    }
+    stream.finish()?;
+    Ok(())
+}
+
+/// Converts rows of packed framebuffer data to the pixel layout of a screenshot
+pub(crate) struct RowConverter {
+    bpp: u8,
+    format: PixelFormat,
+    /// Never [`ScreenshotColor::Auto`]
+    color: ScreenshotColor,
+    invert: bool,
+}
+
+impl RowConverter {
+    pub(crate) fn new(
+        bpp: u8,
+        format: PixelFormat,
+        color: ScreenshotColor,
+        state: &FbInkState,
+    ) -> Self {
+        let color = match color {
+            ScreenshotColor::Auto if state.has_color_panel => ScreenshotColor::Rgb,
+            ScreenshotColor::Auto => ScreenshotColor::Gray8,
+            color => color,
+        };
+        let invert =
+            state.inverted_grayscale && matches!(format, PixelFormat::Y4 | PixelFormat::Y8);
+        Self {
+            bpp,
+            format,
+            color,
+            invert,
+        }
+    }
+
+    #[cfg(feature = "image")]
+    pub(crate) fn color(&self) -> ScreenshotColor {
+        self.color
+    }
+
+    pub(crate) fn png_color(&self) -> (png::ColorType, png::BitDepth) {
+        match self.color {
+            ScreenshotColor::Rgb => (png::ColorType::Rgb, png::BitDepth::Eight),
+            ScreenshotColor::Gray4 => (png::ColorType::Grayscale, png::BitDepth::Four),
+            _ => (png::ColorType::Grayscale, png::BitDepth::Eight),
+        }
+    }
+
+    /// Bytes needed for a converted row of `width` pixels
+    pub(crate) fn row_len(&self, width: usize) -> usize {
+        match self.color {
+            ScreenshotColor::Rgb => width * 3,
+            ScreenshotColor::Gray4 => width.div_ceil(2),
+            _ => width,
+        }
+    }
+
+    /// Convert `width` pixels of `src` starting at `x0` into `out`, which must be
+    /// [`row_len`](Self::row_len) bytes long. Returns None if `src` is too short.
+    pub(crate) fn convert(
+        &self,
+        src: &[u8],
+        x0: usize,
+        width: usize,
+        out: &mut [u8],
+    ) -> Option<()> {
+        self.convert_with(width, out, |x| {
+            decode_rgb(src, x0 + x, self.bpp, self.format)
+        })
+    }
+
+    /// Like [`convert`](Self::convert), but reads pixel `x` of the row with `pixel`
+    pub(crate) fn convert_with<F: Fn(usize) -> Option<[u8; 3]>>(
+        &self,
+        width: usize,
+        out: &mut [u8],
+        pixel: F,
+    ) -> Option<()> {
+        out.fill(0);
+        for x in 0..width {
+            let rgb = pixel(x)?;
+            if self.color == ScreenshotColor::Rgb {
+                out[x * 3..x * 3 + 3].copy_from_slice(&rgb);
+                continue;
+            }
+            let mut gray = luma(rgb);
+            if self.invert {
+                gray = 255 - gray;
+            }
+            if self.color == ScreenshotColor::Gray4 {
+                // PNG packs the leftmost pixel into the high nibble
+                let nibble = gray >> 4;
+                out[x / 2] |= if x.is_multiple_of(2) {
+                    nibble << 4
+                } else {
+                    nibble
+                };
+            } else {
+                out[x] = gray;
+            }
+        }
+        Some(())
+    }
+}
+
+/// Read pixel `x` of a row of packed framebuffer data as RGB
+pub(crate) fn decode_rgb(row: &[u8], x: usize, bpp: u8, format: PixelFormat) -> Option<[u8; 3]> {
+    let rgb = match bpp {
+        4 => {
+            let byte = row.get(x / 2)?;
+            // The even pixel is in the high nibble
+            let nibble = if x.is_multiple_of(2) {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+            let v = nibble * 17;
+            [v, v, v]
+        }
+        8 => {
+            let v = *row.get(x)?;
+            [v, v, v]
+        }
+        16 => {
+            let p = row.get(x * 2..x * 2 + 2)?;
+            let v = u16::from_le_bytes([p[0], p[1]]);
+            let (hi, mid, lo) = ((v >> 11) as u8, ((v >> 5) & 0x3F) as u8, (v & 0x1F) as u8);
+            let (hi, mid, lo) = (
+                (hi << 3) | (hi >> 2),
+                (mid << 2) | (mid >> 4),
+                (lo << 3) | (lo >> 2),
+            );
+            match format {
+                PixelFormat::Bgr565 => [lo, mid, hi],
+                _ => [hi, mid, lo],
+            }
+        }
+        24 => {
+            let p = row.get(x * 3..x * 3 + 3)?;
+            match format {
+                PixelFormat::Bgr24 => [p[2], p[1], p[0]],
+                _ => [p[0], p[1], p[2]],
+            }
+        }
+        32 => {
+            let p = row.get(x * 4..x * 4 + 4)?;
+            match format {
+                PixelFormat::Bgra | PixelFormat::Bgr32 => [p[2], p[1], p[0]],
+                _ => [p[0], p[1], p[2]],
+            }
+        }
+        _ => return None,
+    };
+    Some(rgb)
+}
+
+/// Write an RGB colour as pixel `x` of a row of packed framebuffer data. Grayscale formats
+/// store the colour's luma. Returns None if `row` is too short.
+pub(crate) fn encode_rgb(
+    row: &mut [u8],
+    x: usize,
+    bpp: u8,
+    format: PixelFormat,
+    rgb: [u8; 3],
+) -> Option<()> {
+    let [r, g, b] = rgb;
+    match bpp {
+        4 => {
+            let byte = row.get_mut(x / 2)?;
+            let nibble = luma(rgb) >> 4;
+            // The even pixel is in the high nibble
+            *byte = if x.is_multiple_of(2) {
+                (*byte & 0x0F) | (nibble << 4)
+            } else {
+                (*byte & 0xF0) | nibble
+            };
+        }
+        8 => *row.get_mut(x)? = luma(rgb),
+        16 => {
+            let (hi, lo) = match format {
+                PixelFormat::Bgr565 => (b, r),
+                _ => (r, b),
+            };
+            let v = (u16::from(hi >> 3) << 11) | (u16::from(g >> 2) << 5) | u16::from(lo >> 3);
+            row.get_mut(x * 2..x * 2 + 2)?
+                .copy_from_slice(&v.to_le_bytes());
+        }
+        24 => {
+            let p = match format {
+                PixelFormat::Bgr24 => [b, g, r],
+                _ => [r, g, b],
+            };
+            row.get_mut(x * 3..x * 3 + 3)?.copy_from_slice(&p);
+        }
+        32 => {
+            let p = match format {
+                PixelFormat::Bgra | PixelFormat::Bgr32 => [b, g, r, u8::MAX],
+                _ => [r, g, b, u8::MAX],
+            };
+            row.get_mut(x * 4..x * 4 + 4)?.copy_from_slice(&p);
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Rec. 601 luma, which is what FBInk uses for its own grayscale conversions
+pub(crate) fn luma([r, g, b]: [u8; 3]) -> u8 {
+    ((u32::from(r) * 299 + u32::from(g) * 587 + u32::from(b) * 114 + 500) / 1000) as u8
+}
+
+fn metadata(state: &FbInkState) -> Vec<(&'static str, String)> {
+    let rotation = format!(
+        "{} (native {})",
+        state.canonical_rotation(),
+        state.current_rota
+    );
+    let mut chunks = vec![
+        (
+            "Software",
+            format!("fbink-rs {}", env!("CARGO_PKG_VERSION")),
+        ),
+        ("Device", state.device_details()),
+        ("Rotation", rotation),
+    ];
+    if let Ok(now) = SystemTime::now().duration_since(UNIX_EPOCH) {
+        chunks.push(("Creation Time", format_utc(now.as_secs())));
+    }
+    chunks
+}
+
+/// Format a Unix timestamp as an RFC 1123 date & time, which the PNG spec recommends for the
+/// Creation Time keyword
+fn format_utc(secs: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Howard Hinnant's civil_from_days
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    // The epoch was a Thursday
+    let weekday = WEEKDAYS[(days % 7) as usize];
+    let month = MONTHS[month as usize - 1];
+    format!(
+        "{weekday}, {day:02} {month} {year:04} {:02}:{:02}:{:02} GMT",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::Rect;
+    use crate::display::Display;
+    use crate::dump::OwnedDump;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+    use crate::FbInkConfig;
+    use image::{imageops, GrayImage};
+
+    fn state() -> FbInkState {
+        let fbink = VirtualFbInk::new(VirtualDevice::default(), FbInkConfig::default()).unwrap();
+        fbink.state()
+    }
+
+    /// An 8bpp dump with a distinct value in every pixel
+    fn gradient(width: u16, height: u16, rota: u8) -> OwnedDump {
+        let len = usize::from(width) * usize::from(height);
+        let data = (0..len).map(|i| (i * 10) as u8).collect();
+        let area = Rect::new(0, 0, width, height);
+        OwnedDump::new(data, width.into(), area, rota, 8).unwrap()
+    }
+
+    /// Encode a dump and decode it again, returning the PNG's info and its packed rows
+    fn encode(
+        dump: &OwnedDump,
+        state: &FbInkState,
+        options: &ScreenshotOptions,
+    ) -> (png::Info<'static>, Vec<u8>) {
+        let mut png = Vec::new();
+        write_png(dump, PixelFormat::Y8, state, &mut png, options).unwrap();
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let frame = reader.next_frame(&mut buf).unwrap();
+        buf.truncate(frame.buffer_size());
+        (reader.info().clone(), buf)
+    }
+
+    fn plain() -> ScreenshotOptions {
+        ScreenshotOptions {
+            metadata: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rotation_mapping() {
+        let dump = gradient(3, 2, 0);
+        let image = GrayImage::from_raw(3, 2, dump.data().to_vec()).unwrap();
+        let expected = [
+            image.clone(),
+            imageops::rotate90(&image),
+            imageops::rotate180(&image),
+            imageops::rotate270(&image),
+        ];
+        let mut state = state();
+        for (turns, expected) in expected.iter().enumerate() {
+            state.current_rota = turns as u8;
+            let (info, data) = encode(&dump, &state, &plain());
+            assert_eq!((info.width, info.height), expected.dimensions(), "{turns}");
+            assert_eq!(data, expected.as_raw().as_slice(), "{turns}");
+
+            // Native screenshots are never rotated
+            let native = ScreenshotOptions {
+                orientation: ScreenshotOrientation::Native,
+                ..plain()
+            };
+            let (info, data) = encode(&dump, &state, &native);
+            assert_eq!((info.width, info.height), (3, 2));
+            assert_eq!(data, image.as_raw().as_slice());
+        }
+    }
+
+    #[test]
+    fn rotated_crop() {
+        let mut dump = gradient(4, 4, 0);
+        dump.crop_rect(Rect::new(1, 1, 3, 2));
+        let image = GrayImage::from_raw(4, 4, dump.data().to_vec()).unwrap();
+        let cropped = imageops::crop_imm(&image, 1, 1, 3, 2).to_image();
+        let mut state = state();
+        state.current_rota = 1;
+        let (info, data) = encode(&dump, &state, &plain());
+        assert_eq!((info.width, info.height), (2, 3));
+        assert_eq!(data, imageops::rotate90(&cropped).into_raw());
+    }
+
+    #[test]
+    fn gray4_packing() {
+        // Three 4bpp pixels, 1 15 8, with the even pixel in the high nibble
+        let data = vec![0x1F, 0x80];
+        let dump = OwnedDump::new(data, 2, Rect::new(0, 0, 3, 1), 0, 4).unwrap();
+        let (info, data) = encode(&dump, &state(), &plain());
+        assert_eq!(info.color_type, png::ColorType::Grayscale);
+        assert_eq!(info.bit_depth, png::BitDepth::Eight);
+        assert_eq!(data, [17, 255, 136]);
+
+        let gray4 = ScreenshotOptions {
+            color: ScreenshotColor::Gray4,
+            ..plain()
+        };
+        let (info, data) = encode(&dump, &state(), &gray4);
+        assert_eq!(info.bit_depth, png::BitDepth::Four);
+        // The odd width leaves the last low nibble empty
+        assert_eq!(data, [0x1F, 0x80]);
+
+        // Panels with inverted grayscale are flipped back
+        let mut inverted = state();
+        inverted.inverted_grayscale = true;
+        let (_, data) = encode(&dump, &inverted, &gray4);
+        assert_eq!(data, [0xE0, 0x70]);
+    }
+
+    #[test]
+    fn metadata_chunks() {
+        let dump = gradient(2, 2, 0);
+        let (info, _) = encode(&dump, &state(), &ScreenshotOptions::default());
+        let text: Vec<_> = info
+            .uncompressed_latin1_text
+            .iter()
+            .map(|chunk| (chunk.keyword.as_str(), chunk.text.as_str()))
+            .collect();
+        let keywords: Vec<_> = text.iter().map(|(keyword, _)| *keyword).collect();
+        assert_eq!(
+            keywords,
+            ["Software", "Device", "Rotation", "Creation Time"]
+        );
+        let (_, time) = text[3];
+        assert!(time.ends_with(" GMT"), "{time}");
+
+        let (info, _) = encode(&dump, &state(), &plain());
+        assert!(info.uncompressed_latin1_text.is_empty());
+    }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_utc(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+        assert_eq!(format_utc(951_782_400), "Tue, 29 Feb 2000 00:00:00 GMT");
+        assert_eq!(format_utc(1_700_000_000), "Tue, 14 Nov 2023 22:13:20 GMT");
+        assert_eq!(format_utc(4_102_444_799), "Thu, 31 Dec 2099 23:59:59 GMT");
+    }
+}
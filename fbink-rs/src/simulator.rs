@@ -0,0 +1,552 @@
+//! Simulate how an eInk panel responds to refreshes, including waveform timing, the flash of
+//! full refreshes and ghosting from repeated partial refreshes, to tune refresh policies
+//! without a device
+use crate::config::{FbInkConfig, WaveformMode};
+use crate::coords::{Native, Rect, Rotated, View};
+use crate::display::Display;
+use crate::dump::Dump;
+use crate::error::FbInkError;
+use crate::region::Region;
+use crate::thin::ReinitResult;
+use crate::virtual_fbink::{RefreshRequest, VirtualFbInk};
+use crate::FbInkState;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use image::{GrayImage, Luma};
+
+/// How a waveform behaves on the simulated panel
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WaveformTiming {
+    /// Time between the refresh being requested and the panel starting to update
+    pub latency: Duration,
+    /// How long the panel takes to update
+    pub duration: Duration,
+    /// Number of gray levels the waveform can display
+    pub gray_levels: u8,
+    /// Fraction of the previous content that lingers after a non-flashing update
+    pub ghosting: f32,
+}
+
+impl WaveformTiming {
+    /// Rough timings of the common waveforms on an i.MX EPDC. Waveforms not listed behave
+    /// like GL16. Auto behaves like GC16 when flashing and GL16 otherwise.
+    pub fn for_mode(mode: WaveformMode, is_flashing: bool) -> Self {
+        let timing = |latency, duration, gray_levels, ghosting| Self {
+            latency: Duration::from_millis(latency),
+            duration: Duration::from_millis(duration),
+            gray_levels,
+            ghosting,
+        };
+        match mode {
+            WaveformMode::A2 | WaveformMode::A2In | WaveformMode::A2Out => timing(10, 120, 2, 0.12),
+            WaveformMode::Dual | WaveformMode::Dunm => timing(20, 260, 2, 0.06),
+            WaveformMode::DU4 | WaveformMode::GC4 | WaveformMode::GL4 | WaveformMode::GC4L => {
+                timing(20, 290, 4, 0.04)
+            }
+            WaveformMode::GC16 | WaveformMode::GC16HQ | WaveformMode::GCC16 => {
+                timing(30, 450, 16, 0.01)
+            }
+            WaveformMode::GC16Fast => timing(30, 320, 16, 0.015),
+            WaveformMode::Reagl | WaveformMode::Reagld | WaveformMode::GLRC16 => {
+                timing(30, 450, 16, 0.003)
+            }
+            WaveformMode::Auto if is_flashing => timing(30, 450, 16, 0.01),
+            WaveformMode::GL16Fast => timing(30, 320, 16, 0.03),
+            _ => timing(30, 450, 16, 0.03),
+        }
+    }
+}
+
+/// Options for the [`Simulator`]
+#[derive(Debug, Clone, Copy)]
+pub struct SimulatorOptions {
+    /// Timing of each waveform, given whether the refresh is flashing
+    pub timing: fn(WaveformMode, bool) -> WaveformTiming,
+    /// Capture a frame when a flashing refresh turns its region black, as well as when each
+    /// refresh completes
+    pub capture_flash: bool,
+}
+
+impl Default for SimulatorOptions {
+    fn default() -> Self {
+        Self {
+            timing: WaveformTiming::for_mode,
+            capture_flash: true,
+        }
+    }
+}
+
+/// When a refresh was requested and when the simulated panel performed it
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimelineEntry {
+    pub request: RefreshRequest,
+    pub submitted: Duration,
+    pub start: Duration,
+    pub end: Duration,
+}
+
+/// What the panel looked like at a point in time
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub time: Duration,
+    /// The marker of the refresh that produced the frame
+    pub marker: u32,
+    pub image: GrayImage,
+}
+
+/// A submitted refresh that the panel hasn't finished performing yet
+#[derive(Debug, Clone)]
+struct Update {
+    rect: Rect<Rotated>,
+    marker: u32,
+    is_flashing: bool,
+    ghosting: f32,
+    /// When the flash turns the region black, if it's still to be captured
+    flash: Option<Duration>,
+    end: Duration,
+    /// The level each pixel in the rect is driven to, row by row
+    levels: Vec<u8>,
+}
+
+/// Models an EPDC updating a grayscale panel. Feed it refresh requests along with the
+/// framebuffer's content at the time, and it produces frames of what a user would perceive.
+/// Each update takes effect when it ends, so the panel and frames only reflect updates that
+/// have completed by the current simulated time.
+#[derive(Debug, Clone)]
+pub struct Simulator {
+    options: SimulatorOptions,
+    width: u32,
+    height: u32,
+    /// The gray level each pixel was last driven to
+    panel: Vec<u8>,
+    /// Remnants of previous content, added to the panel's level when perceived
+    ghost: Vec<f32>,
+    now: Duration,
+    timeline: Vec<TimelineEntry>,
+    pending: Vec<Update>,
+    frames: Vec<Frame>,
+}
+
+impl Simulator {
+    /// Simulate a white panel of the given size
+    pub fn new(width: u16, height: u16, options: SimulatorOptions) -> Self {
+        let pixels = usize::from(width) * usize::from(height);
+        Self {
+            options,
+            width: width.into(),
+            height: height.into(),
+            panel: vec![u8::MAX; pixels],
+            ghost: vec![0.0; pixels],
+            now: Duration::ZERO,
+            timeline: Vec::new(),
+            pending: Vec::new(),
+            frames: Vec::new(),
+        }
+    }
+
+    /// The current simulated time
+    pub fn now(&self) -> Duration {
+        self.now
+    }
+
+    /// Move the simulated time forward, e.g. to model time spent rendering between refreshes
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+        self.settle();
+    }
+
+    /// Submit a refresh of `framebuffer` at the current simulated time. The update starts
+    /// after the waveform's latency, or once any earlier update of an overlapping region has
+    /// finished, like an EPDC handling collisions.
+    pub fn refresh(
+        &mut self,
+        request: &RefreshRequest,
+        framebuffer: &GrayImage,
+    ) -> Result<&TimelineEntry, FbInkError> {
+        if framebuffer.dimensions() != (self.width, self.height) {
+            let msg = format!(
+                "framebuffer is {:?} but the panel is {}x{}",
+                framebuffer.dimensions(),
+                self.width,
+                self.height
+            );
+            return Err(FbInkError::InvalidArgument(msg));
+        }
+        self.settle();
+        let rect = self.clamp(request.rect);
+        let timing = (self.options.timing)(request.wfm_mode, request.is_flashing);
+        let collision_end = self
+            .timeline
+            .iter()
+            .filter(|e| overlaps(e.request.rect, rect))
+            .map(|e| e.end)
+            .max()
+            .unwrap_or_default();
+        let start = (self.now + timing.latency).max(collision_end);
+        let end = start + timing.duration;
+
+        // The EPDC reads the framebuffer when the refresh is submitted
+        let step = 255.0 / (f32::from(timing.gray_levels.max(2)) - 1.0);
+        let mut levels = Vec::with_capacity(usize::from(rect.width) * usize::from(rect.height));
+        for y in u32::from(rect.top)..u32::from(rect.top) + u32::from(rect.height) {
+            for x in u32::from(rect.left)..u32::from(rect.left) + u32::from(rect.width) {
+                let target = framebuffer.get_pixel(x, y)[0];
+                levels.push(((f32::from(target) / step).round() * step) as u8);
+            }
+        }
+        let flash = request.is_flashing && self.options.capture_flash;
+        self.pending.push(Update {
+            rect,
+            marker: request.marker,
+            is_flashing: request.is_flashing,
+            ghosting: timing.ghosting,
+            flash: flash.then_some(start + timing.duration / 2),
+            end,
+            levels,
+        });
+        self.timeline.push(TimelineEntry {
+            request: RefreshRequest { rect, ..*request },
+            submitted: self.now,
+            start,
+            end,
+        });
+        Ok(self.timeline.last().unwrap())
+    }
+
+    /// Move the simulated time to when the refresh with the given marker completes
+    pub fn wait_for_complete(&mut self, marker: u32) {
+        if let Some(entry) = self.timeline.iter().find(|e| e.request.marker == marker) {
+            self.now = self.now.max(entry.end);
+        }
+        self.settle();
+    }
+
+    /// Move the simulated time to when every submitted refresh has completed
+    pub fn wait_for_all(&mut self) {
+        if let Some(end) = self.timeline.iter().map(|e| e.end).max() {
+            self.now = self.now.max(end);
+        }
+        self.settle();
+    }
+
+    /// What the panel currently looks like, including ghosting. Updates still in progress
+    /// aren't shown.
+    pub fn perceived(&self) -> GrayImage {
+        GrayImage::from_fn(self.width, self.height, |x, y| {
+            let i = (y * self.width + x) as usize;
+            Luma([(f32::from(self.panel[i]) + self.ghost[i]).clamp(0.0, 255.0) as u8])
+        })
+    }
+
+    /// How much ghosting has built up, as the mean absolute difference per pixel in gray levels
+    pub fn ghosting(&self) -> f32 {
+        if self.ghost.is_empty() {
+            return 0.0;
+        }
+        self.ghost.iter().map(|g| g.abs()).sum::<f32>() / self.ghost.len() as f32
+    }
+
+    pub fn timeline(&self) -> &[TimelineEntry] {
+        &self.timeline
+    }
+
+    /// The frames captured so far in chronological order. Updates are only captured once the
+    /// simulated time passes them, so call [`Simulator::wait_for_all`] to capture every one.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    /// Return the captured frames and stop holding on to them
+    pub fn take_frames(&mut self) -> Vec<Frame> {
+        std::mem::take(&mut self.frames)
+    }
+
+    /// Write each frame as a PNG named after its number and time, e.g. `00003_001500ms.png`,
+    /// along with the timeline as `timeline.csv`
+    pub fn save<P: AsRef<Path>>(&self, dir: P) -> Result<(), FbInkError> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        for (n, frame) in self.frames.iter().enumerate() {
+            let name = format!("{n:05}_{:06}ms.png", frame.time.as_millis());
+            frame.image.save(dir.join(name))?;
+        }
+        let mut csv = fs::File::create(dir.join("timeline.csv"))?;
+        writeln!(
+            csv,
+            "marker,wfm_mode,is_flashing,left,top,width,height,submitted_ms,start_ms,end_ms"
+        )?;
+        for e in &self.timeline {
+            let r = e.request.rect;
+            writeln!(
+                csv,
+                "{},{:?},{},{},{},{},{},{},{},{}",
+                e.request.marker,
+                e.request.wfm_mode,
+                e.request.is_flashing,
+                r.left,
+                r.top,
+                r.width,
+                r.height,
+                e.submitted.as_millis(),
+                e.start.as_millis(),
+                e.end.as_millis()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Perform the pending updates that have completed by now, in the order they complete
+    fn settle(&mut self) {
+        loop {
+            // Each update's next event is its flash, if any, and then its end
+            let next = self
+                .pending
+                .iter()
+                .enumerate()
+                .map(|(i, update)| (update.flash.unwrap_or(update.end), i))
+                .filter(|&(time, _)| time <= self.now)
+                .min();
+            let Some((time, i)) = next else {
+                break;
+            };
+            let update = &mut self.pending[i];
+            let marker = update.marker;
+            if update.flash.take().is_some() {
+                let rect = update.rect;
+                self.drive(rect, 0);
+            } else {
+                let update = self.pending.remove(i);
+                self.apply(&update);
+            }
+            let image = self.perceived();
+            self.push_frame(time, marker, image);
+        }
+    }
+
+    /// Drive the update's pixels to their new levels, leaving behind some of the old content
+    /// unless it flashes
+    fn apply(&mut self, update: &Update) {
+        let rect = update.rect;
+        let mut levels = update.levels.iter();
+        for y in u32::from(rect.top)..u32::from(rect.top) + u32::from(rect.height) {
+            for x in u32::from(rect.left)..u32::from(rect.left) + u32::from(rect.width) {
+                let i = (y * self.width + x) as usize;
+                let level = *levels.next().unwrap();
+                if update.is_flashing {
+                    self.ghost[i] = 0.0;
+                } else if level != self.panel[i] {
+                    let previous = f32::from(self.panel[i]) - f32::from(level);
+                    self.ghost[i] =
+                        self.ghost[i] * (1.0 - update.ghosting) + previous * update.ghosting;
+                }
+                self.panel[i] = level;
+            }
+        }
+    }
+
+    /// Drive every pixel in the rect to the same level, clearing any ghosting like a flash
+    fn drive(&mut self, rect: Rect<Rotated>, level: u8) {
+        for y in u32::from(rect.top)..u32::from(rect.top) + u32::from(rect.height) {
+            let row = (y * self.width) as usize;
+            let (left, right) = (usize::from(rect.left), usize::from(rect.left + rect.width));
+            self.panel[row + left..row + right].fill(level);
+            self.ghost[row + left..row + right].fill(0.0);
+        }
+    }
+
+    fn push_frame(&mut self, time: Duration, marker: u32, image: GrayImage) {
+        self.frames.push(Frame {
+            time,
+            marker,
+            image,
+        });
+    }
+
+    fn clamp(&self, rect: Rect<Rotated>) -> Rect<Rotated> {
+        if rect.is_empty() {
+            return Rect::new(0, 0, self.width as u16, self.height as u16);
+        }
+        let left = u32::from(rect.left).min(self.width);
+        let top = u32::from(rect.top).min(self.height);
+        Rect::new(
+            left as u16,
+            top as u16,
+            u32::from(rect.width).min(self.width - left) as u16,
+            u32::from(rect.height).min(self.height - top) as u16,
+        )
+    }
+}
+
+fn overlaps(a: Rect<Rotated>, b: Rect<Rotated>) -> bool {
+    u32::from(a.left) < u32::from(b.left) + u32::from(b.width)
+        && u32::from(b.left) < u32::from(a.left) + u32::from(a.width)
+        && u32::from(a.top) < u32::from(b.top) + u32::from(b.height)
+        && u32::from(b.top) < u32::from(a.top) + u32::from(a.height)
+}
+
+/// A [`VirtualFbInk`] whose refreshes are fed to a [`Simulator`] as they're requested.
+/// Waiting for a refresh to complete advances the simulated time instead of blocking.
+#[derive(Debug)]
+pub struct SimulatedFbInk {
+    pub fbink: VirtualFbInk,
+    simulator: Mutex<Simulator>,
+}
+
+impl SimulatedFbInk {
+    pub fn new(fbink: VirtualFbInk, options: SimulatorOptions) -> Self {
+        // VirtualFbInk already rejects devices larger than FBInk's coordinates can address
+        let device = fbink.device();
+        let (width, height) = (device.width as u16, device.height as u16);
+        Self {
+            fbink,
+            simulator: Mutex::new(Simulator::new(width, height, options)),
+        }
+    }
+
+    pub fn simulator(&self) -> MutexGuard<'_, Simulator> {
+        self.simulator.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Submit any refreshes the virtual framebuffer has logged since the last call
+    fn submit(&self) -> Result<(), FbInkError> {
+        let requests = self.fbink.take_refreshes();
+        if requests.is_empty() {
+            return Ok(());
+        }
+        let framebuffer = self.fbink.image().to_luma8();
+        let mut simulator = self.simulator();
+        for request in &requests {
+            simulator.refresh(request, &framebuffer)?;
+        }
+        Ok(())
+    }
+
+    fn then_submit<T>(&self, result: Result<T, FbInkError>) -> Result<T, FbInkError> {
+        let value = result?;
+        self.submit()?;
+        Ok(value)
+    }
+}
+
+impl Display for SimulatedFbInk {
+    fn state(&self) -> FbInkState {
+        self.fbink.state()
+    }
+    fn config(&self) -> &FbInkConfig {
+        &self.fbink.config
+    }
+    fn config_mut(&mut self) -> &mut FbInkConfig {
+        &mut self.fbink.config
+    }
+    fn reinit(&self) -> ReinitResult {
+        self.fbink.reinit()
+    }
+    fn print(&self, msg: &str) -> Result<i32, FbInkError> {
+        self.then_submit(self.fbink.print(msg))
+    }
+    fn print_coords(&self, msg: &str, x: i16, y: i16) -> Result<i32, FbInkError> {
+        self.then_submit(self.fbink.print_coords(msg, x, y))
+    }
+    fn print_raw_data(&self, data: &[u8], rect: Rect<View>) -> Result<(), FbInkError> {
+        self.then_submit(self.fbink.print_raw_data(data, rect))
+    }
+    fn refresh(&self, rect: Rect<Rotated>) -> Result<(), FbInkError> {
+        self.then_submit(self.fbink.refresh(rect))
+    }
+    fn refresh_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        self.then_submit(self.fbink.refresh_rect(region))
+    }
+    fn cls(&self) -> Result<(), FbInkError> {
+        self.then_submit(self.fbink.cls())
+    }
+    fn cls_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        self.then_submit(self.fbink.cls_rect(region))
+    }
+    fn dump(&self) -> Result<Box<dyn Dump>, FbInkError> {
+        self.fbink.dump()
+    }
+    fn rect_dump(&self, region: &Region<Native>) -> Result<Box<dyn Dump>, FbInkError> {
+        self.fbink.rect_dump(region)
+    }
+    fn restore(&self, dump: &dyn Dump) -> Result<(), FbInkError> {
+        self.then_submit(self.fbink.restore(dump))
+    }
+    fn get_last_rect(&self) -> Rect<Rotated> {
+        self.fbink.get_last_rect()
+    }
+    fn get_last_native_rect(&self) -> Rect<Native> {
+        self.fbink.get_last_native_rect()
+    }
+    fn get_last_marker(&self) -> u32 {
+        self.fbink.get_last_marker()
+    }
+    fn wait_for_complete(&self, marker: u32) -> Result<(), FbInkError> {
+        self.simulator().wait_for_complete(marker);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::HardwareDitherMode;
+
+    fn request(marker: u32, wfm_mode: WaveformMode, rect: Rect<Rotated>) -> RefreshRequest {
+        RefreshRequest {
+            rect,
+            wfm_mode,
+            dithering_mode: HardwareDitherMode::default(),
+            is_flashing: false,
+            is_nightmode: false,
+            marker,
+        }
+    }
+
+    #[test]
+    fn updates_apply_in_end_order() {
+        let mut simulator = Simulator::new(8, 4, SimulatorOptions::default());
+        let black = GrayImage::new(8, 4);
+        let slow = request(1, WaveformMode::GC16, Rect::new(0, 0, 4, 4));
+        simulator.refresh(&slow, &black).unwrap();
+        simulator.advance(Duration::from_millis(10));
+        // Submitted later but finishes first
+        let fast = request(2, WaveformMode::A2, Rect::new(4, 0, 4, 4));
+        simulator.refresh(&fast, &black).unwrap();
+        assert!(simulator.frames().is_empty());
+        assert_eq!(simulator.perceived().get_pixel(6, 0)[0], u8::MAX);
+
+        simulator.wait_for_all();
+        let frames = simulator.frames();
+        let markers: Vec<_> = frames.iter().map(|f| f.marker).collect();
+        assert_eq!(markers, [2, 1]);
+        assert!(frames[0].time < frames[1].time);
+        // The slow update hasn't reached the panel when the fast one completes
+        assert_eq!(frames[0].image.get_pixel(0, 0)[0], u8::MAX);
+        assert!(frames[0].image.get_pixel(6, 0)[0] < 128);
+        assert!(frames[1].image.get_pixel(0, 0)[0] < 128);
+    }
+
+    #[test]
+    fn flash_is_captured_mid_update() {
+        let mut simulator = Simulator::new(4, 4, SimulatorOptions::default());
+        let mut flashing = request(1, WaveformMode::GC16, Rect::default());
+        flashing.is_flashing = true;
+        let white = GrayImage::from_pixel(4, 4, Luma([u8::MAX]));
+        let entry = simulator.refresh(&flashing, &white).unwrap().clone();
+        simulator.wait_for_complete(1);
+        assert_eq!(simulator.now(), entry.end);
+        let frames = simulator.frames();
+        assert_eq!(frames.len(), 2);
+        assert!(frames[0].time > entry.start && frames[0].time < entry.end);
+        assert_eq!(frames[0].image.get_pixel(2, 2)[0], 0);
+        assert_eq!(frames[1].image.get_pixel(2, 2)[0], u8::MAX);
+        assert_eq!(simulator.ghosting(), 0.0);
+    }
+}
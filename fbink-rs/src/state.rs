@@ -143,6 +143,31 @@ impl FbInkState {
             Err(_) => CanonicalRotation::from_primitive(self.current_rota),
         }
     }
+
+    /// How many clockwise quarter turns rotate data laid out for the native rotation `rota` to
+    /// the layout of the current rotation. Compares their canonical rotations using the
+    /// rotation map, which accounts for any quirks in how the native rotation is reported.
+    pub fn quarter_turns_from(&self, rota: u8) -> u8 {
+        let canonical = |native: u8| self.rotation_map.iter().position(|&r| r == native);
+        let mut sorted = self.rotation_map;
+        sorted.sort_unstable();
+        if sorted == [0, 1, 2, 3] {
+            if let (Some(current), Some(from)) = (canonical(self.current_rota), canonical(rota)) {
+                return ((current + 4 - from) % 4) as u8;
+            }
+        }
+        // No usable rotation map, so assume native rotations increase clockwise
+        ((self.current_rota % 4) + 4 - (rota % 4)) % 4
+    }
+
+    /// How many clockwise quarter turns rotate a dump taken at the native rotation `rota` to
+    /// the orientation the user currently sees the screen in
+    pub fn display_turns(&self, rota: u8) -> u8 {
+        // In pickel's quirky landscape mode the framebuffer is landscape, but what's drawn
+        // is shown in portrait, rotated clockwise
+        let quirk = u8::from(self.is_ntx_quirky_landscape);
+        (self.quarter_turns_from(rota) + quirk) % 4
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, FromPrimitive, IntoPrimitive)]
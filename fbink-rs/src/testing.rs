@@ -0,0 +1,396 @@
+//! Helpers for comparing what's on screen against reference ("golden") images in tests.
+//!
+//! Set the `FBINK_BLESS` environment variable to write the current dump as the new golden
+//! image instead of comparing against it. When a comparison fails, the actual image and a diff
+//! highlighting mismatched pixels in red are written next to the golden image as
+//! `<name>.actual.png` and `<name>.diff.png`.
+use crate::dump::Dump;
+use crate::error::FbInkError;
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{Rgba, RgbaImage};
+
+/// The environment variable that makes [`assert_dump_matches`] bless new golden images
+pub const BLESS_ENV_VAR: &str = "FBINK_BLESS";
+
+/// How much a dump may differ from its golden image and still be considered a match.
+/// The default only accepts identical images. Alpha is always ignored.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tolerance {
+    /// The maximum difference allowed in any colour channel before a pixel counts as mismatched
+    pub channel: u8,
+    /// The fraction of pixels (0.0 to 1.0) that may be mismatched
+    pub max_mismatched: f64,
+    /// If set, the mean structural similarity (SSIM) of the two images' luma must be at least
+    /// this value (up to 1.0 for identical images). Catches changes a human would notice while
+    /// tolerating small shifts in antialiasing or dithering.
+    pub min_ssim: Option<f64>,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self::exact()
+    }
+}
+
+impl Tolerance {
+    /// Only accept identical images
+    pub fn exact() -> Self {
+        Self {
+            channel: 0,
+            max_mismatched: 0.0,
+            min_ssim: None,
+        }
+    }
+    /// Accept pixels whose channels differ by at most `channel`
+    pub fn per_pixel(channel: u8) -> Self {
+        Self {
+            channel,
+            ..Self::exact()
+        }
+    }
+    /// Ignore per-pixel differences and only require a minimum SSIM
+    pub fn perceptual(min_ssim: f64) -> Self {
+        Self {
+            channel: u8::MAX,
+            max_mismatched: 1.0,
+            min_ssim: Some(min_ssim),
+        }
+    }
+}
+
+/// The result of comparing a dump against a golden image. If their dimensions differ, every
+/// pixel counts as mismatched and the comparison never matches.
+#[derive(Debug, Clone)]
+pub struct Comparison {
+    pub width: u32,
+    pub height: u32,
+    /// The dimensions of the golden image
+    pub golden_dimensions: (u32, u32),
+    /// Number of pixels with a channel difference above the tolerance
+    pub mismatched: u64,
+    /// The largest difference found in any channel
+    pub max_channel_diff: u8,
+    /// Mean SSIM of the two images' luma. Only computed if the tolerance requires it
+    pub ssim: Option<f64>,
+    /// The mismatched pixels highlighted in red over a faded copy of the golden image
+    pub diff_image: RgbaImage,
+    tolerance: Tolerance,
+}
+
+impl Comparison {
+    pub fn mismatched_fraction(&self) -> f64 {
+        let total = u64::from(self.width) * u64::from(self.height);
+        if total == 0 {
+            return 0.0;
+        }
+        self.mismatched as f64 / total as f64
+    }
+    /// Whether the comparison is within the tolerance it was made with
+    pub fn is_match(&self) -> bool {
+        let ssim_ok = match (self.tolerance.min_ssim, self.ssim) {
+            (Some(min), Some(ssim)) => ssim >= min,
+            _ => true,
+        };
+        (self.width, self.height) == self.golden_dimensions
+            && self.mismatched_fraction() <= self.tolerance.max_mismatched
+            && ssim_ok
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if (self.width, self.height) != self.golden_dimensions {
+            let (width, height) = self.golden_dimensions;
+            return write!(
+                f,
+                "actual image is {}x{} but golden image is {width}x{height}",
+                self.width, self.height
+            );
+        }
+        write!(
+            f,
+            "{} of {}x{} pixels mismatched ({:.3}%, allowed {:.3}%), max channel difference {}",
+            self.mismatched,
+            self.width,
+            self.height,
+            self.mismatched_fraction() * 100.0,
+            self.tolerance.max_mismatched * 100.0,
+            self.max_channel_diff,
+        )?;
+        if let (Some(ssim), Some(min)) = (self.ssim, self.tolerance.min_ssim) {
+            write!(f, ", SSIM {ssim:.4} (minimum {min:.4})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare an image against a golden image. Images with different dimensions never match
+pub fn compare_images(actual: &RgbaImage, golden: &RgbaImage, tolerance: Tolerance) -> Comparison {
+    let (width, height) = actual.dimensions();
+    if golden.dimensions() != (width, height) {
+        return Comparison {
+            width,
+            height,
+            golden_dimensions: golden.dimensions(),
+            mismatched: u64::from(width) * u64::from(height),
+            max_channel_diff: u8::MAX,
+            ssim: None,
+            diff_image: RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 255])),
+            tolerance,
+        };
+    }
+    let mut diff_image = RgbaImage::new(width, height);
+    let mut mismatched = 0;
+    let mut max_channel_diff = 0;
+    for ((a, g), d) in actual
+        .pixels()
+        .zip(golden.pixels())
+        .zip(diff_image.pixels_mut())
+    {
+        let diff = (0..3).map(|c| a[c].abs_diff(g[c])).max().unwrap_or(0);
+        max_channel_diff = max_channel_diff.max(diff);
+        *d = if diff > tolerance.channel {
+            mismatched += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            // Fade the matching pixels so the mismatches stand out
+            let luma = luma(g) as u8;
+            Rgba([luma / 4 + 191, luma / 4 + 191, luma / 4 + 191, 255])
+        };
+    }
+    let ssim = tolerance.min_ssim.map(|_| mean_ssim(actual, golden));
+    Comparison {
+        width,
+        height,
+        golden_dimensions: golden.dimensions(),
+        mismatched,
+        max_channel_diff,
+        ssim,
+        diff_image,
+        tolerance,
+    }
+}
+
+/// Compare a dump against a golden image without panicking. Doesn't bless or write any files.
+pub fn compare_dump<P: AsRef<Path>>(
+    dump: &dyn Dump,
+    golden: P,
+    tolerance: Tolerance,
+) -> Result<Comparison, FbInkError> {
+    let actual = dump_image(dump)?;
+    let golden = image::open(golden)?.to_rgba8();
+    if actual.dimensions() != golden.dimensions() {
+        let msg = format!(
+            "dump is {:?} but golden image is {:?}",
+            actual.dimensions(),
+            golden.dimensions()
+        );
+        return Err(FbInkError::InvalidArgument(msg));
+    }
+    Ok(compare_images(&actual, &golden, tolerance))
+}
+
+/// Assert that a dump matches a golden PNG within the given tolerance. Panics on mismatch,
+/// writing the actual and diff images next to the golden image. If `FBINK_BLESS` is set, the
+/// golden image is (re)written from the dump instead.
+#[track_caller]
+pub fn assert_dump_matches<P: AsRef<Path>>(dump: &dyn Dump, golden: P, tolerance: Tolerance) {
+    let golden = golden.as_ref();
+    let actual = match dump_image(dump) {
+        Ok(image) => image,
+        Err(e) => panic!("Failed to convert dump to an image: {e}"),
+    };
+    if env::var_os(BLESS_ENV_VAR).is_some() {
+        if let Some(parent) = golden.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                panic!("Failed to create {}: {e}", parent.display());
+            }
+        }
+        if let Err(e) = actual.save(golden) {
+            panic!("Failed to bless {}: {e}", golden.display());
+        }
+        return;
+    }
+    if !golden.exists() {
+        panic!(
+            "Golden image {} doesn't exist. Run with {BLESS_ENV_VAR}=1 to create it",
+            golden.display()
+        );
+    }
+    let expected = match image::open(golden) {
+        Ok(image) => image.to_rgba8(),
+        Err(e) => panic!("Failed to open golden image {}: {e}", golden.display()),
+    };
+    if actual.dimensions() != expected.dimensions() {
+        let actual_path = sibling_path(golden, "actual");
+        let _ = actual.save(&actual_path);
+        panic!(
+            "Dump is {:?} but golden image {} is {:?}. Actual image written to {}",
+            actual.dimensions(),
+            golden.display(),
+            expected.dimensions(),
+            actual_path.display()
+        );
+    }
+    let comparison = compare_images(&actual, &expected, tolerance);
+    if !comparison.is_match() {
+        let actual_path = sibling_path(golden, "actual");
+        let diff_path = sibling_path(golden, "diff");
+        let _ = actual.save(&actual_path);
+        let _ = comparison.diff_image.save(&diff_path);
+        panic!(
+            "Dump doesn't match golden image {}: {comparison}. See {} and {}",
+            golden.display(),
+            actual_path.display(),
+            diff_path.display()
+        );
+    }
+}
+
+/// The dump (or its clip, if cropped) decoded with its pixel format, so 4 bpp, RGB565 and BGR
+/// data compare the same as they'd look on screen
+fn dump_image(dump: &dyn Dump) -> Result<RgbaImage, FbInkError> {
+    Ok(dump.dynamic_image()?.to_rgba8())
+}
+
+/// e.g. `golden/home.png` -> `golden/home.diff.png`
+fn sibling_path(golden: &Path, suffix: &str) -> PathBuf {
+    let stem = golden.file_stem().unwrap_or_default().to_string_lossy();
+    golden.with_file_name(format!("{stem}.{suffix}.png"))
+}
+
+fn luma(p: &Rgba<u8>) -> f64 {
+    0.299 * f64::from(p[0]) + 0.587 * f64::from(p[1]) + 0.114 * f64::from(p[2])
+}
+
+/// Mean SSIM over non-overlapping 8x8 windows of the images' luma
+fn mean_ssim(a: &RgbaImage, b: &RgbaImage) -> f64 {
+    const WINDOW: u32 = 8;
+    // Standard constants for 8-bit images
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (width, height) = a.dimensions();
+    let mut total = 0.0;
+    let mut windows = 0;
+    for wy in (0..height).step_by(WINDOW as usize) {
+        for wx in (0..width).step_by(WINDOW as usize) {
+            let (w, h) = (WINDOW.min(width - wx), WINDOW.min(height - wy));
+            let n = f64::from(w * h);
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in wy..wy + h {
+                for x in wx..wx + w {
+                    let la = luma(a.get_pixel(x, y));
+                    let lb = luma(b.get_pixel(x, y));
+                    sum_a += la;
+                    sum_b += lb;
+                    sum_aa += la * la;
+                    sum_bb += lb * lb;
+                    sum_ab += la * lb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / n, sum_b / n);
+            let var_a = sum_aa / n - mean_a * mean_a;
+            let var_b = sum_bb / n - mean_b * mean_b;
+            let covar = sum_ab / n - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covar + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    if windows == 0 {
+        return 1.0;
+    }
+    total / f64::from(windows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coords::Rect;
+    use crate::dump::OwnedDump;
+    use crate::state::PixelFormat;
+
+    use tempfile::TempDir;
+
+    fn golden(dir: &TempDir, name: &str, image: &RgbaImage) -> PathBuf {
+        let path = dir.path().join(format!("{name}.png"));
+        image.save(&path).unwrap();
+        path
+    }
+
+    fn dump(data: Vec<u8>, width: u16, height: u16, bpp: u8, format: PixelFormat) -> OwnedDump {
+        let stride = data.len() / usize::from(height);
+        let area = Rect::new(0, 0, width, height);
+        let dump = OwnedDump::new(data, stride, area, 0, bpp).unwrap();
+        dump.with_pixel_format(format)
+    }
+
+    #[test]
+    fn compare_4bpp() {
+        let dump = dump(vec![0xf0, 0x0f], 2, 2, 4, PixelFormat::Y4);
+        let white = Rgba([255, 255, 255, 255]);
+        let black = Rgba([0, 0, 0, 255]);
+        let expected = RgbaImage::from_fn(2, 2, |x, y| if x == y { white } else { black });
+        let dir = TempDir::new().unwrap();
+        let path = golden(&dir, "4bpp", &expected);
+        let comparison = compare_dump(&dump, &path, Tolerance::exact()).unwrap();
+        assert!(comparison.is_match(), "{comparison}");
+        assert_dump_matches(&dump, &path, Tolerance::exact());
+    }
+
+    #[test]
+    fn compare_bgr565() {
+        // Blue in BGR565, which would be red if decoded as RGB
+        let dump = dump(
+            0xf800u16.to_le_bytes().to_vec(),
+            1,
+            1,
+            16,
+            PixelFormat::Bgr565,
+        );
+        let dir = TempDir::new().unwrap();
+        let blue = golden(
+            &dir,
+            "bgr565",
+            &RgbaImage::from_pixel(1, 1, Rgba([0, 0, 255, 255])),
+        );
+        assert!(compare_dump(&dump, &blue, Tolerance::exact())
+            .unwrap()
+            .is_match());
+        let red = golden(
+            &dir,
+            "rgb565",
+            &RgbaImage::from_pixel(1, 1, Rgba([255, 0, 0, 255])),
+        );
+        let comparison = compare_dump(&dump, &red, Tolerance::exact()).unwrap();
+        assert_eq!(comparison.mismatched, 1);
+        assert_eq!(comparison.max_channel_diff, 255);
+    }
+
+    #[test]
+    fn mismatched_dimensions() {
+        let white = Rgba([255, 255, 255, 255]);
+        let actual = RgbaImage::from_pixel(4, 2, white);
+        let tall = RgbaImage::from_pixel(2, 4, white);
+        // Even a tolerance that ignores every pixel doesn't accept a different size
+        let comparison = compare_images(&actual, &tall, Tolerance::perceptual(0.0));
+        assert!(!comparison.is_match());
+        assert_eq!(comparison.golden_dimensions, (2, 4));
+        assert_eq!(comparison.mismatched, 8);
+        assert_eq!(comparison.diff_image.dimensions(), (4, 2));
+        let smaller = RgbaImage::from_pixel(4, 1, white);
+        assert!(!compare_images(&actual, &smaller, Tolerance::default()).is_match());
+
+        let dir = TempDir::new().unwrap();
+        let path = golden(&dir, "tall", &tall);
+        let dump = dump(vec![0xff; 8], 4, 2, 8, PixelFormat::Y8);
+        assert!(compare_dump(&dump, &path, Tolerance::exact()).is_err());
+    }
+}
@@ -0,0 +1,186 @@
+//! Measuring text without printing it, e.g. to clear the area it will cover beforehand
+use crate::config::FbInkConfig;
+use crate::coords::{Rect, Rotated, View};
+use crate::error::FbInkError;
+use crate::state::FbInkState;
+
+use fbink_sys as raw;
+
+/// How printed text would be laid out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TextMetrics {
+    /// The number of lines the text wraps to, including any that don't fit on the screen
+    pub lines: u16,
+    /// The number of lines that would actually be printed
+    pub rendered_lines: u16,
+    /// The area the printed lines would cover
+    pub rect: Rect<Rotated>,
+    /// Whether some of the text wouldn't fit on the screen
+    pub truncated: bool,
+}
+
+impl From<raw::FBInkOTFit> for TextMetrics {
+    fn from(fit: raw::FBInkOTFit) -> Self {
+        Self {
+            lines: fit.computed_lines,
+            rendered_lines: fit.rendered_lines,
+            rect: Rect::from_raw(fit.bbox),
+            truncated: fit.truncated,
+        }
+    }
+}
+
+/// Split text into the lines FBInk prints it as, starting a new line at each `'\n'` and
+/// wherever a line reaches the edge of the screen. A trailing newline doesn't add a line.
+pub(crate) fn wrap(msg: &str, available: usize) -> Vec<Vec<char>> {
+    let msg = msg.strip_suffix('\n').unwrap_or(msg);
+    let mut lines = Vec::new();
+    for segment in msg.split('\n') {
+        let chars: Vec<char> = segment.chars().collect();
+        match chars.is_empty() {
+            true => lines.push(chars),
+            false => lines.extend(chars.chunks(available.max(1)).map(<[char]>::to_vec)),
+        }
+    }
+    lines
+}
+
+/// Lay out text in a fixed-cell font the way FBInk's print does, using the cell size and
+/// grid from the state. The state must be up to date with the config's font and fontmult, so
+/// reinit after changing them.
+pub fn measure(
+    config: &FbInkConfig,
+    state: &FbInkState,
+    msg: &str,
+) -> Result<TextMetrics, FbInkError> {
+    if msg.is_empty() {
+        return Err(FbInkError::InvalidArgument("empty string".into()));
+    }
+    let (max_cols, max_rows) = (u32::from(state.max_cols), u32::from(state.max_rows));
+    let (cell_w, cell_h) = (i64::from(state.font_w), i64::from(state.font_h));
+    // Negative rows and columns count back from the end
+    let from_end = |pos: i16, max: u32| {
+        let pos = if pos < 0 {
+            i64::from(max) + i64::from(pos)
+        } else {
+            i64::from(pos)
+        };
+        pos.clamp(0, i64::from(max.saturating_sub(1))) as u32
+    };
+    let col = from_end(config.col, max_cols);
+    let available = max_cols.saturating_sub(col);
+    if available == 0 {
+        return Err(FbInkError::OutOfRange("no room to print".into()));
+    }
+    let wrapped = wrap(msg, available as usize);
+    let lines = wrapped.len() as u32;
+    let mut row = from_end(config.row, max_rows);
+    if config.is_halfway {
+        row += max_rows.saturating_sub(lines) / 2;
+    }
+    let rendered = lines.min(max_rows.saturating_sub(row));
+
+    let (mut left, mut top) = (i64::MAX, i64::MAX);
+    let (mut right, mut bottom) = (i64::MIN, i64::MIN);
+    for (i, line) in (0..rendered).zip(&wrapped) {
+        let len = match config.is_padded || config.is_rpadded {
+            true => available,
+            false => line.len() as u32,
+        };
+        let cols = match config.is_centered {
+            true => (max_cols - len) / 2,
+            false => col,
+        };
+        let x = i64::from(cols) * cell_w + i64::from(config.hoffset);
+        let y = i64::from(row + i) * cell_h + i64::from(config.voffset);
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x + i64::from(len) * cell_w);
+        bottom = bottom.max(y + cell_h);
+    }
+    let rect = match rendered {
+        0 => Rect::default(),
+        _ => {
+            // Whatever is pushed off the viewport by the offsets isn't printed
+            let clamp =
+                |value: i64, max: u32| value.clamp(0, i64::from(max).min(u16::MAX.into())) as u16;
+            let (left, right) = (
+                clamp(left, state.view_width),
+                clamp(right, state.view_width),
+            );
+            let (top, bottom) = (
+                clamp(top, state.view_height),
+                clamp(bottom, state.view_height),
+            );
+            Rect::<View>::new(left, top, right - left, bottom - top).to(state)
+        }
+    };
+    Ok(TextMetrics {
+        lines: lines.min(u16::MAX.into()) as u16,
+        rendered_lines: rendered as u16,
+        rect,
+        truncated: rendered < lines,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::Display;
+    use crate::virtual_fbink::{VirtualDevice, VirtualFbInk};
+
+    /// Measure the text and check it against what VirtualFbInk prints
+    fn measure_printed(config: FbInkConfig, msg: &str) -> TextMetrics {
+        let device = VirtualDevice {
+            width: 160,
+            height: 80,
+            dpi: 100,
+            ..Default::default()
+        };
+        let fbink = VirtualFbInk::new(device, config).unwrap();
+        let metrics = measure(&config, &fbink.state(), msg).unwrap();
+        let rows = fbink.print(msg).unwrap();
+        assert_eq!(i32::from(metrics.rendered_lines), rows, "{msg:?}");
+        assert_eq!(metrics.rect, fbink.get_last_rect(), "{msg:?}");
+        metrics
+    }
+
+    #[test]
+    fn wrap_on_newlines() {
+        let lines = |msg, available| -> Vec<String> {
+            let lines = wrap(msg, available).into_iter();
+            lines.map(|line| line.into_iter().collect()).collect()
+        };
+        assert_eq!(lines("abcdef", 4), ["abcd", "ef"]);
+        assert_eq!(lines("ab\ncdefg", 4), ["ab", "cdef", "g"]);
+        assert_eq!(lines("ab\n\ncd\n", 4), ["ab", "", "cd"]);
+        assert_eq!(lines("\n", 4), [""]);
+    }
+
+    #[test]
+    fn measure_newlines() {
+        // 10 columns and 5 rows of 16px cells
+        let config = FbInkConfig {
+            fontmult: 2,
+            ..Default::default()
+        };
+        let metrics = measure_printed(config, "one\ntwo");
+        assert_eq!(metrics.lines, 2);
+        assert_eq!(metrics.rect, Rect::new(0, 0, 48, 32));
+        let metrics = measure_printed(config, "a\nlonger than a line");
+        assert_eq!(metrics.lines, 3);
+        assert_eq!(metrics.rect, Rect::new(0, 0, 160, 48));
+        let centered = FbInkConfig {
+            is_centered: true,
+            row: 1,
+            ..config
+        };
+        let metrics = measure_printed(centered, "ab\nabcd\n");
+        assert_eq!(metrics.lines, 2);
+        assert_eq!(metrics.rect, Rect::new(48, 16, 64, 32));
+        let metrics = measure_printed(config, "1\n2\n3\n4\n5\n6");
+        assert_eq!((metrics.lines, metrics.rendered_lines), (6, 5));
+        assert!(metrics.truncated);
+    }
+}
@@ -2,13 +2,21 @@
 //! See the comments in `FBInk/fbink.h` for more usage instructions.
 //! Comments are also auto-generated in [`fbink_sys`] but with broken formatting.
 use crate::config::FbInkConfig;
+#[cfg(feature = "opentype")]
+use crate::config::{FbInkOtConfig, FontStyle};
+#[cfg(feature = "image")]
 use crate::dump::FbInkDump;
 use crate::error::FbInkError;
 use crate::state::{FbInkState, SunxiForceRotation};
 
+#[cfg(any(feature = "bitmap", feature = "opentype"))]
 use std::ffi::CString;
 use std::mem::MaybeUninit;
 use std::os::raw::c_int;
+#[cfg(feature = "opentype")]
+use std::os::unix::ffi::OsStrExt;
+#[cfg(feature = "opentype")]
+use std::path::Path;
 
 use fbink_sys as raw;
 pub use fbink_sys::FBInkRect as FbInkRect;
@@ -69,6 +77,7 @@ pub fn fbink_reinit(fbfd: c_int, config: &FbInkConfig) -> ReinitResult {
 pub type ReinitResult = Result<Option<FlagSet<ReinitChanges>>, FbInkError>;
 
 flags! {
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub enum ReinitChanges: u32 {
         BppChanged = raw::OK_BPP_CHANGE,
         RotationChanged = raw::OK_ROTA_CHANGE,
@@ -77,6 +86,7 @@ flags! {
     }
 }
 
+#[cfg(feature = "bitmap")]
 /// Print text with the current configuration. Returns number of rows printed on success
 pub fn fbink_print(fbfd: c_int, config: &FbInkConfig, msg: &str) -> Result<i32, FbInkError> {
     let c_string = CString::new(msg)?;
@@ -150,6 +160,7 @@ pub fn fbink_grid_refresh(
     }
 }
 
+#[cfg(feature = "draw")]
 /// Clear a region of the screen
 pub fn fbink_cls(
     fbfd: c_int,
@@ -169,6 +180,7 @@ pub fn fbink_cls(
     }
 }
 
+#[cfg(feature = "draw")]
 /// Clear the screen using grid coordinates with the same positioning trickery as fbink_print
 pub fn fbink_grid_clear(
     fbfd: c_int,
@@ -188,18 +200,20 @@ pub fn fbink_grid_clear(
     }
 }
 
+#[cfg(feature = "image")]
 /// Dump the contents of the framebuffer
 pub fn fbink_dump(fbfd: c_int) -> Result<FbInkDump, FbInkError> {
     let mut dump = MaybeUninit::<raw::FBInkDump>::zeroed();
     let rv = unsafe { raw::fbink_dump(fbfd, dump.as_mut_ptr()) };
     match -rv {
-        libc::EXIT_SUCCESS => Ok(FbInkDump::new(unsafe { dump.assume_init() })),
+        libc::EXIT_SUCCESS => FbInkDump::new(unsafe { dump.assume_init() }),
         libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("dump".into())),
         libc::ENOSYS => Err(FbInkError::NoImageSupport),
         x => Err(FbInkError::Other(x)),
     }
 }
 
+#[cfg(feature = "image")]
 /// Dump the contents of a specific region of the framebuffer
 pub fn fbink_region_dump(
     fbfd: c_int,
@@ -222,7 +236,7 @@ pub fn fbink_region_dump(
         )
     };
     match -rv {
-        libc::EXIT_SUCCESS => Ok(FbInkDump::new(unsafe { dump.assume_init() })),
+        libc::EXIT_SUCCESS => FbInkDump::new(unsafe { dump.assume_init() }),
         libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("region_dump".into())),
         libc::EINVAL => Err(FbInkError::InvalidArgument("empty region".into())),
         libc::ENOSYS => Err(FbInkError::NoImageSupport),
@@ -230,12 +244,13 @@ pub fn fbink_region_dump(
     }
 }
 
+#[cfg(feature = "image")]
 /// Like region_dump but takes a FbInkRect and doesn't apply any rotation/positioning tricks
 pub fn fbink_rect_dump(fbfd: c_int, rect: FbInkRect) -> Result<FbInkDump, FbInkError> {
     let mut dump = MaybeUninit::<raw::FBInkDump>::zeroed();
     let rv = unsafe { raw::fbink_rect_dump(fbfd, &rect, dump.as_mut_ptr()) };
     match -rv {
-        libc::EXIT_SUCCESS => Ok(FbInkDump::new(unsafe { dump.assume_init() })),
+        libc::EXIT_SUCCESS => FbInkDump::new(unsafe { dump.assume_init() }),
         libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("rect_dump".into())),
         libc::ENOSYS => Err(FbInkError::NoImageSupport),
         libc::EINVAL => Err(FbInkError::InvalidArgument("region out of bounds".into())),
@@ -248,13 +263,24 @@ pub fn fbink_get_last_rect(rotated: bool) -> FbInkRect {
     unsafe { raw::fbink_get_last_rect(rotated) }
 }
 
+#[cfg(feature = "image")]
 /// Restore the contents of a dump back to the framebuffer
 pub fn fbink_restore(
     fbfd: c_int,
     config: &FbInkConfig,
     dump: &FbInkDump,
 ) -> Result<(), FbInkError> {
-    let rv = unsafe { raw::fbink_restore(fbfd, &(*config).into(), dump.as_raw()) };
+    fbink_restore_raw(fbfd, config, dump.as_raw())
+}
+
+#[cfg(feature = "image")]
+/// Like fbink_restore but takes a raw FBInkDump, e.g. one borrowing data owned by Rust
+pub fn fbink_restore_raw(
+    fbfd: c_int,
+    config: &FbInkConfig,
+    dump: &raw::FBInkDump,
+) -> Result<(), FbInkError> {
+    let rv = unsafe { raw::fbink_restore(fbfd, &(*config).into(), dump) };
     match -rv {
         libc::EXIT_SUCCESS => Ok(()),
         libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("restore".into())),
@@ -282,6 +308,7 @@ pub fn fbink_restore(
 //     }
 // }
 
+#[cfg(feature = "image")]
 /// Print raw scanlines on the screen (packed pixels).
 pub fn fbink_print_raw_data(
     fbfd: c_int,
@@ -311,11 +338,64 @@ pub fn fbink_print_raw_data(
         x => Err(FbInkError::Other(x)),
     }
 }
-// pub fn fbink_add_ot_font() {}
+#[cfg(feature = "opentype")]
+/// Load an OpenType font for the given style. Fonts stay loaded until fbink_free_ot_fonts
+pub fn fbink_add_ot_font(path: impl AsRef<Path>, style: FontStyle) -> Result<(), FbInkError> {
+    let c_string = CString::new(path.as_ref().as_os_str().as_bytes())?;
+    let rv = unsafe { raw::fbink_add_ot_font(c_string.as_ptr(), style.into()) };
+    match -rv {
+        libc::EXIT_SUCCESS => Ok(()),
+        libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("add_ot_font".into())),
+        libc::ENOSYS => Err(FbInkError::NotSupported("OpenType fonts".into())),
+        _ => Err(FbInkError::Other(rv)),
+    }
+}
 // pub fn fbink_add_ot_font_v2() {}
-// pub fn fbink_free_ot_fonts() {}
+#[cfg(feature = "opentype")]
+/// Free all the OpenType fonts added with fbink_add_ot_font
+pub fn fbink_free_ot_fonts() -> Result<(), FbInkError> {
+    let rv = unsafe { raw::fbink_free_ot_fonts() };
+    match -rv {
+        libc::EXIT_SUCCESS => Ok(()),
+        libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("free_ot_fonts".into())),
+        libc::ENOSYS => Err(FbInkError::NotSupported("OpenType fonts".into())),
+        _ => Err(FbInkError::Other(rv)),
+    }
+}
 // pub fn fbink_free_ot_fonts_v2() {}
-// pub fn fbink_print_ot() {}
+#[cfg(feature = "opentype")]
+/// Print text with the loaded OpenType fonts. Returns the top margin for a following print and
+/// how the text fit. With `compute_only` set, nothing is drawn.
+pub fn fbink_print_ot(
+    fbfd: c_int,
+    msg: &str,
+    ot_config: &FbInkOtConfig,
+    config: &FbInkConfig,
+) -> Result<(i32, raw::FBInkOTFit), FbInkError> {
+    let c_string = CString::new(msg)?;
+    let mut fit = raw::FBInkOTFit::default();
+    let rv = unsafe {
+        raw::fbink_print_ot(
+            fbfd,
+            c_string.as_ptr(),
+            &(*ot_config).into(),
+            &(*config).into(),
+            &mut fit,
+        )
+    };
+    if rv >= 0 {
+        return Ok((rv, fit));
+    }
+    match -rv {
+        libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("print_ot".into())),
+        libc::EINVAL => Err(FbInkError::InvalidArgument(
+            "empty string or no fonts loaded".into(),
+        )),
+        libc::ENOSPC => Err(FbInkError::OutOfRange("no room to print".into())),
+        libc::ENOSYS => Err(FbInkError::NotSupported("OpenType fonts".into())),
+        _ => Err(FbInkError::Other(rv)),
+    }
+}
 //
 // pub fn fbink_printf() {}
 //
@@ -342,7 +422,10 @@ pub fn fbink_wait_for_any_complete(fbfd: c_int) -> Result<(), FbInkError> {
         x => Err(FbInkError::Other(x)),
     }
 }
-// pub fn fbink_get_last_marker() {}
+/// Return the marker of the last refresh FBInk requested
+pub fn fbink_get_last_marker() -> u32 {
+    unsafe { raw::fbink_get_last_marker() }
+}
 //
 // pub fn fbink_update_verbosity() {}
 // pub fn fbink_update_pen_colors() {}
@@ -351,8 +434,43 @@ pub fn fbink_wait_for_any_complete(fbfd: c_int) -> Result<(), FbInkError> {
 // pub fn fbink_set_fg_pen_rgba() {}
 // pub fn fbink_set_bg_pen_rgba() {}
 //
-// pub fn fbink_print_progress_bar() {}
-// pub fn fbink_print_activity_bar() {}
+#[cfg(feature = "draw")]
+/// Print a full-width progress bar filled up to `percentage`, positioned like fbink_print
+pub fn fbink_print_progress_bar(
+    fbfd: c_int,
+    percentage: u8,
+    config: &FbInkConfig,
+) -> Result<(), FbInkError> {
+    let rv = unsafe { raw::fbink_print_progress_bar(fbfd, percentage, &(*config).into()) };
+    match -rv {
+        libc::EXIT_SUCCESS => Ok(()),
+        libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("print_progress_bar".into())),
+        libc::ENOSYS => {
+            let msg = "FBInk was built without drawing support".into();
+            Err(FbInkError::NotSupported(msg))
+        }
+        x => Err(FbInkError::Other(x)),
+    }
+}
+#[cfg(feature = "draw")]
+/// Print a step of an activity bar (an indeterminate progress bar), positioned like
+/// fbink_print. `progress` is the step of the animation, from 0 to 16.
+pub fn fbink_print_activity_bar(
+    fbfd: c_int,
+    progress: u8,
+    config: &FbInkConfig,
+) -> Result<(), FbInkError> {
+    let rv = unsafe { raw::fbink_print_activity_bar(fbfd, progress, &(*config).into()) };
+    match -rv {
+        libc::EXIT_SUCCESS => Ok(()),
+        libc::EXIT_FAILURE => Err(FbInkError::ExitFailure("print_activity_bar".into())),
+        libc::ENOSYS => {
+            let msg = "FBInk was built without drawing support".into();
+            Err(FbInkError::NotSupported(msg))
+        }
+        x => Err(FbInkError::Other(x)),
+    }
+}
 //
 pub fn fbink_free_dump_data(data: &mut raw::FBInkDump) -> Result<(), FbInkError> {
     let rv = unsafe { raw::fbink_free_dump_data(data) };
@@ -0,0 +1,890 @@
+//! An in-memory framebuffer that emulates the basics of FBInk entirely in Rust, for testing
+//! apps on a desktop or in CI without a device
+use crate::config::{FbInkConfig, HardwareDitherMode, WaveformMode};
+use crate::coords::{Native, Rect, Rotated, View};
+use crate::display::Display;
+use crate::dump::{Dump, OwnedDump};
+use crate::error::FbInkError;
+use crate::region::Region;
+use crate::screenshot::{decode_rgb, encode_rgb};
+use crate::state::{DeviceId, NtxRotationQuirk, PixelFormat, SunxiForceRotation};
+use crate::text;
+use crate::thin::ReinitResult;
+use crate::{FbInkRect, FbInkState};
+
+use std::sync::{Mutex, MutexGuard};
+
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+
+/// The device a [`VirtualFbInk`] simulates
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VirtualDevice {
+    /// Width of the framebuffer in its current rotation
+    pub width: u32,
+    /// Height of the framebuffer in its current rotation
+    pub height: u32,
+    pub bpp: u8,
+    pub pixel_format: PixelFormat,
+    pub device_id: DeviceId,
+    /// The native rotation reported in the state and stored in dumps
+    pub rota: u8,
+    pub dpi: u16,
+    pub has_color_panel: bool,
+}
+
+impl Default for VirtualDevice {
+    /// A Kobo Libra 2 in portrait
+    fn default() -> Self {
+        Self {
+            width: 1264,
+            height: 1680,
+            bpp: 8,
+            pixel_format: PixelFormat::Y8,
+            device_id: DeviceId::KoboLibra2,
+            rota: 0,
+            dpi: 300,
+            has_color_panel: false,
+        }
+    }
+}
+
+/// A refresh requested from a [`VirtualFbInk`], with the settings it was requested with
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RefreshRequest {
+    pub rect: Rect<Rotated>,
+    pub wfm_mode: WaveformMode,
+    pub dithering_mode: HardwareDitherMode,
+    pub is_flashing: bool,
+    pub is_nightmode: bool,
+    pub marker: u32,
+}
+
+#[derive(Debug)]
+struct Screen {
+    data: Vec<u8>,
+    last_rect: FbInkRect,
+    marker: u32,
+    refreshes: Vec<RefreshRequest>,
+}
+
+/// A framebuffer held in memory that implements [`Display`] like [`FbInk`](crate::FbInk).
+/// Text is printed with a built-in 8x8 bitmap font regardless of the configured font, and
+/// refreshes are only logged. Like FBInk, most behaviour is controlled by the config.
+#[derive(Debug)]
+pub struct VirtualFbInk {
+    pub config: FbInkConfig,
+    device: VirtualDevice,
+    stride: usize,
+    screen: Mutex<Screen>,
+}
+
+impl VirtualFbInk {
+    /// Create a virtual framebuffer cleared to white
+    pub fn new(device: VirtualDevice, config: FbInkConfig) -> Result<Self, FbInkError> {
+        if !matches!(device.bpp, 4 | 8 | 16 | 24 | 32) {
+            let msg = format!("{} bpp framebuffers", device.bpp);
+            return Err(FbInkError::NotSupported(msg));
+        }
+        if device.width == 0 || device.height == 0 {
+            let msg = format!("{}x{} framebuffer", device.width, device.height);
+            return Err(FbInkError::InvalidArgument(msg));
+        }
+        if device.width > u16::MAX.into() || device.height > u16::MAX.into() {
+            let msg = format!("{}x{} framebuffer", device.width, device.height);
+            return Err(FbInkError::OutOfRange(msg));
+        }
+        let stride = (device.width as usize * usize::from(device.bpp)).div_ceil(8);
+        let fbink = Self {
+            config,
+            stride,
+            screen: Mutex::new(Screen {
+                data: vec![0; stride * device.height as usize],
+                last_rect: FbInkRect::default(),
+                marker: 0,
+                refreshes: Vec::new(),
+            }),
+            device,
+        };
+        let full = fbink.full_rect();
+        fbink.fill(&mut fbink.screen(), full, [u8::MAX; 3]);
+        Ok(fbink)
+    }
+
+    pub fn device(&self) -> &VirtualDevice {
+        &self.device
+    }
+
+    /// Every refresh requested so far, oldest first
+    pub fn refreshes(&self) -> Vec<RefreshRequest> {
+        self.screen().refreshes.clone()
+    }
+
+    /// Return the refreshes requested so far and clear the log
+    pub fn take_refreshes(&self) -> Vec<RefreshRequest> {
+        std::mem::take(&mut self.screen().refreshes)
+    }
+
+    /// The current contents of the framebuffer as an image, decoded according to the pixel
+    /// format. Grayscale formats give a grayscale image, anything else RGB.
+    pub fn image(&self) -> DynamicImage {
+        let screen = self.screen();
+        let (width, height) = (self.device.width, self.device.height);
+        let rgb = |x, y| self.get_pixel(&screen, x, y);
+        match self.device.pixel_format {
+            PixelFormat::Y4 | PixelFormat::Y8 => {
+                GrayImage::from_fn(width, height, |x, y| Luma([rgb(x, y)[0]])).into()
+            }
+            _ => RgbImage::from_fn(width, height, |x, y| Rgb(rgb(x, y))).into(),
+        }
+    }
+
+    /// The multiplier applied to the 8x8 font. Scales with the DPI when not configured
+    pub fn fontmult(&self) -> u8 {
+        match (self.config.fontmult, self.device.dpi) {
+            (0, 0..=212) => 2,
+            (0, 213..=300) => 3,
+            (0, _) => 4,
+            (mult, _) => mult,
+        }
+    }
+
+    fn screen(&self) -> MutexGuard<'_, Screen> {
+        // The screen is only ever left half-drawn, so it's fine to keep using it after a panic
+        self.screen.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn full_rect(&self) -> FbInkRect {
+        FbInkRect {
+            left: 0,
+            top: 0,
+            width: self.device.width as u16,
+            height: self.device.height as u16,
+        }
+    }
+
+    fn pen_colors(&self, config: &FbInkConfig) -> (u8, u8) {
+        let fg = u8::from(config.fg_color) * 17;
+        let bg = u8::MAX - u8::from(config.bg_color) * 17;
+        if config.is_inverted {
+            (bg, fg)
+        } else {
+            (fg, bg)
+        }
+    }
+
+    fn get_pixel(&self, screen: &Screen, x: u32, y: u32) -> [u8; 3] {
+        let row = &screen.data[y as usize * self.stride..];
+        decode_rgb(row, x as usize, self.device.bpp, self.device.pixel_format).unwrap_or_default()
+    }
+
+    fn set_pixel(&self, screen: &mut Screen, x: u32, y: u32, rgb: [u8; 3]) {
+        if x >= self.device.width || y >= self.device.height {
+            return;
+        }
+        let row = &mut screen.data[y as usize * self.stride..];
+        let (bpp, format) = (self.device.bpp, self.device.pixel_format);
+        encode_rgb(row, x as usize, bpp, format, rgb);
+    }
+
+    fn fill(&self, screen: &mut Screen, rect: FbInkRect, rgb: [u8; 3]) {
+        for y in u32::from(rect.top)..u32::from(rect.top) + u32::from(rect.height) {
+            for x in u32::from(rect.left)..u32::from(rect.left) + u32::from(rect.width) {
+                self.set_pixel(screen, x, y, rgb);
+            }
+        }
+    }
+
+    /// Clamp a rect to the screen
+    fn clamp(&self, rect: FbInkRect) -> FbInkRect {
+        let (width, height) = (self.device.width, self.device.height);
+        let left = u32::from(rect.left).min(width);
+        let top = u32::from(rect.top).min(height);
+        FbInkRect {
+            left: left as u16,
+            top: top as u16,
+            width: u32::from(rect.width).min(width - left) as u16,
+            height: u32::from(rect.height).min(height - top) as u16,
+        }
+    }
+
+    /// Fill a rect that's already on the screen with the background pen color
+    fn clear(&self, rect: FbInkRect) {
+        let (_, bg) = self.pen_colors(&self.config);
+        let mut screen = self.screen();
+        self.fill(&mut screen, rect, [bg; 3]);
+        screen.last_rect = rect;
+        if !self.config.no_refresh {
+            self.log_refresh(&mut screen, &self.config, rect);
+        }
+    }
+
+    /// Log a refresh of the given rect, or the whole screen if it's empty
+    fn log_refresh(&self, screen: &mut Screen, config: &FbInkConfig, rect: FbInkRect) {
+        let rect = if rect.width == 0 || rect.height == 0 {
+            self.full_rect()
+        } else {
+            self.clamp(rect)
+        };
+        screen.marker += 1;
+        let marker = screen.marker;
+        screen.refreshes.push(RefreshRequest {
+            rect: Rect::from_raw(rect),
+            wfm_mode: config.wfm_mode,
+            dithering_mode: config.dithering_mode,
+            is_flashing: config.is_flashing,
+            is_nightmode: config.is_nightmode,
+            marker,
+        });
+    }
+
+    /// Print with FBInk's row/column positioning, wrapping lines at the edge of the screen
+    fn print_with(&self, config: &FbInkConfig, msg: &str) -> Result<i32, FbInkError> {
+        if msg.is_empty() {
+            return Err(FbInkError::InvalidArgument("empty string".into()));
+        }
+        let mult = u32::from(self.fontmult().max(1));
+        let cell = GLYPH_SIZE * mult;
+        let (max_cols, max_rows) = (self.device.width / cell, self.device.height / cell);
+        let from_end = |pos: i16, max: u32| {
+            let pos = if pos < 0 {
+                i64::from(max) + i64::from(pos)
+            } else {
+                i64::from(pos)
+            };
+            pos.clamp(0, i64::from(max.saturating_sub(1))) as u32
+        };
+        let col = from_end(config.col, max_cols);
+        let available = (max_cols - col) as usize;
+        if available == 0 {
+            return Err(FbInkError::OutOfRange("no room to print".into()));
+        }
+        let mut lines = text::wrap(msg, available);
+        if config.is_padded || config.is_rpadded {
+            for line in &mut lines {
+                let padding = available - line.len();
+                let left = if config.is_padded { padding / 2 } else { 0 };
+                line.splice(0..0, std::iter::repeat_n(' ', left));
+                line.resize(available, ' ');
+            }
+        }
+        let mut row = from_end(config.row, max_rows);
+        if config.is_halfway {
+            row += max_rows.saturating_sub(lines.len() as u32) / 2;
+        }
+        let lines = &lines[..lines.len().min(max_rows.saturating_sub(row) as usize)];
+
+        let (fg, bg) = self.pen_colors(config);
+        let mut screen = self.screen();
+        if config.is_cleared {
+            self.fill(&mut screen, self.full_rect(), [bg; 3]);
+        }
+        let (mut left, mut top) = (u32::MAX, u32::MAX);
+        let (mut right, mut bottom) = (0, 0);
+        for (i, line) in lines.iter().enumerate() {
+            let cols = if config.is_centered {
+                (max_cols - line.len() as u32) / 2
+            } else {
+                col
+            };
+            let x0 = i64::from(cols * cell) + i64::from(config.hoffset);
+            let y0 = i64::from((row + i as u32) * cell) + i64::from(config.voffset);
+            for (j, &c) in line.iter().enumerate() {
+                let glyph = glyph(c);
+                for gy in 0..GLYPH_SIZE * mult {
+                    let bits = glyph[(gy / mult) as usize];
+                    for gx in 0..GLYPH_SIZE * mult {
+                        let set = bits & (1 << (gx / mult)) != 0;
+                        let color = match set {
+                            true if config.is_fgless => continue,
+                            false if config.is_bgless || config.is_overlay => continue,
+                            true => fg,
+                            false => bg,
+                        };
+                        let x = x0 + i64::from(j as u32 * cell + gx);
+                        let y = y0 + i64::from(gy);
+                        let (Ok(x), Ok(y)) = (u32::try_from(x), u32::try_from(y)) else {
+                            continue;
+                        };
+                        self.set_pixel(&mut screen, x, y, [color; 3]);
+                    }
+                }
+            }
+            let width = line.len() as i64 * i64::from(cell);
+            left = left.min(x0.max(0) as u32);
+            top = top.min(y0.max(0) as u32);
+            right = right.max((x0 + width).max(0) as u32);
+            bottom = bottom.max((y0 + i64::from(cell)).max(0) as u32);
+        }
+        let rect = if lines.is_empty() {
+            FbInkRect::default()
+        } else {
+            self.clamp(FbInkRect {
+                left: left.min(u16::MAX.into()) as u16,
+                top: top.min(u16::MAX.into()) as u16,
+                width: right.saturating_sub(left).min(u16::MAX.into()) as u16,
+                height: bottom.saturating_sub(top).min(u16::MAX.into()) as u16,
+            })
+        };
+        screen.last_rect = rect;
+        if !config.no_refresh && rect.width > 0 && rect.height > 0 {
+            let rect = if config.is_cleared {
+                self.full_rect()
+            } else {
+                rect
+            };
+            self.log_refresh(&mut screen, config, rect);
+        }
+        Ok(lines.len() as i32)
+    }
+}
+
+impl Display for VirtualFbInk {
+    fn state(&self) -> FbInkState {
+        let device = &self.device;
+        let mult = self.fontmult().max(1);
+        let font_size = GLYPH_SIZE as u16 * u16::from(mult);
+        let (fg, bg) = self.pen_colors(&self.config);
+        let name = match device.device_id {
+            DeviceId::Unknown(_) => "Virtual".to_string(),
+            id => id.as_ref().to_string(),
+        };
+        FbInkState {
+            user_hz: 100,
+            font_name: "IBM".into(),
+            view_width: device.width,
+            view_height: device.height,
+            screen_width: device.width,
+            screen_height: device.height,
+            scanline_stride: self.stride as u32,
+            bpp: device.bpp.into(),
+            inverted_grayscale: false,
+            device_name: name,
+            device_codename: "virtual".into(),
+            device_platform: "Virtual".into(),
+            device_id: device.device_id,
+            pen_fg_color: fg,
+            pen_bg_color: bg,
+            screen_dpi: device.dpi,
+            font_w: font_size,
+            font_h: font_size,
+            max_cols: (device.width / u32::from(font_size)) as u16,
+            max_rows: (device.height / u32::from(font_size)) as u16,
+            view_hori_origin: 0,
+            view_vert_origin: 0,
+            view_vert_offset: 0,
+            fontsize_mult: mult,
+            glyph_width: GLYPH_SIZE as u8,
+            glyph_height: GLYPH_SIZE as u8,
+            is_perfect_fit: device.width.is_multiple_of(u32::from(font_size)),
+            is_mtk: false,
+            is_sunxi: false,
+            sunxi_has_fbdamage: false,
+            sunxi_force_rota: SunxiForceRotation::Gyro,
+            is_kindle_legacy: false,
+            is_kobo_non_mt: false,
+            unreliable_wait_for: false,
+            can_wake_epdc: false,
+            ntx_boot_rota: device.rota,
+            ntx_rota_quirk: NtxRotationQuirk::Straight,
+            rotation_map: [0, 1, 2, 3],
+            touch_swap_axes: false,
+            touch_mirror_x: false,
+            touch_mirror_y: false,
+            is_ntx_quirky_landscape: false,
+            current_rota: device.rota,
+            can_rotate: false,
+            can_hw_invert: false,
+            has_eclipse_wfm: false,
+            has_color_panel: device.has_color_panel,
+            pixel_format: device.pixel_format,
+            can_wait_for_submission: false,
+        }
+    }
+
+    fn config(&self) -> &FbInkConfig {
+        &self.config
+    }
+
+    fn config_mut(&mut self) -> &mut FbInkConfig {
+        &mut self.config
+    }
+
+    /// The virtual device has nothing that depends on the config, so this never reports changes
+    fn reinit(&self) -> ReinitResult {
+        Ok(None)
+    }
+
+    fn print(&self, msg: &str) -> Result<i32, FbInkError> {
+        self.print_with(&self.config, msg)
+    }
+
+    fn print_coords(&self, msg: &str, x: i16, y: i16) -> Result<i32, FbInkError> {
+        let mut config = self.config;
+        config.hoffset = x;
+        config.voffset = y;
+        // ensure other options that affect positioning are disabled.
+        config.row = 0;
+        config.col = 0;
+        config.is_centered = false;
+        config.is_halfway = false;
+        config.is_padded = false;
+        config.is_rpadded = false;
+        self.print_with(&config, msg)
+    }
+
+    /// Print 8-bit grayscale, grayscale + alpha, RGB or RGBA pixels, depending on the size of
+    /// `data`. There's no viewport, so the rect is positioned on the screen as is.
+    fn print_raw_data(&self, data: &[u8], rect: Rect<View>) -> Result<(), FbInkError> {
+        let (w, h) = (u32::from(rect.width), u32::from(rect.height));
+        let pixels = w as usize * h as usize;
+        if pixels == 0
+            || !data.len().is_multiple_of(pixels)
+            || !(1..=4).contains(&(data.len() / pixels))
+        {
+            let msg = format!("{} bytes for a {w}x{h} image", data.len());
+            return Err(FbInkError::InvalidArgument(msg));
+        }
+        let components = data.len() / pixels;
+        let (x0, y0) = (u32::from(rect.left), u32::from(rect.top));
+        let mut screen = self.screen();
+        for (i, p) in data.chunks_exact(components).enumerate() {
+            let (x, y) = (x0 + i as u32 % w, y0 + i as u32 / w);
+            if x >= self.device.width || y >= self.device.height {
+                continue;
+            }
+            let (mut rgb, alpha) = match *p {
+                [v] => ([v; 3], u8::MAX),
+                [v, a] => ([v; 3], a),
+                [r, g, b] => ([r, g, b], u8::MAX),
+                [r, g, b, a] => ([r, g, b], a),
+                _ => unreachable!(),
+            };
+            if !self.config.ignore_alpha && alpha < u8::MAX {
+                let under = self.get_pixel(&screen, x, y);
+                for (c, u) in rgb.iter_mut().zip(under) {
+                    let blended = u16::from(*c) * u16::from(alpha)
+                        + u16::from(u) * (u16::from(u8::MAX) - u16::from(alpha));
+                    *c = (blended / 255) as u8;
+                }
+            }
+            if self.config.is_inverted {
+                rgb = rgb.map(|c| u8::MAX - c);
+            }
+            self.set_pixel(&mut screen, x, y, rgb);
+        }
+        let rect = self.clamp(rect.into());
+        screen.last_rect = rect;
+        if !self.config.no_refresh && rect.width > 0 && rect.height > 0 {
+            self.log_refresh(&mut screen, &self.config, rect);
+        }
+        Ok(())
+    }
+
+    fn refresh(&self, rect: Rect<Rotated>) -> Result<(), FbInkError> {
+        self.log_refresh(&mut self.screen(), &self.config, rect.into());
+        Ok(())
+    }
+
+    fn refresh_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        let mut screen = self.screen();
+        for rect in region.fbink_rects() {
+            self.log_refresh(&mut screen, &self.config, rect.into());
+        }
+        Ok(())
+    }
+
+    fn cls(&self) -> Result<(), FbInkError> {
+        self.clear(self.full_rect());
+        Ok(())
+    }
+
+    fn cls_rect(&self, region: &Region<Rotated>) -> Result<(), FbInkError> {
+        for rect in region.fbink_rects() {
+            let rect = match rect.is_empty() {
+                true => self.full_rect(),
+                false => self.clamp(rect.into()),
+            };
+            if rect.width != 0 && rect.height != 0 {
+                self.clear(rect);
+            }
+        }
+        Ok(())
+    }
+
+    fn dump(&self) -> Result<Box<dyn Dump>, FbInkError> {
+        let data = self.screen().data.clone();
+        let (rota, bpp) = (self.device.rota, self.device.bpp);
+        let area = Rect::from_raw(self.full_rect());
+        let dump = OwnedDump::new(data, self.stride, area, rota, bpp)?
+            .with_pixel_format(self.device.pixel_format);
+        Ok(Box::new(dump))
+    }
+
+    fn rect_dump(&self, region: &Region<Native>) -> Result<Box<dyn Dump>, FbInkError> {
+        let Some(rect) = region.bounds() else {
+            return Err(FbInkError::InvalidArgument("empty region".into()));
+        };
+        let clamped = self.clamp(rect.into());
+        if rect.is_empty() || (clamped.width, clamped.height) != (rect.width, rect.height) {
+            return Err(FbInkError::InvalidArgument("region out of bounds".into()));
+        }
+        let dump = self.dump()?;
+        Ok(Box::new(dump.extract(rect)?))
+    }
+
+    /// Copy the dump's data back to the framebuffer. Like FBInk, this fails if the dump was
+    /// taken at a different bpp or rotation.
+    fn restore(&self, dump: &dyn Dump) -> Result<(), FbInkError> {
+        if dump.bpp() != self.device.bpp || dump.rota() != self.device.rota {
+            let msg = format!(
+                "dump is {} bpp at rotation {} but the framebuffer is {} bpp at rotation {}",
+                dump.bpp(),
+                dump.rota(),
+                self.device.bpp,
+                self.device.rota
+            );
+            return Err(FbInkError::InvalidArgument(msg));
+        }
+        let area = dump.area();
+        let rect = if dump.is_full() { area } else { dump.clip() };
+        let rect = self.clamp(rect.into());
+        let (area_right, area_bottom) = (
+            u32::from(area.left) + u32::from(area.width),
+            u32::from(area.top) + u32::from(area.height),
+        );
+        let data = dump.data();
+        let mut screen = self.screen();
+        for y in u32::from(rect.top)..u32::from(rect.top) + u32::from(rect.height) {
+            if y < u32::from(area.top) || y >= area_bottom {
+                continue;
+            }
+            let src_row = (y - u32::from(area.top)) as usize * dump.stride();
+            for x in u32::from(rect.left)..u32::from(rect.left) + u32::from(rect.width) {
+                if x < u32::from(area.left) || x >= area_right {
+                    continue;
+                }
+                let src_x = (x - u32::from(area.left)) as usize;
+                copy_pixel(
+                    data.get(src_row..).unwrap_or_default(),
+                    src_x,
+                    &mut screen.data[y as usize * self.stride..],
+                    x as usize,
+                    self.device.bpp,
+                );
+            }
+        }
+        screen.last_rect = rect;
+        if !self.config.no_refresh {
+            self.log_refresh(&mut screen, &self.config, rect);
+        }
+        Ok(())
+    }
+
+    /// There are no rotation tricks, so the native and rotated spaces are the same
+    fn get_last_rect(&self) -> Rect<Rotated> {
+        Rect::from_raw(self.screen().last_rect)
+    }
+
+    fn get_last_native_rect(&self) -> Rect<Native> {
+        Rect::from_raw(self.screen().last_rect)
+    }
+
+    fn get_last_marker(&self) -> u32 {
+        self.screen().marker
+    }
+
+    /// Refreshes complete instantly
+    fn wait_for_complete(&self, _marker: u32) -> Result<(), FbInkError> {
+        Ok(())
+    }
+}
+
+/// Copy a pixel between rows of packed data with the same bpp
+fn copy_pixel(src: &[u8], src_x: usize, dst: &mut [u8], dst_x: usize, bpp: u8) {
+    if bpp == 4 {
+        let Some(&byte) = src.get(src_x / 2) else {
+            return;
+        };
+        // The even pixel is in the high nibble
+        let nibble = if src_x.is_multiple_of(2) {
+            byte >> 4
+        } else {
+            byte & 0x0F
+        };
+        if let Some(out) = dst.get_mut(dst_x / 2) {
+            *out = if dst_x.is_multiple_of(2) {
+                (*out & 0x0F) | (nibble << 4)
+            } else {
+                (*out & 0xF0) | nibble
+            };
+        }
+        return;
+    }
+    let bytes = usize::from(bpp / 8);
+    if let (Some(from), Some(to)) = (
+        src.get(src_x * bytes..(src_x + 1) * bytes),
+        dst.get_mut(dst_x * bytes..(dst_x + 1) * bytes),
+    ) {
+        to.copy_from_slice(from);
+    }
+}
+
+const GLYPH_SIZE: u32 = 8;
+
+/// The glyph for a character, with one byte per row and the leftmost pixel in the lowest bit.
+/// Characters outside of printable ASCII are shown as `?`.
+fn glyph(c: char) -> &'static [u8; 8] {
+    let index = match c {
+        ' '..='~' => c as usize - ' ' as usize,
+        _ => '?' as usize - ' ' as usize,
+    };
+    &FONT_8X8[index]
+}
+
+/// Printable ASCII from the public domain font8x8 by Daniel Hepper, based on the IBM PC BIOS font
+#[rustfmt::skip]
+const FONT_8X8: [[u8; 8]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+    [0x18, 0x3C, 0x3C, 0x18, 0x18, 0x00, 0x18, 0x00], // '!'
+    [0x36, 0x36, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '"'
+    [0x36, 0x36, 0x7F, 0x36, 0x7F, 0x36, 0x36, 0x00], // '#'
+    [0x0C, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x0C, 0x00], // '$'
+    [0x00, 0x63, 0x33, 0x18, 0x0C, 0x66, 0x63, 0x00], // '%'
+    [0x1C, 0x36, 0x1C, 0x6E, 0x3B, 0x33, 0x6E, 0x00], // '&'
+    [0x06, 0x06, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00], // '''
+    [0x18, 0x0C, 0x06, 0x06, 0x06, 0x0C, 0x18, 0x00], // '('
+    [0x06, 0x0C, 0x18, 0x18, 0x18, 0x0C, 0x06, 0x00], // ')'
+    [0x00, 0x66, 0x3C, 0xFF, 0x3C, 0x66, 0x00, 0x00], // '*'
+    [0x00, 0x0C, 0x0C, 0x3F, 0x0C, 0x0C, 0x00, 0x00], // '+'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ','
+    [0x00, 0x00, 0x00, 0x3F, 0x00, 0x00, 0x00, 0x00], // '-'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C, 0x00], // '.'
+    [0x60, 0x30, 0x18, 0x0C, 0x06, 0x03, 0x01, 0x00], // '/'
+    [0x3E, 0x63, 0x73, 0x7B, 0x6F, 0x67, 0x3E, 0x00], // '0'
+    [0x0C, 0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x3F, 0x00], // '1'
+    [0x1E, 0x33, 0x30, 0x1C, 0x06, 0x33, 0x3F, 0x00], // '2'
+    [0x1E, 0x33, 0x30, 0x1C, 0x30, 0x33, 0x1E, 0x00], // '3'
+    [0x38, 0x3C, 0x36, 0x33, 0x7F, 0x30, 0x78, 0x00], // '4'
+    [0x3F, 0x03, 0x1F, 0x30, 0x30, 0x33, 0x1E, 0x00], // '5'
+    [0x1C, 0x06, 0x03, 0x1F, 0x33, 0x33, 0x1E, 0x00], // '6'
+    [0x3F, 0x33, 0x30, 0x18, 0x0C, 0x0C, 0x0C, 0x00], // '7'
+    [0x1E, 0x33, 0x33, 0x1E, 0x33, 0x33, 0x1E, 0x00], // '8'
+    [0x1E, 0x33, 0x33, 0x3E, 0x30, 0x18, 0x0E, 0x00], // '9'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x00], // ':'
+    [0x00, 0x0C, 0x0C, 0x00, 0x00, 0x0C, 0x0C, 0x06], // ';'
+    [0x18, 0x0C, 0x06, 0x03, 0x06, 0x0C, 0x18, 0x00], // '<'
+    [0x00, 0x00, 0x3F, 0x00, 0x00, 0x3F, 0x00, 0x00], // '='
+    [0x06, 0x0C, 0x18, 0x30, 0x18, 0x0C, 0x06, 0x00], // '>'
+    [0x1E, 0x33, 0x30, 0x18, 0x0C, 0x00, 0x0C, 0x00], // '?'
+    [0x3E, 0x63, 0x7B, 0x7B, 0x7B, 0x03, 0x1E, 0x00], // '@'
+    [0x0C, 0x1E, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x00], // 'A'
+    [0x3F, 0x66, 0x66, 0x3E, 0x66, 0x66, 0x3F, 0x00], // 'B'
+    [0x3C, 0x66, 0x03, 0x03, 0x03, 0x66, 0x3C, 0x00], // 'C'
+    [0x1F, 0x36, 0x66, 0x66, 0x66, 0x36, 0x1F, 0x00], // 'D'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x46, 0x7F, 0x00], // 'E'
+    [0x7F, 0x46, 0x16, 0x1E, 0x16, 0x06, 0x0F, 0x00], // 'F'
+    [0x3C, 0x66, 0x03, 0x03, 0x73, 0x66, 0x7C, 0x00], // 'G'
+    [0x33, 0x33, 0x33, 0x3F, 0x33, 0x33, 0x33, 0x00], // 'H'
+    [0x1E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'I'
+    [0x78, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E, 0x00], // 'J'
+    [0x67, 0x66, 0x36, 0x1E, 0x36, 0x66, 0x67, 0x00], // 'K'
+    [0x0F, 0x06, 0x06, 0x06, 0x46, 0x66, 0x7F, 0x00], // 'L'
+    [0x63, 0x77, 0x7F, 0x7F, 0x6B, 0x63, 0x63, 0x00], // 'M'
+    [0x63, 0x67, 0x6F, 0x7B, 0x73, 0x63, 0x63, 0x00], // 'N'
+    [0x1C, 0x36, 0x63, 0x63, 0x63, 0x36, 0x1C, 0x00], // 'O'
+    [0x3F, 0x66, 0x66, 0x3E, 0x06, 0x06, 0x0F, 0x00], // 'P'
+    [0x1E, 0x33, 0x33, 0x33, 0x3B, 0x1E, 0x38, 0x00], // 'Q'
+    [0x3F, 0x66, 0x66, 0x3E, 0x36, 0x66, 0x67, 0x00], // 'R'
+    [0x1E, 0x33, 0x07, 0x0E, 0x38, 0x33, 0x1E, 0x00], // 'S'
+    [0x3F, 0x2D, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'T'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x33, 0x3F, 0x00], // 'U'
+    [0x33, 0x33, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'V'
+    [0x63, 0x63, 0x63, 0x6B, 0x7F, 0x77, 0x63, 0x00], // 'W'
+    [0x63, 0x63, 0x36, 0x1C, 0x1C, 0x36, 0x63, 0x00], // 'X'
+    [0x33, 0x33, 0x33, 0x1E, 0x0C, 0x0C, 0x1E, 0x00], // 'Y'
+    [0x7F, 0x63, 0x31, 0x18, 0x4C, 0x66, 0x7F, 0x00], // 'Z'
+    [0x1E, 0x06, 0x06, 0x06, 0x06, 0x06, 0x1E, 0x00], // '['
+    [0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x40, 0x00], // '\'
+    [0x1E, 0x18, 0x18, 0x18, 0x18, 0x18, 0x1E, 0x00], // ']'
+    [0x08, 0x1C, 0x36, 0x63, 0x00, 0x00, 0x00, 0x00], // '^'
+    [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF], // '_'
+    [0x0C, 0x0C, 0x18, 0x00, 0x00, 0x00, 0x00, 0x00], // '`'
+    [0x00, 0x00, 0x1E, 0x30, 0x3E, 0x33, 0x6E, 0x00], // 'a'
+    [0x07, 0x06, 0x06, 0x3E, 0x66, 0x66, 0x3B, 0x00], // 'b'
+    [0x00, 0x00, 0x1E, 0x33, 0x03, 0x33, 0x1E, 0x00], // 'c'
+    [0x38, 0x30, 0x30, 0x3E, 0x33, 0x33, 0x6E, 0x00], // 'd'
+    [0x00, 0x00, 0x1E, 0x33, 0x3F, 0x03, 0x1E, 0x00], // 'e'
+    [0x1C, 0x36, 0x06, 0x0F, 0x06, 0x06, 0x0F, 0x00], // 'f'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'g'
+    [0x07, 0x06, 0x36, 0x6E, 0x66, 0x66, 0x67, 0x00], // 'h'
+    [0x0C, 0x00, 0x0E, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'i'
+    [0x30, 0x00, 0x30, 0x30, 0x30, 0x33, 0x33, 0x1E], // 'j'
+    [0x07, 0x06, 0x66, 0x36, 0x1E, 0x36, 0x67, 0x00], // 'k'
+    [0x0E, 0x0C, 0x0C, 0x0C, 0x0C, 0x0C, 0x1E, 0x00], // 'l'
+    [0x00, 0x00, 0x33, 0x7F, 0x7F, 0x6B, 0x63, 0x00], // 'm'
+    [0x00, 0x00, 0x1F, 0x33, 0x33, 0x33, 0x33, 0x00], // 'n'
+    [0x00, 0x00, 0x1E, 0x33, 0x33, 0x33, 0x1E, 0x00], // 'o'
+    [0x00, 0x00, 0x3B, 0x66, 0x66, 0x3E, 0x06, 0x0F], // 'p'
+    [0x00, 0x00, 0x6E, 0x33, 0x33, 0x3E, 0x30, 0x78], // 'q'
+    [0x00, 0x00, 0x3B, 0x6E, 0x66, 0x06, 0x0F, 0x00], // 'r'
+    [0x00, 0x00, 0x3E, 0x03, 0x1E, 0x30, 0x1F, 0x00], // 's'
+    [0x08, 0x0C, 0x3E, 0x0C, 0x0C, 0x2C, 0x18, 0x00], // 't'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x33, 0x6E, 0x00], // 'u'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x1E, 0x0C, 0x00], // 'v'
+    [0x00, 0x00, 0x63, 0x6B, 0x7F, 0x7F, 0x36, 0x00], // 'w'
+    [0x00, 0x00, 0x63, 0x36, 0x1C, 0x36, 0x63, 0x00], // 'x'
+    [0x00, 0x00, 0x33, 0x33, 0x33, 0x3E, 0x30, 0x1F], // 'y'
+    [0x00, 0x00, 0x3F, 0x19, 0x0C, 0x26, 0x3F, 0x00], // 'z'
+    [0x38, 0x0C, 0x0C, 0x07, 0x0C, 0x0C, 0x38, 0x00], // '{'
+    [0x18, 0x18, 0x18, 0x00, 0x18, 0x18, 0x18, 0x00], // '|'
+    [0x07, 0x0C, 0x0C, 0x38, 0x0C, 0x0C, 0x07, 0x00], // '}'
+    [0x6E, 0x3B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00], // '~'
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    fn virtual_fbink(bpp: u8, pixel_format: PixelFormat) -> VirtualFbInk {
+        let device = VirtualDevice {
+            width: 32,
+            height: 24,
+            bpp,
+            pixel_format,
+            dpi: 100,
+            ..Default::default()
+        };
+        let config = FbInkConfig {
+            fontmult: 1,
+            ..Default::default()
+        };
+        VirtualFbInk::new(device, config).unwrap()
+    }
+
+    /// The rects of the logged refreshes, emptying the log
+    fn refreshed(fbink: &VirtualFbInk) -> Vec<Rect<Rotated>> {
+        let refreshes = fbink.take_refreshes();
+        refreshes.iter().map(|r| r.rect).collect()
+    }
+
+    /// The position of every pixel in the dump that isn't white
+    fn inked(fbink: &VirtualFbInk) -> Vec<(u32, u32)> {
+        let image = fbink.dump().unwrap().dynamic_image().unwrap().to_luma8();
+        let inked = image.enumerate_pixels().filter(|(_, _, p)| p[0] != u8::MAX);
+        inked.map(|(x, y, _)| (x, y)).collect()
+    }
+
+    #[test]
+    fn print() {
+        let fbink = virtual_fbink(8, PixelFormat::Y8);
+        assert!(inked(&fbink).is_empty());
+        assert_eq!(fbink.print("I").unwrap(), 1);
+        let rect = Rect::new(0, 0, 8, 8);
+        assert_eq!(fbink.get_last_rect(), rect);
+        assert_eq!(refreshed(&fbink), [rect]);
+        assert_eq!(fbink.get_last_marker(), 1);
+        let inked = inked(&fbink);
+        assert!(!inked.is_empty());
+        assert!(inked.iter().all(|&(x, y)| x < 8 && y < 8));
+        // The top row of an I is ink from x 1 to 4
+        assert!(inked.contains(&(1, 0)) && inked.contains(&(4, 0)) && !inked.contains(&(0, 0)));
+    }
+
+    #[test]
+    fn print_without_refresh() {
+        let mut fbink = virtual_fbink(8, PixelFormat::Y8);
+        fbink.config.no_refresh = true;
+        fbink.config.row = 1;
+        fbink.config.col = 2;
+        fbink.print("ab").unwrap();
+        assert_eq!(fbink.get_last_rect(), Rect::new(16, 8, 16, 8));
+        assert!(fbink.take_refreshes().is_empty());
+    }
+
+    #[test]
+    fn cls() {
+        let fbink = virtual_fbink(8, PixelFormat::Y8);
+        fbink.print("ab").unwrap();
+        fbink.take_refreshes();
+        fbink.cls_rect(&Rect::new(0, 0, 8, 8).into()).unwrap();
+        assert_eq!(refreshed(&fbink), [Rect::new(0, 0, 8, 8)]);
+        assert!(inked(&fbink).iter().all(|&(x, _)| x >= 8));
+        fbink.cls().unwrap();
+        assert_eq!(refreshed(&fbink), [Rect::new(0, 0, 32, 24)]);
+        assert!(inked(&fbink).is_empty());
+    }
+
+    #[test]
+    fn refresh() {
+        let fbink = virtual_fbink(8, PixelFormat::Y8);
+        fbink.refresh(Rect::new(1, 2, 3, 4)).unwrap();
+        // Empty rects refresh the whole screen and rects are clamped to it
+        fbink.refresh(Rect::default()).unwrap();
+        fbink.refresh(Rect::new(30, 20, 10, 10)).unwrap();
+        let expected = [
+            Rect::new(1, 2, 3, 4),
+            Rect::new(0, 0, 32, 24),
+            Rect::new(30, 20, 2, 4),
+        ];
+        assert_eq!(refreshed(&fbink), expected);
+        assert_eq!(fbink.get_last_marker(), 3);
+        let mut fbink = fbink;
+        fbink.config.is_flashing = true;
+        fbink.refresh(Rect::default()).unwrap();
+        let refresh = &fbink.refreshes()[0];
+        assert!(refresh.is_flashing);
+        assert_eq!(refresh.marker, 4);
+    }
+
+    #[test]
+    fn restore() {
+        let fbink = virtual_fbink(4, PixelFormat::Y4);
+        fbink.print("x").unwrap();
+        let dump = fbink.dump().unwrap();
+        let printed = inked(&fbink);
+        fbink.cls().unwrap();
+        fbink.take_refreshes();
+        fbink.restore(dump.as_ref()).unwrap();
+        assert_eq!(inked(&fbink), printed);
+        assert_eq!(refreshed(&fbink), [Rect::new(0, 0, 32, 24)]);
+
+        // A cropped dump only restores its clip
+        let mut dump = fbink.dump().unwrap();
+        dump.crop_rect(Rect::new(0, 0, 4, 8));
+        fbink.cls().unwrap();
+        fbink.take_refreshes();
+        fbink.restore(dump.as_ref()).unwrap();
+        let left: Vec<_> = printed.into_iter().filter(|&(x, _)| x < 4).collect();
+        assert_eq!(inked(&fbink), left);
+        assert_eq!(refreshed(&fbink), [Rect::new(0, 0, 4, 8)]);
+
+        let other = virtual_fbink(8, PixelFormat::Y8);
+        assert!(other.restore(dump.as_ref()).is_err());
+    }
+
+    #[test]
+    fn dumps_keep_pixel_format() {
+        let red = [255, 0, 0];
+        for (bpp, format) in [
+            (16, PixelFormat::Bgr565),
+            (16, PixelFormat::Rgb565),
+            (24, PixelFormat::Bgr24),
+            (32, PixelFormat::Bgra),
+        ] {
+            let fbink = virtual_fbink(bpp, format);
+            fbink.print_raw_data(&red, Rect::new(3, 2, 1, 1)).unwrap();
+            let dump = fbink.dump().unwrap();
+            assert_eq!(dump.pixel_format(), format);
+            let image = dump.dynamic_image().unwrap().to_rgba8();
+            assert_eq!(image.get_pixel(3, 2), &Rgba([255, 0, 0, 255]), "{format:?}");
+            assert_eq!(image.get_pixel(0, 0), &Rgba([255; 4]), "{format:?}");
+            let region = Rect::<Native>::new(2, 2, 2, 1).into();
+            let dump = fbink.rect_dump(&region).unwrap();
+            let image = dump.dynamic_image().unwrap().to_rgba8();
+            assert_eq!(image.get_pixel(1, 0), &Rgba([255, 0, 0, 255]), "{format:?}");
+        }
+    }
+}
@@ -1,110 +1,322 @@
 // adapted from https://rust-lang.github.io/rust-bindgen/non-system-libraries.html
 use std::env;
+use std::fs;
 use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Cargo features that select the device FBInk is built for, and the define each maps to.
+/// Without any of them FBInk is built for Kobo.
+const TARGETS: [(&str, &str); 6] = [
+    ("KOBO", "FBINK_FOR_KOBO"),
+    ("KINDLE", "FBINK_FOR_KINDLE"),
+    ("CERVANTES", "FBINK_FOR_CERVANTES"),
+    ("REMARKABLE", "FBINK_FOR_REMARKABLE"),
+    ("POCKETBOOK", "FBINK_FOR_POCKETBOOK"),
+    ("LINUX", "FBINK_FOR_LINUX"),
+];
+
+/// Cargo features for FBInk's optional components and the define each maps to
+const COMPONENTS: [(&str, &str); 6] = [
+    ("DRAW", "FBINK_WITH_DRAW"),
+    ("BITMAP", "FBINK_WITH_BITMAP"),
+    ("FONTS", "FBINK_WITH_FONTS"),
+    ("IMAGE", "FBINK_WITH_IMAGE"),
+    ("OPENTYPE", "FBINK_WITH_OPENTYPE"),
+    ("INPUT", "FBINK_WITH_INPUT"),
+];
+
+fn has_feature(name: &str) -> bool {
+    env::var_os(format!("CARGO_FEATURE_{name}")).is_some()
+}
+
+/// The defines to build FBInk with, and whether it's being built for Kobo
+fn defines() -> (Vec<&'static str>, bool) {
+    let targets: Vec<_> = TARGETS
+        .iter()
+        .filter(|(name, _)| has_feature(name))
+        .collect();
+    if targets.len() > 1 {
+        let names: Vec<_> = targets
+            .iter()
+            .map(|(name, _)| name.to_lowercase())
+            .collect();
+        panic!(
+            "only one target feature can be enabled, got {}",
+            names.join(", ")
+        );
+    }
+    let mut defines = Vec::new();
+    let is_kobo = match targets.first() {
+        Some((name, define)) => {
+            defines.push(*define);
+            *name == "KOBO"
+        }
+        None => true,
+    };
+    // Like FBInk's Makefile, a build without every component is a MINIMAL build with the
+    // enabled components opted back in
+    let components: Vec<_> = COMPONENTS
+        .iter()
+        .filter(|(name, _)| has_feature(name))
+        .collect();
+    if components.len() < COMPONENTS.len() {
+        defines.push("FBINK_MINIMAL");
+    }
+    defines.extend(components.iter().map(|(_, define)| *define));
+    // Simulating button presses is only implemented for Kobo
+    if is_kobo && has_feature("BUTTON_SCAN") {
+        defines.push("FBINK_WITH_BUTTON_SCAN");
+    }
+    (defines, is_kobo)
+}
 
 fn main() {
-    let fbink_root = PathBuf::from("FBInk")
-        // `rustc-link-search` requires an absolute path.
-        .canonicalize()
-        .expect("cannot canonicalize path");
-
-    let src_files = [
-        "fbink.c",
-        "qimagescale/qimagescale.c",
-        "cutef8/dfa.c",
-        "cutef8/utf8.c",
-        "i2c-tools/lib/smbus.c",
-        "libunibreak/src/linebreak.c",
-        "libunibreak/src/linebreakdata.c",
-        "libunibreak/src/unibreakdef.c",
-        "libunibreak/src/linebreakdef.c",
-        "libunibreak/src/eastasianwidthdef.c"
-    ];
-    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
-    let lib_path = out_dir.join("libfbink.a");
-    // Tell cargo to look for libraries in the specified directory
-    println!("cargo:rustc-link-search={}", out_dir.to_str().unwrap());
-    // Tell cargo to tell rustc to link our `libfbink.a`
-    println!("cargo:rustc-link-lib=static=fbink");
-
-    // Compile the C source files into object files using clang.
-    let mut obj_paths = Vec::new();
-    for src_file in src_files.iter() {
-        let mut args = Vec::new();
-        let src_path = fbink_root.join(src_file);
-        let obj_path = out_dir
-            .join(src_path.file_name().unwrap())
-            .with_extension("o");
-        if *src_file != "fbink.c" {
-            args.push("-fvisibility=hidden");
-        };
-        compile_object(&obj_path, &src_path, &args);
-        obj_paths.push(obj_path);
+    let (defines, is_kobo) = defines();
+    for var in [
+        "FBINK_LIB_DIR",
+        "FBINK_STATIC",
+        "FBINK_INCLUDE_DIR",
+        UPDATE_BINDINGS_VAR,
+    ] {
+        println!("cargo:rerun-if-env-changed={var}");
+    }
+
+    let header = match link_existing() {
+        Some(header) => header,
+        None => {
+            // Only the bundled build needs the submodule to be checked out
+            let fbink_root = PathBuf::from("FBInk")
+                .canonicalize()
+                .expect("cannot canonicalize path");
+            build_bundled(&fbink_root, &defines, is_kobo);
+            Header {
+                path: fbink_root.join("fbink.h"),
+                version: git_describe(&fbink_root),
+            }
+        }
+    };
+    println!("cargo:rustc-link-lib=m");
+    write_bindings(&header, &defines);
+}
+
+/// The fbink.h that the bindings are for
+struct Header {
+    path: PathBuf,
+    version: Option<String>,
+}
+
+/// Link to a libfbink that's already been built, if one was requested. Returns the header
+/// to use for the bindings, which is the bundled one unless FBINK_INCLUDE_DIR is set.
+fn link_existing() -> Option<Header> {
+    let is_static = env::var_os("FBINK_STATIC").is_some_and(|v| v != "0");
+    let include_dir = env::var_os("FBINK_INCLUDE_DIR").map(PathBuf::from);
+    let bundled_header = || PathBuf::from("FBInk/fbink.h");
+    if let Some(lib_dir) = env::var_os("FBINK_LIB_DIR") {
+        let kind = if is_static { "static" } else { "dylib" };
+        println!(
+            "cargo:rustc-link-search=native={}",
+            Path::new(&lib_dir).display()
+        );
+        println!("cargo:rustc-link-lib={kind}=fbink");
+        return Some(Header {
+            path: include_dir.map_or_else(bundled_header, |dir| dir.join("fbink.h")),
+            version: None,
+        });
+    }
+    find_with_pkg_config(is_static).map(|(include_dirs, version)| {
+        let path = include_dir
+            .into_iter()
+            .chain(include_dirs)
+            .map(|dir| dir.join("fbink.h"))
+            .find(|path| path.exists())
+            .unwrap_or_else(bundled_header);
+        Header {
+            path,
+            version: Some(version),
+        }
+    })
+}
+
+/// Look for a system libfbink with pkg-config, returning its include dirs and version
+#[cfg(feature = "system")]
+fn find_with_pkg_config(is_static: bool) -> Option<(Vec<PathBuf>, String)> {
+    match pkg_config::Config::new().statik(is_static).probe("fbink") {
+        Ok(library) => Some((library.include_paths, library.version)),
+        Err(e) => panic!("the system feature is enabled but libfbink wasn't found: {e}"),
+    }
+}
+
+#[cfg(not(feature = "system"))]
+fn find_with_pkg_config(_is_static: bool) -> Option<(Vec<PathBuf>, String)> {
+    None
+}
+
+/// Compile the bundled FBInk submodule into static libraries
+fn build_bundled(fbink_root: &Path, defines: &[&str], is_kobo: bool) {
+    // cc picks the compiler for the target and honours CC, CFLAGS, AR etc. (including the
+    // target-specific variants such as CC_armv7_unknown_linux_musleabihf)
+    let mut build = cc::Build::new();
+    build
+        .include(fbink_root.join("i2c-tools/include"))
+        .warnings(false);
+    for define in defines {
+        build.define(define, None);
+    }
+    // Only set these if cross-compiling with the Docker image so host builds use the system headers
+    if let Ok(sysroot_path) = env::var("CROSS_SYSROOT_PATH") {
+        build.flag(format!("--sysroot={sysroot_path}"));
+    }
+    if let Ok(include_path) = env::var("CROSS_INCLUDE_PATH") {
+        build.include(include_path);
     }
 
-    // Run `ar` to generate the `libfbink.a` file from the object files.
-    let output = std::process::Command::new("ar")
-        .arg("rcs")
-        .arg(lib_path)
-        .args(obj_paths)
+    // Hide the symbols of the bundled dependencies like FBInk's own Makefile does. Link order
+    // matters for static libraries, so libfbink has to be emitted before its dependencies.
+    let mut dependencies = build.clone();
+    build.file(fbink_root.join("fbink.c")).compile("fbink");
+    dependencies.flag_if_supported("-fvisibility=hidden");
+    dependencies.files(["cutef8/dfa.c", "cutef8/utf8.c"].map(|src| fbink_root.join(src)));
+    if has_feature("IMAGE") {
+        dependencies.file(fbink_root.join("qimagescale/qimagescale.c"));
+    }
+    if has_feature("OPENTYPE") {
+        for src_file in [
+            "linebreak.c",
+            "linebreakdata.c",
+            "unibreakdef.c",
+            "linebreakdef.c",
+            "eastasianwidthdef.c",
+        ] {
+            dependencies.file(fbink_root.join("libunibreak/src").join(src_file));
+        }
+    }
+    // FBInk talks to the accelerometer of some Kobos over I²C
+    if is_kobo {
+        dependencies.file(fbink_root.join("i2c-tools/lib/smbus.c"));
+    }
+    dependencies.compile("fbink_deps");
+    println!("cargo:rerun-if-env-changed=CROSS_SYSROOT_PATH");
+    println!("cargo:rerun-if-env-changed=CROSS_INCLUDE_PATH");
+    println!("cargo:rerun-if-changed=FBInk");
+}
+
+/// The FBInk version of a git checkout such as the bundled submodule
+fn git_describe(dir: &Path) -> Option<String> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["describe", "--tags", "--always"])
         .output()
-        .expect("could not spawn `ar`");
-    if !output.status.success() {
-        eprintln!("{}", String::from_utf8(output.stderr).unwrap());
-        println!("{}", String::from_utf8(output.stdout).unwrap());
-        panic!("could not emit library file");
+        .ok()?;
+    let version = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !version.trim().is_empty()).then(|| version.trim().to_string())
+}
+
+/// Set to update the pregenerated bindings from the header being built against
+const UPDATE_BINDINGS_VAR: &str = "FBINK_UPDATE_BINDINGS";
+
+/// The pregenerated bindings used when the bindgen feature is disabled
+const PREGENERATED_BINDINGS: &str = "bindings/fbink.rs";
+
+/// Pregenerated bindings start with this followed by the header's version, the defines and a
+/// hash of both the header and the defines
+const BINDINGS_TAG: &str = "// fbink.h";
+
+/// FNV-1a hash of the header and the defines it's parsed with, used to tell whether bindings
+/// match them. The defines change what fbink.h declares, so bindings for one set of features
+/// can't be used with another.
+fn bindings_hash(header: &Path, defines: &[&str]) -> String {
+    let mut contents =
+        fs::read(header).unwrap_or_else(|e| panic!("couldn't read {}: {e}", header.display()));
+    for define in defines {
+        contents.push(0);
+        contents.extend_from_slice(define.as_bytes());
     }
-    let output = std::process::Command::new("printenv").output().unwrap();
-    println!("{}", String::from_utf8(output.stdout).unwrap());
+    let hash = contents.iter().fold(0xcbf29ce484222325u64, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    format!("{hash:016x}")
+}
+
+fn bindings_tag(header: &Header, defines: &[&str]) -> String {
+    let version = header.version.as_deref().unwrap_or("unknown");
+    let mut defines = defines.to_vec();
+    defines.sort_unstable();
+    let hash = bindings_hash(&header.path, &defines);
+    format!("{BINDINGS_TAG} {version} ({}) {hash}", defines.join(" "))
+}
 
+/// The version and hash recorded in a bindings tag
+#[cfg(not(feature = "bindgen"))]
+fn tag_fields(tag: &str) -> Option<(&str, &str)> {
+    let fields = tag.strip_prefix(BINDINGS_TAG)?.trim();
+    let (version, _) = fields.split_once(' ')?;
+    let hash = fields.rsplit(' ').next()?;
+    Some((version, hash))
+}
+
+/// Generate the bindings with bindgen, optionally updating the pregenerated copy
+#[cfg(feature = "bindgen")]
+fn write_bindings(header: &Header, defines: &[&str]) {
     let bindings = bindgen::Builder::default()
         // The input header we would like to generate bindings for.
-        .header("FBInk/fbink.h")
+        .header(header.path.to_string_lossy())
         // Tell cargo to invalidate the built crate whenever any of the
         // included header files changed.
         .parse_callbacks(Box::new(bindgen::CargoCallbacks::new()))
         // Only generate bindings for things declared in fbink.h
-        .allowlist_file("FBInk/fbink.h")
+        .allowlist_file(".*fbink\\.h")
         // Make the comments from fbink.h appear as doc comments for our bindings
         .clang_arg("-fparse-all-comments")
+        .clang_args(defines.iter().map(|define| format!("-D{define}")))
         .derive_default(true)
         // Finish the builder and generate the bindings.
         .generate()
         // Unwrap the Result and panic on failure.
         .expect("Unable to generate bindings");
 
+    let bindings = format!("{}\n{bindings}", bindings_tag(header, defines));
+    if env::var_os(UPDATE_BINDINGS_VAR).is_some() {
+        let path = Path::new(&env::var("CARGO_MANIFEST_DIR").unwrap()).join(PREGENERATED_BINDINGS);
+        fs::create_dir_all(path.parent().unwrap()).expect("Couldn't create bindings dir!");
+        fs::write(path, &bindings).expect("Couldn't update pregenerated bindings!");
+    }
     // Write the bindings to the $OUT_DIR/bindings.rs file.
-    bindings
-        .write_to_file(out_dir.join("bindings.rs"))
-        .expect("Couldn't write bindings!");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("bindings.rs"), bindings).expect("Couldn't write bindings!");
 }
 
-fn compile_object(obj_path: &Path, src_path: &Path, args: &[&str]) {
-    let mut toolchain_args = Vec::new();
-    // Only set these args if cross-compiling so the docs can still be built on the host.
-    // They both need to be set for cross-compilation to produce a working binary.
-    if let Ok(sysroot_path) = env::var("CROSS_SYSROOT_PATH") {
-        toolchain_args.push(format!("--sysroot={sysroot_path}"));
-    }
-    if let Ok(include_path) = env::var("CROSS_INCLUDE_PATH") {
-        toolchain_args.push(format!("-I{include_path}"));
+/// Use the pregenerated bindings, making sure they were generated from the same version of the
+/// same header with the same defines
+#[cfg(not(feature = "bindgen"))]
+fn write_bindings(header: &Header, defines: &[&str]) {
+    println!("cargo:rerun-if-changed={PREGENERATED_BINDINGS}");
+    println!("cargo:rerun-if-changed={}", header.path.display());
+    let bindings = fs::read_to_string(PREGENERATED_BINDINGS).unwrap_or_else(|e| {
+        panic!(
+            "couldn't read {PREGENERATED_BINDINGS} ({e}). Enable the bindgen feature, or \
+             generate them by building with it and {UPDATE_BINDINGS_VAR}=1"
+        )
+    });
+    let tag = bindings.lines().next().unwrap_or_default();
+    let expected = bindings_tag(header, defines);
+    // The defines are covered by the hash. The version can only be checked when it's known,
+    // which it isn't for a library from FBINK_LIB_DIR or a bundled FBInk outside of git.
+    let matches = match (tag_fields(tag), tag_fields(&expected)) {
+        (Some((version, hash)), Some((expected_version, expected_hash))) => {
+            hash == expected_hash && (header.version.is_none() || version == expected_version)
+        }
+        _ => false,
     };
-    let output = std::process::Command::new("clang")
-        .arg("-c")
-        .arg("-static")
-        .arg("-IFBInk/i2c-tools/include")
-        .arg("-target")
-        .arg(env::var("TARGET").unwrap())
-        .args(toolchain_args)
-        .args(args)
-        .arg("-o")
-        .arg(obj_path)
-        .arg(src_path)
-        .output()
-        .expect("could not spawn `clang`");
-    if !output.status.success() {
-        eprintln!("{}", String::from_utf8(output.stderr).unwrap());
-        println!("{}", String::from_utf8(output.stdout).unwrap());
-        panic!("could not compile object file");
+    if !matches {
+        panic!(
+            "{PREGENERATED_BINDINGS} doesn't match {} with the enabled features: it's for {:?}, \
+             the build is for {:?}. Regenerate them by building with the bindgen feature and {UPDATE_BINDINGS_VAR}=1",
+            header.path.display(),
+            tag.trim_start_matches(BINDINGS_TAG).trim(),
+            expected.trim_start_matches(BINDINGS_TAG).trim(),
+        );
     }
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    fs::write(out_dir.join("bindings.rs"), bindings).expect("Couldn't write bindings!");
 }
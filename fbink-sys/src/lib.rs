@@ -3,3 +3,31 @@
 #![allow(non_snake_case)]
 
 include!(concat!(env!("OUT_DIR"), "/bindings.rs"));
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::FBInkRect;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    // bindgen can't derive serde, so mirror the struct with a remote derive
+    #[derive(Serialize, Deserialize)]
+    #[serde(remote = "FBInkRect")]
+    struct FBInkRectDef {
+        left: u16,
+        top: u16,
+        width: u16,
+        height: u16,
+    }
+
+    impl Serialize for FBInkRect {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            FBInkRectDef::serialize(self, serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for FBInkRect {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            FBInkRectDef::deserialize(deserializer)
+        }
+    }
+}